@@ -1,30 +1,109 @@
-use crate::exit;
-use crate::lib::DEFAULT_COMPRESS_QUALITY;
-use clap::{crate_name, crate_version, App, AppSettings, Arg};
-use rpassword::prompt_password_stdout;
+use crate::progress::Report;
+use crate::{exit, ERROR_CODE, JSON};
+use clap::{crate_name, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use mei::{is_archive, Keypair, PublicKey, SecretKey, VolumeReader, DEFAULT_COMPRESS_QUALITY};
+use rpassword::prompt_password_stderr;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use std::sync::atomic::Ordering;
+use zeroize::Zeroizing;
 
 const DEFAULT_OUTPUT_FILE: &str = "archive.mei";
 const DEFAULT_OUTPUT_DIR: &str = "./";
 
+pub enum Command {
+    Compress,
+    Decompress,
+    Append,
+    Rewrite,
+    Rekey(RekeyMode),
+    Keygen,
+    List,
+    Test,
+    Info,
+    Diff { target: String, stream: bool },
+    Repair { test: bool },
+    Convert,
+    Cat { path: String },
+}
+
+pub enum RekeyMode {
+    Change,
+    Add,
+    Remove,
+}
+
 pub struct Options {
+    pub command: Command,
     pub input: String,
     pub info: String,
-    pub password: Option<String>,
+    pub password: Option<Zeroizing<String>>,
+    pub new_password: Option<Zeroizing<String>>,
     pub force: bool,
-    pub compress: bool,
     pub output: String,
     pub quality: u32,
+    pub private: bool,
+    pub sign: Option<Keypair>,
+    pub verify: Option<PublicKey>,
+    pub report: Report,
+    pub append: Vec<String>,
+    pub delete: Vec<String>,
+    pub update: Vec<String>,
+    pub incremental: Option<String>,
+    pub checksum: bool,
+    pub volume_size: Option<u64>,
+    pub recovery: Option<u8>,
+    pub keep_going: bool,
+    pub json: bool,
+}
+
+impl Options {
+    fn new(command: Command, input: &str) -> Self {
+        Self {
+            command,
+            input: input.to_string(),
+            info: String::new(),
+            password: None,
+            new_password: None,
+            force: false,
+            output: String::new(),
+            quality: DEFAULT_COMPRESS_QUALITY,
+            private: false,
+            sign: None,
+            verify: None,
+            report: Report::Entries,
+            append: Vec::new(),
+            delete: Vec::new(),
+            update: Vec::new(),
+            incremental: None,
+            checksum: false,
+            volume_size: None,
+            recovery: None,
+            keep_going: false,
+            json: false,
+        }
+    }
 }
 
 pub fn options() -> Options {
     let app = App::new(crate_name!())
         .global_setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .version(crate_version!())
-        .usage(format!("{} <PATH> -d?", crate_name!()).as_str())
+        .usage(
+            format!(
+                "{} <PATH> -d?\n    {} <SUBCOMMAND>",
+                crate_name!(),
+                crate_name!()
+            )
+            .as_str(),
+        )
         .arg(
             Arg::with_name("PATH")
                 .required(true)
-                .help("Set the input file path"),
+                .help("Set the input file path, an archive is extracted"),
         )
         .arg(
             Arg::with_name("decompress")
@@ -32,85 +111,837 @@ pub fn options() -> Options {
                 .long("decompress")
                 .help("Decompress archived file"),
         )
+        .args(&common_args())
+        .args(&extract_args())
+        .args(&create_args(true))
         .arg(
-            Arg::with_name("output")
-                .short("o")
-                .long("output")
+            Arg::with_name("append")
+                .long("append")
                 .takes_value(true)
+                .multiple(true)
                 .value_name("PATH")
-                .help("Set output file path"),
+                .conflicts_with_all(&["decompress", "info", "private", "output"])
+                .help("Add files to the archive at <PATH>"),
         )
         .arg(
-            Arg::with_name("force")
-                .short("f")
-                .long("force")
-                .help("Overwrite local files"),
+            Arg::with_name("delete")
+                .long("delete")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("PATTERN")
+                .conflicts_with_all(&["decompress", "append", "info", "private", "output"])
+                .help("Remove the entries matching a pattern, such as '*.pem' or 'dir/**'"),
         )
         .arg(
+            Arg::with_name("update")
+                .long("update")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("PATH")
+                .conflicts_with_all(&["decompress", "append", "info", "private", "output"])
+                .help("Replace the entries of files and directories in the archive"),
+        )
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Create an archive from files and directories")
+                .arg(
+                    Arg::with_name("PATH")
+                        .required(true)
+                        .help("Set the input file path"),
+                )
+                .args(&common_args())
+                .args(&create_args(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Extract the entries of an archive")
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .args(&common_args())
+                .args(&extract_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the entries of an archive")
+                .args(&password_args())
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Decompress an archive and check the files against its checksums")
+                .args(&password_args())
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .arg(verify_arg())
+                .arg(
+                    Arg::with_name("progress")
+                        .long("progress")
+                        .conflicts_with("quiet")
+                        .help("Show a progress bar instead of the entries"),
+                )
+                .arg(
+                    Arg::with_name("quiet")
+                        .long("quiet")
+                        .help("Do not print the entries"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Show the format, encryption and sizes of an archive")
+                .args(&password_args())
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .conflicts_with("format")
+                        .help("Print a JSON object, the same as '--format json'"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .about("Change the passwords of an encrypted archive")
+                .args(&password_args())
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .arg(
+                    Arg::with_name("add")
+                        .long("add")
+                        .conflicts_with("remove")
+                        .help("Add another password instead of replacing it"),
+                )
+                .arg(
+                    Arg::with_name("remove")
+                        .long("remove")
+                        .help("Remove the password instead of replacing it"),
+                )
+                .arg(
+                    Arg::with_name("new-password-env")
+                        .long("new-password-env")
+                        .takes_value(true)
+                        .value_name("VAR")
+                        .conflicts_with_all(&["remove", "new-password-file"])
+                        .help("Read the new password from an environment variable"),
+                )
+                .arg(
+                    Arg::with_name("new-password-file")
+                        .long("new-password-file")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with("remove")
+                        .help("Read the new password from a file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare an archive with a directory or another archive")
+                .args(&password_args())
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .arg(
+                    Arg::with_name("TARGET")
+                        .required(true)
+                        .help("Set the directory the archive was unpacked to, or another archive"),
+                )
+                .arg(
+                    Arg::with_name("stream")
+                        .long("stream")
+                        .help("Decompress the archive and compare the files instead of checksums"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Write the content of a file in an archive to stdout")
+                .args(&password_args())
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .arg(
+                    Arg::with_name("PATH")
+                        .required(true)
+                        .help("Set the path of the file in the archive"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Convert a tar, tar.gz or zip archive to a mei archive, or the reverse")
+                .args(&password_args())
+                .arg(
+                    Arg::with_name("INPUT")
+                        .required(true)
+                        .help("Set the archive to convert"),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .required(true)
+                        .help("Set the output archive, its format is chosen by the extension"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Overwrite local files"),
+                )
+                .arg(
+                    Arg::with_name("info")
+                        .short("i")
+                        .long("info")
+                        .takes_value(true)
+                        .value_name("info")
+                        .help("Set archive file information"),
+                )
+                .arg(
+                    Arg::with_name("quality")
+                        .short("q")
+                        .long("quality")
+                        .takes_value(true)
+                        .value_name("1-11")
+                        .help("Set compression quality"),
+                )
+                .arg(
+                    Arg::with_name("private")
+                        .long("private")
+                        .help("Encrypt the archive info and entry types, and hide file sizes"),
+                )
+                .arg(
+                    Arg::with_name("sign")
+                        .long("sign")
+                        .takes_value(true)
+                        .value_name("SECRET_KEY")
+                        .help("Sign the archive with a secret key file"),
+                )
+                .arg(
+                    Arg::with_name("quiet")
+                        .long("quiet")
+                        .help("Do not print the entries"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Rebuild the damaged parts of an archive from its recovery record")
+                .arg(
+                    Arg::with_name("ARCHIVE")
+                        .required(true)
+                        .help("Set the archive file path"),
+                )
+                .arg(
+                    Arg::with_name("test")
+                        .long("test")
+                        .help("Only report what is damaged and what can be repaired"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Generate a key pair for signing archives")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .help("Write the keys to NAME.key and NAME.pub"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Overwrite local files"),
+                ),
+        )
+        .get_matches_safe()
+        .unwrap_or_else(|err| {
+            // Exit 1 of `diff` means that there are differences
+            if err.use_stderr() && env::args().nth(1).as_deref() == Some("diff") {
+                eprintln!("{}", err.message);
+                process::exit(2);
+            }
+            err.exit()
+        });
+
+    if let Some(sub) = app.subcommand_matches("keygen") {
+        return Options {
+            force: sub.is_present("force"),
+            ..Options::new(Command::Keygen, sub.value_of("NAME").unwrap())
+        };
+    }
+
+    if let Some(sub) = app.subcommand_matches("diff") {
+        ERROR_CODE.store(2, Ordering::Relaxed);
+        let command = Command::Diff {
+            target: sub.value_of("TARGET").unwrap().to_string(),
+            stream: sub.is_present("stream"),
+        };
+        return Options {
+            password: password(sub, false),
+            ..Options::new(command, sub.value_of("ARCHIVE").unwrap())
+        };
+    }
+
+    if let Some(sub) = app.subcommand_matches("cat") {
+        let command = Command::Cat {
+            path: sub.value_of("PATH").unwrap().to_string(),
+        };
+        return Options {
+            password: password(sub, false),
+            ..Options::new(command, sub.value_of("ARCHIVE").unwrap())
+        };
+    }
+
+    if let Some(sub) = app.subcommand_matches("convert") {
+        let output = sub.value_of("OUTPUT").unwrap();
+        // Only a new mei archive is encrypted with the password
+        let password = password(sub, output.ends_with(".mei"));
+        if sub.is_present("private") && password.is_none() {
+            exit!("'--private' requires a password");
+        }
+        return Options {
+            info: sub.value_of("info").unwrap_or_default().to_string(),
+            password,
+            force: sub.is_present("force"),
+            output: output.to_string(),
+            quality: quality(sub),
+            private: sub.is_present("private"),
+            sign: sub.value_of("sign").map(keypair),
+            report: report(sub),
+            ..Options::new(Command::Convert, sub.value_of("INPUT").unwrap())
+        };
+    }
+
+    if let Some(sub) = app.subcommand_matches("repair") {
+        let command = Command::Repair {
+            test: sub.is_present("test"),
+        };
+        return Options::new(command, sub.value_of("ARCHIVE").unwrap());
+    }
+
+    if let Some(sub) = app.subcommand_matches("rekey") {
+        let mode = if sub.is_present("add") {
+            RekeyMode::Add
+        } else if sub.is_present("remove") {
+            RekeyMode::Remove
+        } else {
+            RekeyMode::Change
+        };
+        let new_password = match mode {
+            RekeyMode::Remove => None,
+            _ => Some(new_password(sub)),
+        };
+        return Options {
+            password: password(sub, false).or_else(|| Some(prompt("Password: ", false))),
+            new_password,
+            ..Options::new(Command::Rekey(mode), sub.value_of("ARCHIVE").unwrap())
+        };
+    }
+
+    if let Some(sub) = app.subcommand_matches("create") {
+        return archive_options(sub, Command::Compress, sub.value_of("PATH").unwrap());
+    }
+
+    if let Some(sub) = app.subcommand_matches("extract") {
+        return archive_options(sub, Command::Decompress, sub.value_of("ARCHIVE").unwrap());
+    }
+
+    if let Some(sub) = app.subcommand_matches("list") {
+        return Options {
+            json: json(sub),
+            password: password(sub, false),
+            ..Options::new(Command::List, sub.value_of("ARCHIVE").unwrap())
+        };
+    }
+
+    if let Some(sub) = app.subcommand_matches("test") {
+        return Options {
+            json: json(sub),
+            password: password(sub, false),
+            verify: sub.value_of("verify").map(public_key),
+            report: report(sub),
+            ..Options::new(Command::Test, sub.value_of("ARCHIVE").unwrap())
+        };
+    }
+
+    if let Some(sub) = app.subcommand_matches("info") {
+        return Options {
+            json: json(sub),
+            password: password(sub, false),
+            ..Options::new(Command::Info, sub.value_of("ARCHIVE").unwrap())
+        };
+    }
+
+    let input = app.value_of("PATH").unwrap();
+    let json = json(&app);
+    let command = if app.is_present("decompress") {
+        Command::Decompress
+    } else if app.is_present("append") {
+        Command::Append
+    } else if app.is_present("delete") || app.is_present("update") {
+        Command::Rewrite
+    } else if !CREATE_ONLY.iter().any(|name| app.is_present(name)) && is_archive_path(input) {
+        // Archiving an archive is almost never what `mei archive.mei` means
+        if !json {
+            eprintln!(
+                "'{}' is a mei archive, extracting it. Use '{} create' to archive it",
+                input,
+                crate_name!()
+            );
+        }
+        Command::Decompress
+    } else {
+        Command::Compress
+    };
+    // With `-d` or an archive that was detected
+    if !matches!(command, Command::Decompress) {
+        if let Some(name) = EXTRACT_ONLY.iter().find(|name| app.is_present(name)) {
+            exit!("'--{}' can only be used when extracting an archive", name);
+        }
+    }
+    archive_options(&app, command, input)
+}
+
+// Options of the legacy form that only make sense when creating an archive
+const CREATE_ONLY: [&str; 7] = [
+    "info",
+    "quality",
+    "private",
+    "sign",
+    "incremental-from",
+    "volume-size",
+    "recovery",
+];
+
+// Options of the legacy form that only make sense when extracting an archive
+const EXTRACT_ONLY: [&str; 2] = ["keep-going", "verify"];
+
+fn is_archive_path(p: &str) -> bool {
+    VolumeReader::open(p).map(is_archive).unwrap_or(false)
+}
+
+// Options of `create`, `extract` and the legacy form
+fn archive_options(app: &ArgMatches, command: Command, input: &str) -> Options {
+    let json = json(app);
+    let password = password(app, matches!(command, Command::Compress));
+    if app.is_present("private") && password.is_none() {
+        exit!("'--private' requires a password");
+    }
+    let output = app.value_of("output").unwrap_or(match command {
+        Command::Decompress => DEFAULT_OUTPUT_DIR,
+        _ => DEFAULT_OUTPUT_FILE,
+    });
+
+    Options {
+        command,
+        input: input.to_string(),
+        info: app.value_of("info").unwrap_or_default().to_string(),
+        password,
+        new_password: None,
+        force: app.is_present("force"),
+        output: output.to_string(),
+        quality: quality(app),
+        private: app.is_present("private"),
+        sign: app.value_of("sign").map(keypair),
+        verify: app.value_of("verify").map(public_key),
+        report: report(app),
+        append: values(app, "append"),
+        delete: values(app, "delete"),
+        update: values(app, "update"),
+        incremental: app.value_of("incremental-from").map(String::from),
+        checksum: app.is_present("checksum"),
+        volume_size: app.value_of("volume-size").map(|s| {
+            size(s).unwrap_or_else(|| exit!("The value of '--volume-size' is a size such as 4G"))
+        }),
+        keep_going: app.is_present("keep-going"),
+        json,
+        recovery: app.value_of("recovery").map(|s| {
+            match s.strip_suffix('%').unwrap_or(s).parse::<u8>() {
+                Ok(n) if (1..=100).contains(&n) => n,
+                _ => exit!("The value of '--recovery' is a percentage between 1% and 100%"),
+            }
+        }),
+    }
+}
+
+fn common_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = vec![
+        Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Set output file path"),
+        Arg::with_name("force")
+            .short("f")
+            .long("force")
+            .help("Overwrite local files, or drop the signature of an archive that is changed"),
+        Arg::with_name("progress")
+            .long("progress")
+            .conflicts_with("quiet")
+            .help("Show a progress bar instead of the entries"),
+        Arg::with_name("quiet")
+            .long("quiet")
+            .help("Do not print the entries"),
+        format_arg(),
+    ];
+    args.extend_from_slice(&password_args());
+    args
+}
+
+// The legacy form also has `-d`, `--append`, `--delete` and `--update` to conflict with
+fn create_args<'a, 'b>(legacy: bool) -> Vec<Arg<'a, 'b>> {
+    let conflicts = |arg: Arg<'a, 'b>, names: &[&'a str]| {
+        if legacy {
+            arg.conflicts_with_all(names)
+        } else {
+            arg
+        }
+    };
+    let modes = ["decompress", "append", "delete", "update"];
+    vec![
+        conflicts(
             Arg::with_name("info")
                 .short("i")
                 .long("info")
                 .takes_value(true)
                 .value_name("info")
-                .conflicts_with("decompress")
                 .help("Set archive file information"),
-        )
-        .arg(
+            &["decompress"],
+        ),
+        conflicts(
             Arg::with_name("quality")
                 .short("q")
                 .long("quality")
                 .takes_value(true)
                 .value_name("1-11")
-                .conflicts_with("decompress")
                 .help("Set compression quality"),
-        )
-        .arg(
-            Arg::with_name("password")
-                .short("p")
-                .long("password")
-                .value_name("PASSWORD")
-                .min_values(0)
-                .max_values(1)
-                .help("Set/Use archive file password"),
-        )
-        .get_matches();
+            &["decompress"],
+        ),
+        conflicts(
+            Arg::with_name("incremental-from")
+                .long("incremental-from")
+                .takes_value(true)
+                .value_name("ARCHIVE")
+                .help("Only add the files that changed since an archive, and the deleted paths"),
+            &modes,
+        ),
+        Arg::with_name("checksum")
+            .long("checksum")
+            .requires("incremental-from")
+            .help("Also compare the content of files whose size and time did not change"),
+        conflicts(
+            Arg::with_name("volume-size")
+                .long("volume-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Split the archive into files of at most SIZE bytes, such as 4G or 500M"),
+            &modes,
+        ),
+        conflicts(
+            Arg::with_name("recovery")
+                .long("recovery")
+                .takes_value(true)
+                .value_name("PERCENT")
+                .conflicts_with("volume-size")
+                .help("Add a recovery record that can repair up to PERCENT of damage, such as 5%"),
+            &modes,
+        ),
+        conflicts(
+            Arg::with_name("private")
+                .long("private")
+                .help("Encrypt the archive info and entry types, and hide file sizes"),
+            &["decompress"],
+        ),
+        conflicts(
+            Arg::with_name("sign")
+                .long("sign")
+                .takes_value(true)
+                .value_name("SECRET_KEY")
+                .help("Sign the archive with a secret key file"),
+            &["decompress"],
+        ),
+    ]
+}
 
-    Options {
-        input: app.value_of("PATH").unwrap().to_string(),
-        info: app.value_of("info").unwrap_or_default().to_string(),
-        password: {
-            if app.is_present("password") {
-                let val = match app.value_of("password") {
-                    Some(s) => s.to_string(),
-                    None => prompt_password_stdout("Password: ").unwrap(),
-                };
-                Some(val)
-            } else {
-                None
-            }
-        },
-        force: app.is_present("force"),
-        compress: !app.is_present("decompress"),
-        output: app
-            .value_of("output")
-            .unwrap_or_else(|| {
-                if app.is_present("decompress") {
-                    DEFAULT_OUTPUT_DIR
-                } else {
-                    DEFAULT_OUTPUT_FILE
-                }
-            })
-            .to_string(),
-        quality: app
-            .value_of("quality")
-            .map(|s| {
-                if let Ok(n) = s.parse::<u32>() {
-                    if n <= 11 && n >= 1 {
-                        return n;
-                    }
+// Options of `extract` and the legacy form, which only accepts them when extracting
+fn extract_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("keep-going")
+            .long("keep-going")
+            .help("Skip the entries that cannot be extracted, exits with 2 if any were skipped"),
+        verify_arg(),
+    ]
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(&["text", "json"])
+        .help("Print text, or one JSON object per line and errors as JSON on stderr")
+}
+
+// Errors are JSON from here on, so it is read before the other options are checked
+fn json(app: &ArgMatches) -> bool {
+    let json = app.value_of("format") == Some("json") || app.is_present("json");
+    if json {
+        JSON.store(true, Ordering::Relaxed);
+    }
+    json
+}
+
+fn verify_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("verify")
+        .long("verify")
+        .takes_value(true)
+        .value_name("PUBLIC_KEY")
+        .help("Verify the archive signature with a public key file")
+}
+
+fn report(app: &ArgMatches) -> Report {
+    if json(app) {
+        Report::Json
+    } else if app.is_present("progress") {
+        Report::Bar
+    } else if app.is_present("quiet") {
+        Report::Quiet
+    } else {
+        Report::Entries
+    }
+}
+
+fn quality(app: &ArgMatches) -> u32 {
+    app.value_of("quality")
+        .map(|s| {
+            if let Ok(n) = s.parse::<u32>() {
+                if (1..=11).contains(&n) {
+                    return n;
                 }
-                exit!("The value of '--quality' is between 1-11")
-            })
-            .unwrap_or(DEFAULT_COMPRESS_QUALITY),
+            }
+            exit!("The value of '--quality' is between 1-11")
+        })
+        .unwrap_or(DEFAULT_COMPRESS_QUALITY)
+}
+
+fn public_key(p: &str) -> PublicKey {
+    PublicKey::from_bytes(&read_key(p))
+        .unwrap_or_else(|_| exit!("'{}' is not a valid public key", p))
+}
+
+fn keypair(p: &str) -> Keypair {
+    let secret = SecretKey::from_bytes(&read_key(p))
+        .unwrap_or_else(|_| exit!("'{}' is not a valid secret key", p));
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
+// A number of bytes with an optional K, M, G or T suffix
+fn size(s: &str) -> Option<u64> {
+    let i = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(i);
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    n.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn values(app: &ArgMatches, name: &str) -> Vec<String> {
+    app.values_of(name)
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// Key files contain the 32 key bytes in hex
+fn read_key(p: &str) -> Vec<u8> {
+    let s = fs::read_to_string(p).unwrap_or_else(|err| exit!("Failed to read '{}': {}", p, err));
+    let s = s.trim();
+    if s.len() != 64 || !s.is_ascii() {
+        exit!("'{}' is not a valid key file", p);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .unwrap_or_else(|_| exit!("'{}' is not a valid key file", p))
+        })
+        .collect()
+}
+
+fn password_args<'a, 'b>() -> [Arg<'a, 'b>; 4] {
+    [
+        Arg::with_name("password")
+            .short("p")
+            .long("password")
+            .value_name("PASSWORD")
+            .min_values(0)
+            .max_values(1)
+            .help("Set/Use archive file password"),
+        Arg::with_name("password-env")
+            .long("password-env")
+            .takes_value(true)
+            .value_name("VAR")
+            .conflicts_with_all(&["password", "password-file", "password-fd"])
+            .help("Read archive file password from an environment variable"),
+        Arg::with_name("password-file")
+            .long("password-file")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with_all(&["password", "password-fd"])
+            .help("Read archive file password from a file"),
+        Arg::with_name("password-fd")
+            .long("password-fd")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with("password")
+            .help("Read archive file password from a file descriptor"),
+    ]
+}
+
+fn password(app: &ArgMatches, confirm: bool) -> Option<Zeroizing<String>> {
+    if let Some(var) = app.value_of("password-env") {
+        return match env::var(var) {
+            Ok(val) => Some(not_empty(Zeroizing::new(val))),
+            Err(_) => exit!("Environment variable '{}' is not set", var),
+        };
+    }
+    if let Some(path) = app.value_of("password-file") {
+        return match fs::File::open(path).and_then(read_password) {
+            Ok(s) => Some(not_empty(s)),
+            Err(err) => exit!("Failed to read '{}': {}", path, err),
+        };
+    }
+    if let Some(fd) = app.value_of("password-fd") {
+        let fd = fd
+            .parse()
+            .ok()
+            .filter(|fd| *fd > 2)
+            .unwrap_or_else(|| exit!("The value of '--password-fd' is not a valid descriptor"));
+        return Some(not_empty(read_fd(fd)));
     }
+    if !app.is_present("password") {
+        return None;
+    }
+    if let Some(s) = app.value_of("password") {
+        return Some(not_empty(Zeroizing::new(s.to_string())));
+    }
+    Some(prompt("Password: ", confirm))
+}
+
+fn new_password(app: &ArgMatches) -> Zeroizing<String> {
+    if let Some(var) = app.value_of("new-password-env") {
+        return match env::var(var) {
+            Ok(val) => not_empty(Zeroizing::new(val)),
+            Err(_) => exit!("Environment variable '{}' is not set", var),
+        };
+    }
+    if let Some(path) = app.value_of("new-password-file") {
+        return match fs::File::open(path).and_then(read_password) {
+            Ok(s) => not_empty(s),
+            Err(err) => exit!("Failed to read '{}': {}", path, err),
+        };
+    }
+    prompt("New password: ", true)
+}
+
+// On stderr, so that the prompt is not mixed with the output of `cat` or `--format json`
+fn prompt(msg: &str, confirm: bool) -> Zeroizing<String> {
+    let read = |msg| match prompt_password_stderr(msg) {
+        Ok(val) => Zeroizing::new(val),
+        Err(err) => exit!("Failed to read the password: {}", err),
+    };
+    let val = read(msg);
+    // A typo when encrypting would produce an archive nobody can open
+    if confirm && *val != *read("Confirm password: ") {
+        exit!("Passwords do not match");
+    }
+    val
+}
+
+// The buffer has a fixed size, so reading never reallocates and leaves
+// copies of the password behind in freed memory
+const MAX_PASSWORD_SIZE: usize = 4096;
+
+// Only the first line is used, so files written by `echo` work as expected
+fn read_password<R: io::Read>(mut r: R) -> io::Result<Zeroizing<String>> {
+    let mut buf = Zeroizing::new(vec![0; MAX_PASSWORD_SIZE]);
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    let line = match buf[..len].iter().position(|b| *b == b'\n') {
+        Some(end) => &buf[..end],
+        None if len == buf.len() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "password is too long",
+            ))
+        }
+        None => &buf[..len],
+    };
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    match std::str::from_utf8(line) {
+        Ok(s) => Ok(Zeroizing::new(s.to_string())),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "password is not valid UTF-8",
+        )),
+    }
+}
+
+// An empty variable or file is a mistake, not a password
+fn not_empty(s: Zeroizing<String>) -> Zeroizing<String> {
+    if s.is_empty() {
+        exit!("The password is empty");
+    }
+    s
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> Zeroizing<String> {
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    // Only take ownership of a descriptor the process actually has open
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        exit!("File descriptor '{}' is not open", fd);
+    }
+    // The descriptor is handed over by the caller and is closed after reading
+    let f = unsafe { File::from_raw_fd(fd) };
+    match read_password(f) {
+        Ok(s) => s,
+        Err(err) => exit!("Failed to read file descriptor '{}': {}", fd, err),
+    }
+}
+
+#[cfg(not(unix))]
+fn read_fd(_: i32) -> Zeroizing<String> {
+    exit!("'--password-fd' is only supported on Unix")
 }
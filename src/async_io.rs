@@ -0,0 +1,605 @@
+use super::*;
+use futures_core::Stream;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+#[cfg(feature = "signing")]
+use tokio::io::AsyncSeekExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, AsyncWriteExt};
+
+// The bytes written by the inner `Encode`, they are moved to the async writer after each step
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    fn take(&self) -> Vec<u8> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// `Encode` for an `AsyncWrite`, the archive is the same as the one written by `Encode`
+pub struct AsyncEncode<W> {
+    encode: Encode<Buffer>,
+    buf: Buffer,
+    inner: W,
+}
+
+// Run the key derivation of a password on the blocking thread pool
+async fn blocking<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(rst) => rst,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(err) => Err(Error::IO(IoError::other(err))),
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncEncode<W> {
+    /// The key derivation of a password runs on the blocking thread pool,
+    /// a Tokio runtime is required
+    pub async fn new(
+        writer: W,
+        info: &str,
+        password: Option<Password>,
+        params: CompressParams,
+    ) -> Result<Self, Error> {
+        let buf = Buffer::default();
+        let encode = match password {
+            Some(password) => {
+                let (buf, info) = (buf.clone(), info.to_string());
+                blocking(move || Encode::new(buf, &info, Some(password), params)).await?
+            }
+            None => Encode::new(buf.clone(), info, None, params)?,
+        };
+        Self::with_encode(writer, encode, buf).await
+    }
+
+    /// See `Encode::new_private`, the key derivation runs on the blocking thread pool
+    pub async fn new_private(
+        writer: W,
+        info: &str,
+        password: Password,
+        params: CompressParams,
+    ) -> Result<Self, Error> {
+        let buf = Buffer::default();
+        let (encode_buf, info) = (buf.clone(), info.to_string());
+        let encode =
+            blocking(move || Encode::new_private(encode_buf, &info, password, params)).await?;
+        Self::with_encode(writer, encode, buf).await
+    }
+
+    async fn with_encode(writer: W, encode: Encode<Buffer>, buf: Buffer) -> Result<Self, Error> {
+        let mut encode = Self {
+            encode,
+            buf,
+            inner: writer,
+        };
+        encode.write_buffer().await?;
+        Ok(encode)
+    }
+
+    async fn write_buffer(&mut self) -> Result<(), Error> {
+        let buf = self.buf.take();
+        self.inner.write_all(&buf).await.rst()
+    }
+
+    /// Sign the archive when it is finished
+    #[cfg(feature = "signing")]
+    pub fn sign(&mut self, keypair: Keypair) {
+        self.encode.sign(keypair);
+    }
+
+    /// Add a directory to an archive
+    pub async fn write_directory(&mut self, p: &str) -> Result<(), Error> {
+        self.write_directory_with_metadata(p, &Metadata::default())
+            .await
+    }
+
+    /// Add a directory to an archive with its modification time and permissions
+    pub async fn write_directory_with_metadata(
+        &mut self,
+        p: &str,
+        metadata: &Metadata,
+    ) -> Result<(), Error> {
+        self.encode.write_directory_with_metadata(p, metadata)?;
+        self.write_buffer().await
+    }
+
+    /// Add a file to an archive, returns the number of compressed bytes
+    pub async fn write_file<R: AsyncRead + Unpin>(
+        &mut self,
+        p: &str,
+        reader: &mut R,
+    ) -> Result<usize, Error> {
+        self.write_file_with_metadata(p, &Metadata::default(), reader)
+            .await
+    }
+
+    /// Add a file to an archive with its modification time and permissions
+    pub async fn write_file_with_metadata<R: AsyncRead + Unpin>(
+        &mut self,
+        p: &str,
+        metadata: &Metadata,
+        reader: &mut R,
+    ) -> Result<usize, Error> {
+        let mut data = vec![0; self.encode.params.buf_size];
+        let mut w = self.encode.start_file(p, metadata)?;
+        loop {
+            let n = reader.read(&mut data).await.rst()?;
+            if n == 0 {
+                break;
+            }
+            if let Err(err) = w.write_all(&data[..n]) {
+                return Err(w.take_error().unwrap_or(Error::IO(err)));
+            }
+            let buf = self.buf.take();
+            self.inner.write_all(&buf).await.rst()?;
+        }
+        let bytes = w.finish()?;
+        self.write_buffer().await?;
+        Ok(bytes)
+    }
+
+    /// Write the end of archive marker, the summary and the signature block,
+    /// and return the inner writer
+    pub async fn finish(self) -> Result<W, Error> {
+        let Self {
+            encode,
+            buf,
+            mut inner,
+        } = self;
+        encode.finish()?;
+        inner.write_all(&buf.take()).await.rst()?;
+        inner.flush().await.rst()?;
+        Ok(inner)
+    }
+}
+
+/// The header of an entry read by `AsyncDecode`
+#[derive(Debug, Clone)]
+pub struct AsyncEntry {
+    file_type: FileType,
+    path: String,
+    metadata: Metadata,
+}
+
+impl AsyncEntry {
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+/// An item of `EntryStream`, the data of a file follows its entry
+#[derive(Debug)]
+pub enum EntryEvent {
+    Entry(AsyncEntry),
+    Data(Vec<u8>),
+}
+
+/// `Decode` for an `AsyncRead`
+pub struct AsyncDecode<R> {
+    inner: R,
+    digest: Sha512,
+    pos: u64,
+    framing: Framing,
+    // The digest a signature covers, before the last byte read by the framing
+    signed: Sha512,
+    crypto: Option<Crypto>,
+    info: String,
+    buf_size: usize,
+    // Key slots are excluded from the digest
+    slots_range: Option<(u64, u64)>,
+    #[cfg(feature = "signing")]
+    verify_key: Option<PublicKey>,
+    // Decompresses the data of the current file until it has been read to the end
+    file: Option<DecompressorWriter<Vec<u8>>>,
+    // Index of the current entry
+    index: usize,
+    path: Option<String>,
+    progress: Option<Box<dyn Progress>>,
+    written: u64,
+    total: Option<u64>,
+    // Type and sizes of the current entry, only kept for the progress observer
+    file_type: Option<FileType>,
+    entry_size: u64,
+    entry_compressed_size: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecode<R> {
+    /// The key derivation of a password runs on the blocking thread pool,
+    /// a Tokio runtime is required
+    pub async fn new(reader: R, password: Option<&str>, buf_size: usize) -> Result<Self, Error> {
+        let mut decode = Self {
+            inner: reader,
+            digest: Sha512::new(),
+            pos: 0,
+            framing: Framing::new(),
+            signed: Sha512::new(),
+            crypto: None,
+            info: String::new(),
+            buf_size,
+            slots_range: None,
+            #[cfg(feature = "signing")]
+            verify_key: None,
+            file: None,
+            index: 0,
+            path: None,
+            progress: None,
+            written: 0,
+            total: None,
+            file_type: None,
+            entry_size: 0,
+            entry_compressed_size: 0,
+        };
+        let mut header = match decode.next_frame().await? {
+            Frame::Header(header) => header,
+            _ => unreachable!(),
+        };
+        decode.info = mem::take(&mut header.header.info);
+        decode.slots_range = header.slots_range;
+        decode.crypto = match password {
+            Some(password) if header.encrypted() => {
+                let password = Zeroizing::new(password.to_string());
+                blocking(move || header.open(Some(&password))).await?
+            }
+            _ => header.open(password)?,
+        };
+        if decode.framing.private() {
+            if let Frame::Info(info) = decode.next_frame().await? {
+                decode.info = info;
+            }
+        }
+        Ok(decode)
+    }
+
+    /// See `Decode::verify`
+    #[cfg(feature = "signing")]
+    pub fn verify(&mut self, key: PublicKey) {
+        self.verify_key = Some(key);
+    }
+
+    pub fn info(&self) -> &str {
+        &self.info
+    }
+
+    /// See `Decode::progress`
+    pub fn progress(&mut self, observer: Box<dyn Progress>, archive_size: Option<u64>) {
+        self.progress = Some(observer);
+        self.total = archive_size;
+    }
+
+    fn report(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            progress.update(&Status {
+                entry: self.path.as_deref().unwrap_or_default(),
+                entries: self.index as u64,
+                bytes_read: self.pos,
+                bytes_written: self.written,
+                total: self.total,
+                file_type: self.file_type,
+                entry_size: self.entry_size,
+                entry_compressed_size: self.entry_compressed_size,
+            });
+        }
+    }
+
+    /// The summary, available once all entries have been read
+    pub fn summary(&self) -> Option<&Summary> {
+        self.framing.summary()
+    }
+
+    /// The manifest, available once all entries have been read
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.framing.manifest()
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8], hash: bool) -> Result<(), Error> {
+        match self.inner.read_exact(buf).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Err(Error::Truncated),
+            Err(err) => return Err(Error::IO(err)),
+        }
+        if hash {
+            self.digest.update(&*buf);
+        }
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    // Returns None at the end of the archive
+    async fn read_byte(&mut self) -> Result<Option<u8>, Error> {
+        let mut buf = [0; 1];
+        match self.read_exact(&mut buf, true).await {
+            Ok(()) => Ok(Some(buf[0])),
+            Err(Error::Truncated) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Read a chunk, returns None at the end of a chunk sequence
+    async fn read_data(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut len = [0; 2];
+        self.read_exact(&mut len, true).await?;
+        let mut data = vec![0; u16::from_be_bytes(len) as usize];
+        self.read_exact(&mut data, true).await?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        if self.crypto.is_none() {
+            return Ok(Some(data));
+        }
+        let mut nonce = [0; 12];
+        self.read_exact(&mut nonce, true).await?;
+        self.crypto.as_ref().unwrap().open(&data, &nonce)
+    }
+
+    // Read what the framing needs until it returns a frame, like `read_frame`
+    async fn next_frame(&mut self) -> Result<Frame, Error> {
+        loop {
+            let input = match self.framing.need() {
+                Need::Bytes { len, signed } => {
+                    let mut buf = vec![0; len];
+                    self.read_exact(&mut buf, signed).await?;
+                    Input::Bytes(buf)
+                }
+                Need::Byte => {
+                    self.signed = self.digest.clone();
+                    Input::Byte(self.read_byte().await?)
+                }
+                Need::Data => Input::Data(self.read_data().await?),
+                Need::Nothing => return Ok(Frame::Done),
+            };
+            if let Some(frame) = self.framing.feed(input)? {
+                return Ok(frame);
+            }
+        }
+    }
+
+    // The manifest and the signature block follow the end of archive marker
+    async fn read_end(&mut self) -> Result<(), Error> {
+        while !matches!(self.next_frame().await?, Frame::Done) {}
+        #[cfg(feature = "signing")]
+        match (&self.verify_key, self.framing.signature()) {
+            (Some(key), Some(signature)) => verify_signature(key, self.signed.clone(), signature)?,
+            (Some(_), None) => return Err(Error::SignatureMissing),
+            (None, _) => {}
+        }
+        Ok(())
+    }
+
+    /// Read the next entry, the data of the previous file is skipped if it has not been read
+    pub async fn next_entry(&mut self) -> Result<Option<AsyncEntry>, Error> {
+        while self.framing.in_file() {
+            let offset = self.pos;
+            if let Err(err) = self.next_frame().await {
+                let path = self.path.as_deref();
+                return Err(err.context(Operation::ReadData, path, Some(offset)));
+            }
+        }
+        self.file = None;
+        if self.framing.is_done() {
+            return Ok(None);
+        }
+        let offset = self.pos;
+        let frame = self
+            .next_frame()
+            .await
+            .map_err(|err| err.context(Operation::ReadEntry, None, Some(offset)))?;
+        let (file_type, path, metadata) = match frame {
+            Frame::Entry(file_type, path, metadata) => (file_type, path, metadata),
+            _ => {
+                self.read_end().await?;
+                self.file_type = None;
+                self.report();
+                return Ok(None);
+            }
+        };
+        if file_type == FileType::File {
+            self.file = Some(DecompressorWriter::new(Vec::new(), self.buf_size));
+        }
+        self.index += 1;
+        self.path = Some(path.clone());
+        self.file_type = Some(file_type);
+        self.entry_size = 0;
+        self.entry_compressed_size = 0;
+        self.report();
+        Ok(Some(AsyncEntry {
+            file_type,
+            path,
+            metadata,
+        }))
+    }
+
+    /// Read the next piece of the current file, returns None at the end of the file
+    pub async fn next_data(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        while self.framing.in_file() {
+            let offset = self.pos;
+            let frame = self.next_frame().await.map_err(|err| {
+                err.context(Operation::ReadData, self.path.as_deref(), Some(offset))
+            })?;
+            let out = match (frame, &mut self.file) {
+                (Frame::Data(data), Some(file)) => {
+                    self.entry_compressed_size += data.len() as u64;
+                    file.write_all(&data).rst()?;
+                    mem::take(file.get_mut())
+                }
+                _ => match self.file.take() {
+                    Some(file) => match file.into_inner() {
+                        Ok(out) | Err(out) => out,
+                    },
+                    None => Vec::new(),
+                },
+            };
+            self.written += out.len() as u64;
+            self.entry_size += out.len() as u64;
+            self.report();
+            if !out.is_empty() {
+                return Ok(Some(out));
+            }
+        }
+        Ok(None)
+    }
+
+    /// See `Decode::resync`
+    pub async fn resync(&mut self) -> Result<Option<u64>, Error> {
+        self.file = None;
+        if self.framing.version() < SYNC_VERSION {
+            self.framing.fail();
+            return Ok(None);
+        }
+        let start = self.pos;
+        let mut scan = SyncScan::default();
+        while let Some(byte) = self.read_byte().await? {
+            if scan.push(byte) {
+                self.framing.resync();
+                return Ok(Some(self.pos - start - SYNC.len() as u64));
+            }
+        }
+        self.framing.fail();
+        Ok(None)
+    }
+
+    /// Read the data of the current file, nothing is written for a directory
+    pub async fn read_file<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<u64, Error> {
+        let mut size = 0;
+        while let Some(data) = self.next_data().await? {
+            writer.write_all(&data).await.rst()?;
+            size += data.len() as u64;
+        }
+        writer.flush().await.rst()?;
+        Ok(size)
+    }
+
+    /// A stream of the entries, each file entry is followed by its data
+    pub fn into_stream(self) -> EntryStream<R> {
+        EntryStream {
+            decode: Some(self),
+            next: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncDecode<R> {
+    /// See `Decode::verify_now`
+    #[cfg(feature = "signing")]
+    pub async fn verify_now(&mut self, key: PublicKey) -> Result<(), Error> {
+        let trailer = trailer_size(self.framing.version());
+        let r = &mut self.inner;
+        let pos = r.stream_position().await.rst()?;
+        let len = archive_len(r).await.rst()?;
+        if len < trailer {
+            return Err(Error::SignatureMissing);
+        }
+        let end = len - trailer;
+        r.seek(SeekFrom::Start(end)).await.rst()?;
+        let mut buf = [0; SIGNATURE_SIZE];
+        r.read_exact(&mut buf).await.rst()?;
+        if buf[0] != SIGNATURE {
+            return Err(Error::SignatureMissing);
+        }
+        let signature = read_signature(&mut &buf[1..])?;
+
+        let ranges = match self.slots_range {
+            Some((start, stop)) => vec![(0, start), (stop, end)],
+            None => vec![(0, end)],
+        };
+        let mut digest = Sha512::new();
+        let mut buf = vec![0; DEFAULT_BUF_SIZE];
+        for (from, to) in ranges {
+            r.seek(SeekFrom::Start(from)).await.rst()?;
+            let mut left = to - from;
+            while left > 0 {
+                let n = buf.len().min(left as usize);
+                r.read_exact(&mut buf[..n]).await.rst()?;
+                digest.update(&buf[..n]);
+                left -= n as u64;
+            }
+        }
+        r.seek(SeekFrom::Start(pos)).await.rst()?;
+        verify_signature(&key, digest, &signature)?;
+        self.verify_key = Some(key);
+        Ok(())
+    }
+}
+
+// See `archive_len`
+#[cfg(feature = "signing")]
+async fn archive_len<R: AsyncRead + AsyncSeek + Unpin>(r: &mut R) -> IoResult<u64> {
+    let len = r.seek(SeekFrom::End(0)).await?;
+    if len < RECOVERY_END_SIZE as u64 {
+        return Ok(len);
+    }
+    r.seek(SeekFrom::Start(len - RECOVERY_END_SIZE as u64))
+        .await?;
+    let mut buf = [0; RECOVERY_END_SIZE];
+    r.read_exact(&mut buf).await?;
+    Ok(trailer_archive_len(len, &buf))
+}
+
+type Next<R> =
+    Pin<Box<dyn Future<Output = (AsyncDecode<R>, Option<Result<EntryEvent, Error>>)> + Send>>;
+
+pub struct EntryStream<R> {
+    // Owned by the pending future while an item is read
+    decode: Option<AsyncDecode<R>>,
+    next: Option<Next<R>>,
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> Stream for EntryStream<R> {
+    type Item = Result<EntryEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        if self.next.is_none() {
+            let mut decode = match self.decode.take() {
+                Some(decode) => decode,
+                None => return Poll::Ready(None),
+            };
+            self.next = Some(Box::pin(async move {
+                let item = match decode.next_data().await {
+                    Ok(Some(data)) => Some(Ok(EntryEvent::Data(data))),
+                    Ok(None) => decode
+                        .next_entry()
+                        .await
+                        .transpose()
+                        .map(|rst| rst.map(EntryEvent::Entry)),
+                    Err(err) => Some(Err(err)),
+                };
+                (decode, item)
+            }));
+        }
+        let (decode, item) = match self.next.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Ready(ready) => ready,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.next = None;
+        // Nothing can be read after an error or at the end
+        if matches!(item, Some(Ok(_))) {
+            self.decode = Some(decode);
+        }
+        Poll::Ready(item)
+    }
+}
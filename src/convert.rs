@@ -0,0 +1,570 @@
+use crate::app::Options;
+use crate::progress::Reporter;
+use crate::{buf_reader, buf_writer, encoder, exit, temp_path, ThrowError};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use mei::{Decode, Encode, FileType, Manifest, Metadata, DEFAULT_BUF_SIZE};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR, MAIN_SEPARATOR_STR};
+use tar::{Builder, EntryType, Header};
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+    Mei,
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    // From the file extension, the volumes of a split archive are mei archives
+    fn from_path(p: &str) -> Option<Self> {
+        let name = p.to_ascii_lowercase();
+        let volume = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        if name.ends_with(".mei") || (volume.len() < name.len() && volume.ends_with(".mei.")) {
+            Some(Format::Mei)
+        } else if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+fn format(p: &str) -> Format {
+    Format::from_path(p).unwrap_or_else(|| {
+        exit!(
+            "Unknown archive format of '{}', expected .mei, .tar, .tar.gz, .tgz or .zip",
+            p
+        )
+    })
+}
+
+// Convert between a mei archive and a tar or zip archive, nothing is extracted to disk
+pub fn convert_archive(mut options: Options) {
+    let (from, to) = (format(&options.input), format(&options.output));
+    if (from == Format::Mei) == (to == Format::Mei) {
+        exit!("Either the input or the output of 'convert' must be a mei archive");
+    }
+    if !Path::new(&options.input).exists() && from != Format::Mei {
+        exit!("'{}' does not exist", options.input);
+    }
+    if !options.force && Path::new(&options.output).exists() {
+        exit!("'{}' already exist", options.output);
+    }
+
+    // Written next to the output, so that it can be renamed over it
+    let output = Path::new(&options.output).to_path_buf();
+    let temp = output.with_file_name(temp_path());
+    let fail = |err: Box<dyn Error>| -> ! {
+        let _ = fs::remove_file(&temp);
+        exit!("{}", err)
+    };
+    if from == Format::Mei {
+        let reader = buf_reader(&options.input);
+        let size = reader.get_ref().size();
+        let password = options.password.as_ref().map(|s| s.as_str());
+        let mut decode = Decode::new(reader, password, DEFAULT_BUF_SIZE).throw();
+        // Tar headers need the size of a file before its content,
+        // archives older than the manifest are spooled next to the output to find it
+        let manifest = match decode.read_manifest() {
+            Ok(manifest) => Some(manifest),
+            Err(mei::Error::InvalidVersion) => None,
+            Err(err) => Err(err).throw(),
+        };
+        decode.progress(
+            Box::new(Reporter::new(options.report, "Converting")),
+            Some(size),
+        );
+        let writer = buf_writer(&temp, true);
+        let mut spool = Spool::new(output.with_file_name(temp_path()));
+        let rst = from_mei(&mut decode, manifest.as_ref(), to, writer, &mut spool);
+        // Removed before exiting
+        drop(spool);
+        rst.unwrap_or_else(|err| fail(err));
+    } else {
+        let f = File::open(&options.input)
+            .unwrap_or_else(|err| exit!("Failed to open '{}': {}", options.input, err));
+        let writer = buf_writer(&temp, true);
+        let mut encode = encoder(&mut options, writer).unwrap_or_else(|err| fail(err.into()));
+        encode.progress(Box::new(Reporter::new(options.report, "Converting")));
+        to_mei(&mut encode, from, BufReader::new(f)).unwrap_or_else(|err| fail(err));
+        encode.finish().unwrap_or_else(|err| fail(err.into()));
+    }
+    fs::rename(&temp, &output).unwrap_or_else(|err| fail(err.into()));
+}
+
+fn to_mei<R: Read + Seek, W: Write>(encode: &mut Encode<W>, from: Format, r: R) -> Result<()> {
+    match from {
+        Format::Tar => read_tar(encode, r),
+        Format::TarGz => read_tar(encode, GzDecoder::new(r)),
+        _ => read_zip(encode, r),
+    }
+}
+
+// Entry paths always use `/` in tar and zip archives
+fn entry_path(p: &str) -> Option<String> {
+    let p = p.trim_start_matches("./").trim_end_matches('/');
+    if p.is_empty() {
+        None
+    } else {
+        Some(p.replace('/', MAIN_SEPARATOR_STR))
+    }
+}
+
+fn skip(p: &str, reason: &str) {
+    eprintln!("Skipping '{}': {}", p, reason);
+}
+
+fn read_tar<R: Read, W: Write>(encode: &mut Encode<W>, r: R) -> Result<()> {
+    let mut archive = tar::Archive::new(r);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let p = match entry_path(&name) {
+            Some(p) => p,
+            None => continue,
+        };
+        let header = entry.header();
+        let metadata = Metadata {
+            mtime: header.mtime().unwrap_or_default(),
+            mode: header.mode().unwrap_or_default(),
+        };
+        match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                encode.write_file_with_metadata(&p, &metadata, &mut entry)?;
+            }
+            EntryType::Directory => encode.write_directory_with_metadata(&p, &metadata)?,
+            _ => skip(&name, "only files and directories can be converted"),
+        }
+    }
+    Ok(())
+}
+
+fn read_zip<R: Read + Seek, W: Write>(encode: &mut Encode<W>, r: R) -> Result<()> {
+    let mut archive = ZipArchive::new(r)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let p = match entry_path(file.name()) {
+            Some(p) => p,
+            None => continue,
+        };
+        let metadata = Metadata {
+            mtime: zip_mtime(file.last_modified()),
+            mode: file.unix_mode().unwrap_or_default() & 0o7777,
+        };
+        if file.is_dir() {
+            encode.write_directory_with_metadata(&p, &metadata)?;
+        } else {
+            encode.write_file_with_metadata(&p, &metadata, &mut file)?;
+        }
+    }
+    Ok(())
+}
+
+// The entries of a mei archive are written to a tar or zip archive
+trait ArchiveWriter {
+    fn add_directory(&mut self, p: &str, metadata: &Metadata) -> io::Result<()>;
+    // `size` is None if it is not known, unless `needs_size` is true
+    fn add_file(
+        &mut self,
+        p: &str,
+        metadata: &Metadata,
+        size: Option<u64>,
+        data: &mut dyn Read,
+    ) -> io::Result<()>;
+    fn needs_size(&self) -> bool;
+}
+
+// The mode of an entry without permissions
+fn mode(metadata: &Metadata, default: u32) -> u32 {
+    match metadata.mode & 0o7777 {
+        0 => default,
+        mode => mode,
+    }
+}
+
+impl<W: Write> ArchiveWriter for Builder<W> {
+    fn add_directory(&mut self, p: &str, metadata: &Metadata) -> io::Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(mode(metadata, 0o755));
+        header.set_mtime(metadata.mtime);
+        self.append_data(&mut header, format!("{}/", p), io::empty())
+    }
+
+    fn add_file(
+        &mut self,
+        p: &str,
+        metadata: &Metadata,
+        size: Option<u64>,
+        data: &mut dyn Read,
+    ) -> io::Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(size.expect("tar entries need the size"));
+        header.set_mode(mode(metadata, 0o644));
+        header.set_mtime(metadata.mtime);
+        self.append_data(&mut header, p, data)
+    }
+
+    fn needs_size(&self) -> bool {
+        true
+    }
+}
+
+impl<W: Write + Seek> ArchiveWriter for ZipWriter<W> {
+    fn add_directory(&mut self, p: &str, metadata: &Metadata) -> io::Result<()> {
+        let options = zip_options(metadata, 0o755, Some(0));
+        Ok(ZipWriter::add_directory(self, p, options)?)
+    }
+
+    fn add_file(
+        &mut self,
+        p: &str,
+        metadata: &Metadata,
+        size: Option<u64>,
+        data: &mut dyn Read,
+    ) -> io::Result<()> {
+        self.start_file(p, zip_options(metadata, 0o644, size))?;
+        io::copy(data, self).map(|_| ())
+    }
+
+    fn needs_size(&self) -> bool {
+        false
+    }
+}
+
+// A file of unknown size is written with the zip64 fields, in case it is large
+fn zip_options(metadata: &Metadata, default: u32, size: Option<u64>) -> FileOptions {
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(mode(metadata, default))
+        .large_file(size.map_or(true, |size| size >= u32::MAX as u64));
+    match zip_time(metadata.mtime) {
+        Some(time) => options.last_modified_time(time),
+        None => options,
+    }
+}
+
+fn from_mei<R: Read, W: Write + Seek>(
+    decode: &mut Decode<R>,
+    manifest: Option<&Manifest>,
+    to: Format,
+    writer: W,
+    spool: &mut Spool,
+) -> Result<()> {
+    match to {
+        Format::Tar => {
+            let mut builder = Builder::new(writer);
+            write_entries(decode, manifest, &mut builder, spool)?;
+            builder.into_inner()?.flush()?;
+        }
+        Format::TarGz => {
+            let mut builder = Builder::new(GzEncoder::new(writer, Compression::default()));
+            write_entries(decode, manifest, &mut builder, spool)?;
+            builder.into_inner()?.finish()?.flush()?;
+        }
+        _ => {
+            let mut zip = ZipWriter::new(writer);
+            write_entries(decode, manifest, &mut zip, spool)?;
+            zip.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Files whose size is not known are copied here first when the writer needs it,
+// the file is removed when it is dropped
+struct Spool {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Spool {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None }
+    }
+
+    // The spooled content and its size
+    fn write(&mut self, r: &mut dyn Read) -> io::Result<(&mut File, u64)> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&self.path)?,
+        };
+        let file = self.file.insert(file);
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        let size = io::copy(r, file)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok((file, size))
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn write_entries<R: Read>(
+    decode: &mut Decode<R>,
+    manifest: Option<&Manifest>,
+    w: &mut dyn ArchiveWriter,
+    spool: &mut Spool,
+) -> Result<()> {
+    for entry in decode.entries() {
+        let mut entry = entry?;
+        let p = entry.path().replace(MAIN_SEPARATOR, "/");
+        let metadata = *entry.metadata();
+        match entry.file_type() {
+            FileType::Directory => w.add_directory(&p, &metadata)?,
+            FileType::Deleted => skip(&p, "deleted paths cannot be converted"),
+            FileType::File => match manifest.and_then(|manifest| manifest.get(entry.path())) {
+                Some(m) if m.file_type == FileType::File => {
+                    let mut data = Exact {
+                        inner: &mut entry,
+                        remaining: m.size,
+                    };
+                    w.add_file(&p, &metadata, Some(m.size), &mut data)?;
+                    if entry.read(&mut [0])? != 0 {
+                        return Err(
+                            format!("The size of '{}' does not match the manifest", p).into()
+                        );
+                    }
+                }
+                // An earlier entry of a path that was added again, or an archive without a manifest
+                _ if w.needs_size() => {
+                    let (file, size) = spool.write(&mut entry)?;
+                    w.add_file(&p, &metadata, Some(size), &mut file.take(size))?;
+                }
+                _ => w.add_file(&p, &metadata, None, &mut entry)?,
+            },
+        }
+    }
+    Ok(())
+}
+
+// Reads exactly `remaining` bytes, so that the size in a tar header is right
+struct Exact<'a, R> {
+    inner: &'a mut R,
+    remaining: u64,
+}
+
+impl<'a, R: Read> Read for Exact<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let max = (buf.len() as u64).min(self.remaining) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file is shorter than its size in the manifest",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// Zip times have no time zone, they are read and written as UTC
+fn zip_time(mtime: u64) -> Option<DateTime> {
+    let days = (mtime / 86400) as i64;
+    let secs = mtime % 86400;
+    let (y, m, d) = civil_from_days(days);
+    DateTime::from_date_and_time(
+        u16::try_from(y).ok()?,
+        m as u8,
+        d as u8,
+        (secs / 3600) as u8,
+        (secs / 60 % 60) as u8,
+        (secs % 60) as u8,
+    )
+    .ok()
+}
+
+fn zip_mtime(time: DateTime) -> u64 {
+    let days = days_from_civil(time.year() as i64, time.month() as u32, time.day() as u32);
+    let secs = time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+    (days * 86400 + secs).max(0) as u64
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar, and the reverse
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mei::CompressParams;
+    use std::env::temp_dir;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::from_path("a.mei"), Some(Format::Mei));
+        assert_eq!(Format::from_path("A.MEI"), Some(Format::Mei));
+        assert_eq!(Format::from_path("a.mei.001"), Some(Format::Mei));
+        assert_eq!(Format::from_path("a.tar"), Some(Format::Tar));
+        assert_eq!(Format::from_path("a.tar.gz"), Some(Format::TarGz));
+        assert_eq!(Format::from_path("a.tgz"), Some(Format::TarGz));
+        assert_eq!(Format::from_path("a.zip"), Some(Format::Zip));
+        assert_eq!(Format::from_path("a.001"), None);
+        assert_eq!(Format::from_path("a.mei."), None);
+        assert_eq!(Format::from_path("mei"), None);
+    }
+
+    #[test]
+    fn test_entry_path() {
+        let p = format!("a{}b", MAIN_SEPARATOR);
+        assert_eq!(entry_path("a/b").as_deref(), Some(p.as_str()));
+        assert_eq!(entry_path("./a/b/").as_deref(), Some(p.as_str()));
+        assert_eq!(entry_path("./"), None);
+        assert_eq!(entry_path(""), None);
+    }
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        for days in (-1_000_000..1_000_000).step_by(997) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn test_zip_time() {
+        // 2020-09-13 12:26:40 UTC
+        let time = zip_time(1_600_000_000).unwrap();
+        assert_eq!((time.year(), time.month(), time.day()), (2020, 9, 13));
+        assert_eq!((time.hour(), time.minute(), time.second()), (12, 26, 40));
+        assert_eq!(zip_mtime(time), 1_600_000_000);
+        // Zip times start in 1980
+        assert!(zip_time(0).is_none());
+    }
+
+    fn entries(buf: Vec<u8>) -> Vec<(FileType, String, Metadata, Vec<u8>)> {
+        let mut decode = Decode::new(Cursor::new(buf), None, DEFAULT_BUF_SIZE).unwrap();
+        decode
+            .entries()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                let p = entry.path().to_string();
+                (entry.file_type(), p, *entry.metadata(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+        let dir = Metadata {
+            mtime: 1_600_000_000,
+            mode: 0o750,
+        };
+        let file = Metadata {
+            mtime: 1_600_000_002,
+            mode: 0o600,
+        };
+        let p = format!("dir{}a", MAIN_SEPARATOR);
+        encode.write_directory_with_metadata("dir", &dir).unwrap();
+        encode
+            .write_file_with_metadata(&p, &file, &mut &b"hello"[..])
+            .unwrap();
+        let buf = encode.finish().unwrap();
+
+        for format in [Format::Tar, Format::TarGz, Format::Zip] {
+            let mut decode = Decode::new(Cursor::new(buf.clone()), None, DEFAULT_BUF_SIZE).unwrap();
+            let manifest = decode.read_manifest().unwrap();
+            let mut converted = Cursor::new(Vec::new());
+            let mut spool = Spool::new(temp_dir().join("mei_convert_round_trip"));
+            from_mei(
+                &mut decode,
+                Some(&manifest),
+                format,
+                &mut converted,
+                &mut spool,
+            )
+            .unwrap();
+
+            let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+            converted.set_position(0);
+            to_mei(&mut encode, format, converted).unwrap();
+            assert_eq!(
+                entries(encode.finish().unwrap()),
+                entries(buf.clone()),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_old_version() {
+        let buf = include_bytes!("../tests/fixtures/v1.mei").to_vec();
+        let spool = temp_dir().join("mei_convert_old_version");
+        for format in [Format::Tar, Format::Zip] {
+            let mut decode = Decode::new(Cursor::new(buf.clone()), None, DEFAULT_BUF_SIZE).unwrap();
+            assert!(decode.read_manifest().is_err());
+            let mut converted = Cursor::new(Vec::new());
+            let mut spool = Spool::new(spool.clone());
+            from_mei(&mut decode, None, format, &mut converted, &mut spool).unwrap();
+
+            let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+            converted.set_position(0);
+            to_mei(&mut encode, format, converted).unwrap();
+            let content = |buf| {
+                entries(buf)
+                    .into_iter()
+                    .map(|(file_type, p, _, data)| (file_type, p, data))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(content(encode.finish().unwrap()), content(buf.clone()));
+        }
+        // The spooled files are removed
+        assert!(!spool.exists());
+    }
+}
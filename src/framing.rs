@@ -0,0 +1,431 @@
+use super::*;
+
+// The layout of an archive, parsed without any IO so that `Decode` and `AsyncDecode` read
+// the same format: `need` tells the reader what to read next, `feed` takes what was read
+// and returns a frame once one is complete
+pub(crate) struct Framing {
+    state: State,
+    version: u8,
+    method: EncryptMethod,
+    info: String,
+    summary: Option<Summary>,
+    manifest: Option<Manifest>,
+    // The manifest chunks read so far
+    buf: Vec<u8>,
+    signed: bool,
+    #[cfg(feature = "signing")]
+    signature: Option<Signature>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum State {
+    Head,
+    Info,
+    Method,
+    // The key derivation parameters of a version 1 archive
+    Params,
+    KeySlots,
+    // The info of a private archive, the first encrypted chunk
+    PrivateInfo,
+    Sync,
+    Tag,
+    // The type is part of the header in private mode
+    Header(Option<u8>),
+    File,
+    Manifest,
+    Trailer,
+    Signature,
+    Locator,
+    Offset,
+    Done,
+}
+
+/// What `Framing` needs to be read next
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Need {
+    /// Exactly `len` bytes, they are not part of the signed digest if `signed` is false
+    Bytes { len: usize, signed: bool },
+    /// One byte, None at the end of the archive. A signature covers what was read before it
+    Byte,
+    /// A chunk, decrypted once the header has been read. None at the end of a chunk sequence
+    Data,
+    /// The archive has been read
+    Nothing,
+}
+
+/// What was read for a `Need`
+pub(crate) enum Input {
+    Bytes(Vec<u8>),
+    Byte(Option<u8>),
+    Data(Option<Vec<u8>>),
+}
+
+pub(crate) enum Frame {
+    /// The content cipher is opened from the header before reading on
+    Header(RawHeader),
+    /// The info of a private archive
+    Info(String),
+    Entry(FileType, String, Metadata),
+    /// Compressed data of the current file
+    Data(Vec<u8>),
+    FileEnd,
+    /// The end of archive marker and the summary
+    End,
+    /// Everything after the entries has been read too
+    Done,
+}
+
+pub(crate) struct RawHeader {
+    pub header: Header,
+    // The key slots as they are stored, and their range, they are not signed
+    pub slots: Option<Vec<u8>>,
+    pub slots_range: Option<(u64, u64)>,
+    // Version 1 derives the content key from the password, there are no key slots
+    pub params: Option<ScryptParams>,
+}
+
+impl RawHeader {
+    // The content cipher, the password unlocks one of the key slots
+    pub fn open(&self, password: Option<&str>) -> Result<Option<Crypto>, Error> {
+        if let Some(params) = &self.params {
+            return open_legacy_crypto(params, password);
+        }
+        let slots = match &self.slots {
+            Some(buf) => Some(read_key_slots(&mut &buf[..])?),
+            None => None,
+        };
+        open_crypto(self.header.method, slots, password)
+    }
+
+    // Whether the password is needed to read the content
+    #[cfg(feature = "async")]
+    pub fn encrypted(&self) -> bool {
+        self.slots.is_some() || self.params.is_some()
+    }
+}
+
+impl Framing {
+    pub fn new() -> Self {
+        Self {
+            state: State::Head,
+            version: 0,
+            method: EncryptMethod::None,
+            info: String::new(),
+            summary: None,
+            manifest: None,
+            buf: Vec::new(),
+            signed: false,
+            #[cfg(feature = "signing")]
+            signature: None,
+        }
+    }
+
+    pub fn private(&self) -> bool {
+        self.method == EncryptMethod::Aes256GcmPrivate
+    }
+
+    /// The format version of the archive being read
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The data of a file has not been read to the end
+    pub fn in_file(&self) -> bool {
+        self.state == State::File
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Nothing can be read after an error
+    pub fn fail(&mut self) {
+        self.state = State::Done;
+    }
+
+    pub fn summary(&self) -> Option<&Summary> {
+        self.summary.as_ref()
+    }
+
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    pub fn take_manifest(&mut self) -> Option<Manifest> {
+        self.manifest.take()
+    }
+
+    /// A signature block was read, it is not verified
+    pub fn signed(&self) -> bool {
+        self.signed
+    }
+
+    /// The signature block, once the archive has been read
+    #[cfg(feature = "signing")]
+    pub fn signature(&self) -> Option<&Signature> {
+        self.signature.as_ref()
+    }
+
+    /// Continue with the entry header after a sync marker found by `SyncScan`
+    pub fn resync(&mut self) {
+        self.state = self.header_state();
+    }
+
+    pub fn need(&self) -> Need {
+        match self.state {
+            State::Head => Need::Bytes {
+                len: HEAD.len() + VERSION.len(),
+                signed: true,
+            },
+            State::Method => Need::Bytes {
+                len: 1,
+                signed: true,
+            },
+            State::Params => Need::Bytes {
+                len: SCRYPT_PARAMS_SIZE,
+                signed: true,
+            },
+            State::KeySlots => Need::Bytes {
+                len: KEY_SLOT_SIZE * KEY_SLOTS,
+                signed: false,
+            },
+            State::Sync => Need::Bytes {
+                len: SYNC.len(),
+                signed: true,
+            },
+            State::Signature => Need::Bytes {
+                len: SIGNATURE_SIZE - 1,
+                signed: false,
+            },
+            State::Locator => Need::Bytes {
+                len: LOCATOR_SIZE,
+                signed: false,
+            },
+            State::Offset => Need::Bytes {
+                len: LOCATOR_SIZE - 1,
+                signed: false,
+            },
+            State::Info
+            | State::PrivateInfo
+            | State::Header(_)
+            | State::File
+            | State::Manifest => Need::Data,
+            State::Tag | State::Trailer => Need::Byte,
+            State::Done => Need::Nothing,
+        }
+    }
+
+    pub fn feed(&mut self, input: Input) -> Result<Option<Frame>, Error> {
+        match (self.state, input) {
+            (State::Head, Input::Bytes(buf)) => {
+                let mut buf = &buf[..];
+                read_head(&mut buf)?;
+                self.version = read_version(&mut buf)?;
+                self.state = State::Info;
+                Ok(None)
+            }
+            (State::Info, Input::Data(data)) => {
+                self.info = String::from_utf8(data.unwrap_or_default()).map_err(Error::Utf8)?;
+                self.state = State::Method;
+                Ok(None)
+            }
+            (State::Method, Input::Bytes(buf)) => {
+                self.method = read_encrypt_method(&mut &buf[..])?;
+                self.state = match (self.method, self.version) {
+                    (EncryptMethod::None, _) => return self.header(None, None).map(Some),
+                    (EncryptMethod::Aes256GcmPrivate, 1) => return Err(Error::InvalidEncryptMethod),
+                    (_, 1) => State::Params,
+                    _ => State::KeySlots,
+                };
+                Ok(None)
+            }
+            (State::Params, Input::Bytes(buf)) => {
+                let params = read_scrypt_params(&mut &buf[..])?;
+                self.header(None, Some(params)).map(Some)
+            }
+            (State::KeySlots, Input::Bytes(buf)) => self.header(Some(buf), None).map(Some),
+            (State::PrivateInfo, Input::Data(data)) => {
+                let info = String::from_utf8(data.unwrap_or_default()).map_err(Error::Utf8)?;
+                self.state = self.entry_state();
+                Ok(Some(Frame::Info(info)))
+            }
+            (State::Sync, Input::Bytes(buf)) => {
+                read_sync(&mut &buf[..])?;
+                self.state = self.header_state();
+                Ok(None)
+            }
+            // Before the end of archive marker, an archive ends after its last entry
+            // or with the signature block
+            (State::Tag, Input::Byte(None)) if self.version < END_VERSION => {
+                self.state = State::Done;
+                Ok(Some(Frame::Done))
+            }
+            (State::Tag, Input::Byte(Some(SIGNATURE))) if self.version < END_VERSION => {
+                self.state = State::Signature;
+                Ok(None)
+            }
+            (State::Tag, Input::Byte(tag)) => {
+                self.state = State::Header(Some(tag.ok_or(Error::Truncated)?));
+                Ok(None)
+            }
+            // A private archive could end without the end of archive marker
+            (State::Header(None), Input::Data(None)) if self.version < END_VERSION => {
+                self.state = State::Done;
+                Ok(Some(Frame::Done))
+            }
+            (State::Header(tag), Input::Data(data)) => {
+                let mut buf = data.ok_or(Error::FilePath)?;
+                let tag = match tag {
+                    Some(tag) => tag,
+                    None => buf.remove(0),
+                };
+                // The marker is only followed by the summary and the manifest in later versions
+                if tag == END {
+                    if self.version >= END_VERSION {
+                        self.summary = Some(Summary::parse(&buf)?);
+                    }
+                    self.state = if self.version < MANIFEST_VERSION {
+                        State::Trailer
+                    } else {
+                        State::Manifest
+                    };
+                    return Ok(Some(Frame::End));
+                }
+                let (file_type, path, metadata) = if self.version < METADATA_VERSION {
+                    let path = String::from_utf8(buf).map_err(Error::Utf8)?;
+                    (FileType::parse(tag)?, path, Metadata::default())
+                } else {
+                    parse_entry(tag, buf)?
+                };
+                self.state = match file_type {
+                    FileType::File => State::File,
+                    _ => self.entry_state(),
+                };
+                Ok(Some(Frame::Entry(file_type, path, metadata)))
+            }
+            (State::File, Input::Data(Some(data))) => Ok(Some(Frame::Data(data))),
+            (State::File, Input::Data(None)) => {
+                self.state = self.entry_state();
+                Ok(Some(Frame::FileEnd))
+            }
+            (State::Manifest, Input::Data(Some(data))) => {
+                self.buf.extend_from_slice(&data);
+                Ok(None)
+            }
+            (State::Manifest, Input::Data(None)) => {
+                self.manifest = Some(Manifest::parse(&std::mem::take(&mut self.buf))?);
+                self.state = State::Trailer;
+                Ok(None)
+            }
+            // The signature block if the archive is signed, then the manifest locator.
+            // Without the manifest the archive ends here if it is not signed
+            (State::Trailer, Input::Byte(None)) if self.version < MANIFEST_VERSION => {
+                self.state = State::Done;
+                Ok(Some(Frame::Done))
+            }
+            (State::Trailer, Input::Byte(tag)) => {
+                self.state = match tag {
+                    Some(SIGNATURE) => State::Signature,
+                    Some(MANIFEST) if self.version >= MANIFEST_VERSION => State::Offset,
+                    Some(byte) => return Err(Error::FileType(byte)),
+                    None => return Err(Error::Truncated),
+                };
+                Ok(None)
+            }
+            (State::Signature, Input::Bytes(buf)) => {
+                self.signed = true;
+                // Without the signing feature the signature block is skipped
+                #[cfg(feature = "signing")]
+                {
+                    self.signature = Some(read_signature(&mut &buf[..])?);
+                }
+                #[cfg(not(feature = "signing"))]
+                let _ = buf;
+                if self.version < MANIFEST_VERSION {
+                    self.state = State::Done;
+                    return Ok(Some(Frame::Done));
+                }
+                self.state = State::Locator;
+                Ok(None)
+            }
+            (State::Locator, Input::Bytes(buf)) => {
+                read_locator(&mut &buf[..])?;
+                self.state = State::Done;
+                Ok(Some(Frame::Done))
+            }
+            (State::Offset, Input::Bytes(_)) => {
+                self.state = State::Done;
+                Ok(Some(Frame::Done))
+            }
+            (state, _) => unreachable!("unexpected input for {:?}", state),
+        }
+    }
+
+    fn header(
+        &mut self,
+        slots: Option<Vec<u8>>,
+        params: Option<ScryptParams>,
+    ) -> Result<Frame, Error> {
+        let slots_range = slots.as_ref().map(|_| {
+            let start = (HEAD.len() + VERSION.len() + 2 + self.info.len() + 1) as u64;
+            (start, start + (KEY_SLOT_SIZE * KEY_SLOTS) as u64)
+        });
+        let key_slots = match (&slots, &params) {
+            (Some(buf), _) => read_key_slots(&mut &buf[..])?
+                .iter()
+                .flatten()
+                .map(|slot| slot.params.clone())
+                .collect(),
+            (None, Some(params)) => vec![params.clone()],
+            (None, None) => Vec::new(),
+        };
+        self.state = if self.private() {
+            State::PrivateInfo
+        } else {
+            self.entry_state()
+        };
+        Ok(Frame::Header(RawHeader {
+            header: Header {
+                version: self.version,
+                info: std::mem::take(&mut self.info),
+                method: self.method,
+                key_slots,
+            },
+            slots,
+            slots_range,
+            params,
+        }))
+    }
+
+    // Before the next entry header, with its sync marker if the version has them
+    fn entry_state(&self) -> State {
+        if self.version < SYNC_VERSION {
+            self.header_state()
+        } else {
+            State::Sync
+        }
+    }
+
+    // After the sync marker of an entry
+    fn header_state(&self) -> State {
+        if self.private() {
+            State::Header(None)
+        } else {
+            State::Tag
+        }
+    }
+}
+
+// Finds the next sync marker in a damaged archive, one byte at a time
+#[derive(Default)]
+pub(crate) struct SyncScan {
+    window: [u8; SYNC.len()],
+}
+
+impl SyncScan {
+    pub fn push(&mut self, byte: u8) -> bool {
+        self.window.rotate_left(1);
+        self.window[SYNC.len() - 1] = byte;
+        self.window == SYNC
+    }
+}
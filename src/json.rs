@@ -0,0 +1,144 @@
+use std::fmt::Write;
+
+// A JSON object built field by field, strings are escaped
+pub struct Object(String);
+
+impl Object {
+    pub fn new() -> Self {
+        Self(String::from("{"))
+    }
+
+    pub fn field<V: Value>(mut self, key: &str, value: V) -> Self {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        string(&mut self.0, key);
+        self.0.push(':');
+        value.write(&mut self.0);
+        self
+    }
+}
+
+impl std::fmt::Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}}}", self.0)
+    }
+}
+
+pub trait Value {
+    fn write(&self, out: &mut String);
+}
+
+impl Value for &str {
+    fn write(&self, out: &mut String) {
+        string(out, self);
+    }
+}
+
+impl Value for String {
+    fn write(&self, out: &mut String) {
+        string(out, self);
+    }
+}
+
+impl Value for u64 {
+    fn write(&self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl Value for u32 {
+    fn write(&self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl Value for f64 {
+    fn write(&self, out: &mut String) {
+        if self.is_finite() {
+            let _ = write!(out, "{}", self);
+        } else {
+            out.push_str("null");
+        }
+    }
+}
+
+impl Value for bool {
+    fn write(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+impl Value for Object {
+    fn write(&self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl<V: Value> Value for Option<V> {
+    fn write(&self, out: &mut String) {
+        match self {
+            Some(value) => value.write(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<V: Value> Value for Vec<V> {
+    fn write(&self, out: &mut String) {
+        out.push('[');
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            value.write(out);
+        }
+        out.push(']');
+    }
+}
+
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string() {
+        let mut out = String::new();
+        string(&mut out, "a\"b\\c/\n\r\t\u{1}\u{1f}\u{7f}é");
+        // DEL and non-ASCII characters are valid in JSON strings
+        assert_eq!(out, "\"a\\\"b\\\\c/\\n\\r\\t\\u0001\\u001f\u{7f}é\"");
+    }
+
+    #[test]
+    fn test_object() {
+        let object = Object::new()
+            .field("path", "a\"b")
+            .field("size", 5u64)
+            .field("ratio", f64::NAN)
+            .field("error", None::<String>)
+            .field("items", vec![true, false]);
+        assert_eq!(
+            object.to_string(),
+            r#"{"path":"a\"b","size":5,"ratio":null,"error":null,"items":[true,false]}"#
+        );
+        assert_eq!(Object::new().to_string(), "{}");
+    }
+}
@@ -1,9 +1,45 @@
+#[cfg(feature = "encryption")]
 use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
+#[cfg(feature = "encryption")]
 use aes_gcm::Aes256Gcm;
-use brotli::{CompressorReader, DecompressorWriter};
+use brotli::{CompressorWriter, Decompressor, DecompressorWriter};
+#[cfg(feature = "signing")]
+use ed25519_dalek::{Signature, Signer, Verifier};
+#[cfg(feature = "encryption")]
 use rand::{prelude::Rng, thread_rng};
-use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use sha2::{Digest, Sha256, Sha512};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{
+    self, BufReader, Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write,
+};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 use std::string::FromUtf8Error;
+use std::time::{Duration, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+#[cfg(feature = "signing")]
+pub use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "async")]
+pub use async_io::{AsyncDecode, AsyncEncode, AsyncEntry, EntryEvent, EntryStream};
+
+mod framing;
+use framing::{Frame, Framing, Input, Need, SyncScan};
+
+mod volume;
+pub use volume::{VolumeReader, VolumeWriter};
+
+#[cfg(feature = "recovery")]
+mod recovery;
+#[cfg(feature = "recovery")]
+pub use recovery::{add_recovery, check_recovery, recovery_percent, repair, RecoveryReport};
 
 // Reader buffer size
 pub const DEFAULT_BUF_SIZE: usize = 1024 * 8;
@@ -19,6 +55,7 @@ pub const DEFAULT_SCRYPT_P: u32 = 1;
 // File encryption
 const ENCRYPT_NONE: [u8; 1] = [0];
 const ENCRYPT_AES_256_GCM: [u8; 1] = [1];
+const ENCRYPT_AES_256_GCM_PRIVATE: [u8; 1] = [2];
 
 #[derive(Debug)]
 pub enum Error {
@@ -32,11 +69,186 @@ pub enum Error {
     FilePath,
     PasswordRequired,
     NoPasswordRequired,
+    /// The password does not unlock any key slot
+    InvalidPassword,
+    /// The archive is not signed
+    SignatureMissing,
+    /// The archive has been modified or signed by another key
+    InvalidSignature,
+    /// All key slots are in use
+    NoFreeKeySlot,
+    /// The only remaining key slot cannot be removed
+    LastKeySlot,
+    /// A version 1 archive has no key slots to change in place, see `rekey_rewrite`
+    RewriteRequired,
+    /// The archive is encrypted, but the `encryption` feature is disabled
+    EncryptionDisabled,
+    /// The archive ends before the end of archive marker
+    Truncated,
+    /// The sync marker before an entry is missing
+    InvalidSync,
     /// Archive info / File path
     Utf8(FromUtf8Error),
     /// Chunk length cannot be greater than 65535
     ChunkTooLong,
+    /// Entry path length cannot be greater than 65535
+    PathTooLong,
+    /// A volume of a split archive does not exist
+    VolumeMissing(PathBuf),
+    /// A volume is not the expected part of a split archive
+    VolumeOrder {
+        path: PathBuf,
+        expected: u32,
+        found: u32,
+    },
+    /// A volume belongs to another split archive
+    VolumeMismatch(PathBuf),
+    /// The archive has no recovery record
+    RecoveryMissing,
+    /// Both copies of the recovery record checksums are damaged
+    RecoveryDamaged,
+    /// The archive has a recovery record, but the `recovery` feature is disabled
+    RecoveryDisabled,
     IO(IoError),
+    /// An error with the operation, entry path and archive offset it happened at
+    Context {
+        operation: Operation,
+        path: Option<String>,
+        offset: Option<u64>,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// The error without its context
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            err => err,
+        }
+    }
+
+    fn context(self, operation: Operation, path: Option<&str>, offset: Option<u64>) -> Error {
+        match self {
+            Error::Context { .. } => self,
+            err => Error::Context {
+                operation,
+                path: path.map(String::from),
+                offset,
+                source: Box::new(err),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidHead => write!(f, "not a mei archive"),
+            Error::InvalidVersion => write!(f, "unsupported archive version"),
+            Error::InvalidEncryptMethod => write!(f, "unknown encryption method"),
+            Error::InvalidScryptParams => write!(f, "invalid scrypt parameters"),
+            Error::EncryptionFailed => write!(f, "encryption failed"),
+            Error::DecryptionFailed => write!(f, "authentication tag mismatch"),
+            Error::FileType(byte) => write!(f, "unknown entry type {:#04x}", byte),
+            Error::FilePath => write!(f, "invalid entry header"),
+            Error::PasswordRequired => {
+                write!(f, "the archive is encrypted, a password is required")
+            }
+            Error::NoPasswordRequired => write!(f, "the archive is not encrypted"),
+            Error::InvalidPassword => write!(f, "invalid password"),
+            Error::SignatureMissing => write!(f, "the archive is not signed"),
+            Error::InvalidSignature => write!(f, "invalid signature"),
+            Error::NoFreeKeySlot => write!(f, "all {} key slots are in use", KEY_SLOTS),
+            Error::LastKeySlot => write!(f, "the last password cannot be removed"),
+            Error::RewriteRequired => {
+                write!(f, "the archive must be rewritten to change its passwords")
+            }
+            Error::EncryptionDisabled => write!(
+                f,
+                "encrypted archives are not supported, mei was built without the 'encryption' feature"
+            ),
+            Error::Truncated => write!(f, "the archive is truncated"),
+            Error::InvalidSync => write!(f, "missing sync marker before an entry"),
+            Error::Utf8(err) => write!(f, "invalid UTF-8: {}", err),
+            Error::ChunkTooLong => write!(f, "chunk longer than 65535 bytes"),
+            Error::PathTooLong => write!(f, "path longer than 65535 bytes"),
+            Error::VolumeMissing(path) => write!(f, "volume '{}' is missing", path.display()),
+            Error::VolumeOrder {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "volume '{}' is part {} of the archive, expected part {}",
+                path.display(),
+                found,
+                expected
+            ),
+            Error::VolumeMismatch(path) => {
+                write!(f, "volume '{}' belongs to another archive", path.display())
+            }
+            Error::RecoveryMissing => write!(f, "the archive has no recovery record"),
+            Error::RecoveryDamaged => write!(f, "the recovery record is damaged"),
+            Error::RecoveryDisabled => write!(
+                f,
+                "the archive has a recovery record, mei was built without the 'recovery' feature"
+            ),
+            Error::IO(err) => write!(f, "{}", err),
+            Error::Context {
+                operation,
+                path,
+                offset,
+                source,
+            } => {
+                write!(f, "{}", operation)?;
+                if let Some(path) = path {
+                    write!(f, " '{}'", path)?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, " at offset {}", offset)?;
+                }
+                write!(f, ": {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Utf8(err) => Some(err),
+            Error::IO(err) => Some(err),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// What was being done when an error happened
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Operation {
+    ReadEntry,
+    ReadData,
+    WriteEntry,
+    WriteData,
+    Append,
+    Unpack,
+    Compare,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::ReadEntry => "reading the entry header",
+            Operation::ReadData => "reading the data of entry",
+            Operation::WriteEntry => "writing the header of entry",
+            Operation::WriteData => "writing the data of entry",
+            Operation::Append => "adding",
+            Operation::Unpack => "unpacking",
+            Operation::Compare => "comparing",
+        })
+    }
 }
 
 trait IoResultToResult<T> {
@@ -52,34 +264,313 @@ impl<T> IoResultToResult<T> for IoResult<T> {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum FileType {
     File,
     Directory,
+    /// A path that was removed since the reference of an incremental archive
+    Deleted,
 }
 
 impl FileType {
     const DIRECTORY: u8 = 0;
     const FILE: u8 = 1;
+    const DELETED: u8 = 2;
 
     fn parse(byte: u8) -> Result<Self, Error> {
-        if byte == Self::FILE {
-            return Ok(Self::File);
+        match byte {
+            Self::FILE => Ok(Self::File),
+            Self::DIRECTORY => Ok(Self::Directory),
+            Self::DELETED => Ok(Self::Deleted),
+            _ => Err(Error::FileType(byte)),
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Self::File => Self::FILE,
+            Self::Directory => Self::DIRECTORY,
+            Self::Deleted => Self::DELETED,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Metadata {
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: u64,
+    /// Unix permission bits, 0 if unknown
+    pub mode: u32,
+}
+
+impl Metadata {
+    const SIZE: usize = 8 + 4;
+
+    fn parse(buf: &[u8]) -> Self {
+        let mut mtime = [0; 8];
+        mtime.copy_from_slice(&buf[..8]);
+        let mut mode = [0; 4];
+        mode.copy_from_slice(&buf[8..Self::SIZE]);
+        Self {
+            mtime: u64::from_be_bytes(mtime),
+            mode: u32::from_be_bytes(mode),
         }
-        if byte == Self::DIRECTORY {
-            return Ok(Self::Directory);
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.mtime.to_be_bytes());
+        buf.extend_from_slice(&self.mode.to_be_bytes());
+    }
+}
+
+impl From<&fs::Metadata> for Metadata {
+    fn from(m: &fs::Metadata) -> Self {
+        let mtime = m
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&m.permissions());
+        #[cfg(not(unix))]
+        let mode = 0;
+        Self { mtime, mode }
+    }
+}
+
+/// Written at the end of an archive
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Summary {
+    /// Number of files and directories
+    pub entries: u64,
+    /// Total size of the files
+    pub size: u64,
+    /// Total size of the compressed file data
+    pub compressed_size: u64,
+}
+
+impl Summary {
+    const SIZE: usize = 8 * 3;
+
+    fn parse(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() != Self::SIZE {
+            return Err(Error::Truncated);
         }
-        Err(Error::FileType(byte))
+        let n = |i: usize| {
+            let mut b = [0; 8];
+            b.copy_from_slice(&buf[i * 8..i * 8 + 8]);
+            u64::from_be_bytes(b)
+        };
+        Ok(Self {
+            entries: n(0),
+            size: n(1),
+            compressed_size: n(2),
+        })
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.entries.to_be_bytes());
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.compressed_size.to_be_bytes());
     }
+}
+
+/// The files and directories of an archive, written after the summary.
+/// The manifest of an incremental archive also lists the files that did not change
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Manifest {
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub file_type: FileType,
+    pub metadata: Metadata,
+    /// Size of a file, 0 for a directory
+    pub size: u64,
+    /// SHA-256 of the content of a file
+    pub hash: [u8; 32],
+}
+
+/// A difference found by `Manifest::diff`, `Manifest::diff_dir` or `Decode::diff_dir`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    /// The content or the type of the entry is different
+    Modified(String),
+}
 
-    fn write<W: Write>(self, w: &mut W) -> IoResult<usize> {
+impl Change {
+    pub fn path(&self) -> &str {
         match self {
-            FileType::Directory => w.write(&[Self::DIRECTORY]),
-            FileType::File => w.write(&[Self::FILE]),
+            Change::Added(p) | Change::Removed(p) | Change::Modified(p) => p,
+        }
+    }
+}
+
+impl Manifest {
+    // Type + Metadata + Size + Hash + Path length
+    const RECORD_SIZE: usize = 1 + Metadata::SIZE + 8 + 32 + 2;
+
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries.get(path)
+    }
+
+    /// The entries sorted by path
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The changes from this manifest to `other`, sorted by path.
+    /// Files are compared by size and checksum, modification times are ignored
+    pub fn diff(&self, other: &Manifest) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for entry in self.entries() {
+            match other.get(&entry.path) {
+                None => changes.push(Change::Removed(entry.path.clone())),
+                Some(new) if modified(entry, new) => {
+                    changes.push(Change::Modified(new.path.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for entry in other.entries() {
+            if self.get(&entry.path).is_none() {
+                changes.push(Change::Added(entry.path.clone()));
+            }
+        }
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        changes
+    }
+
+    /// Compare with the files in `dir`, where an archive with this manifest was unpacked.
+    /// Only the top level entries of the manifest are looked for in `dir`
+    pub fn diff_dir<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<Change>, Error> {
+        let roots = roots(self.entries().map(|entry| entry.path.as_str()));
+        let mut other = Manifest::default();
+        for (src, p) in walk_roots(dir.as_ref(), &roots)? {
+            let entry = self.disk_entry(&src, p.clone());
+            other.insert(entry.map_err(|err| err.context(Operation::Compare, Some(&p), None))?);
+        }
+        Ok(self.diff(&other))
+    }
+
+    // Files are only hashed if they could be unchanged
+    fn disk_entry(&self, src: &Path, p: String) -> Result<ManifestEntry, Error> {
+        let metadata = fs::metadata(src).rst()?;
+        let (file_type, size) = if metadata.is_dir() {
+            (FileType::Directory, 0)
+        } else {
+            (FileType::File, metadata.len())
+        };
+        let hash = match self.get(&p) {
+            Some(entry)
+                if file_type == FileType::File
+                    && entry.file_type == file_type
+                    && entry.size == size =>
+            {
+                hash_file(src)?
+            }
+            _ => [0; 32],
+        };
+        Ok(ManifestEntry {
+            path: p,
+            file_type,
+            metadata: Metadata::from(&metadata),
+            size,
+            hash,
+        })
+    }
+
+    fn insert(&mut self, entry: ManifestEntry) {
+        self.entries.insert(entry.path.clone(), entry);
+    }
+
+    fn remove(&mut self, path: &str) {
+        self.entries.remove(path);
+    }
+
+    // The records are compressed as a whole
+    fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut w = CompressorWriter::new(
+            Vec::new(),
+            DEFAULT_BUF_SIZE,
+            DEFAULT_COMPRESS_QUALITY,
+            DEFAULT_COMPRESS_WINDOW_SIZE,
+        );
+        let mut buf = Vec::new();
+        for entry in self.entries() {
+            buf.clear();
+            buf.push(entry.file_type.tag());
+            entry.metadata.write(&mut buf);
+            buf.extend_from_slice(&entry.size.to_be_bytes());
+            buf.extend_from_slice(&entry.hash);
+            let len = u16::try_from(entry.path.len()).map_err(|_| Error::PathTooLong)?;
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(entry.path.as_bytes());
+            // Writing to a Vec does not fail
+            let _ = w.write_all(&buf);
+        }
+        Ok(w.into_inner())
+    }
+
+    fn parse(compressed: &[u8]) -> Result<Self, Error> {
+        let mut buf = Vec::new();
+        Decompressor::new(compressed, DEFAULT_BUF_SIZE)
+            .read_to_end(&mut buf)
+            .rst()?;
+        let mut manifest = Self::default();
+        let mut buf = &buf[..];
+        while !buf.is_empty() {
+            if buf.len() < Self::RECORD_SIZE {
+                return Err(Error::Truncated);
+            }
+            let (record, rest) = buf.split_at(Self::RECORD_SIZE);
+            let mut size = [0; 8];
+            size.copy_from_slice(&record[1 + Metadata::SIZE..1 + Metadata::SIZE + 8]);
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&record[1 + Metadata::SIZE + 8..Self::RECORD_SIZE - 2]);
+            let len =
+                u16::from_be_bytes([record[Self::RECORD_SIZE - 2], record[Self::RECORD_SIZE - 1]]);
+            if rest.len() < len as usize {
+                return Err(Error::Truncated);
+            }
+            let (path, rest) = rest.split_at(len as usize);
+            manifest.insert(ManifestEntry {
+                path: String::from_utf8(path.to_vec()).map_err(Error::Utf8)?,
+                file_type: FileType::parse(record[0])?,
+                metadata: Metadata::parse(&record[1..]),
+                size: u64::from_be_bytes(size),
+                hash,
+            });
+            buf = rest;
         }
+        Ok(manifest)
     }
 }
 
+fn modified(old: &ManifestEntry, new: &ManifestEntry) -> bool {
+    old.file_type != new.file_type
+        || (old.file_type == FileType::File && (old.size != new.size || old.hash != new.hash))
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32], Error> {
+    let mut hash = Sha256::new();
+    io::copy(&mut File::open(path).rst()?, &mut hash).rst()?;
+    Ok(hash.finalize().into())
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct CompressParams {
     buf_size: usize,
@@ -119,7 +610,123 @@ impl CompressParams {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// The state of an archive being written or read
+#[derive(Debug, Clone, Copy)]
+pub struct Status<'a> {
+    /// Path of the current entry
+    pub entry: &'a str,
+    /// Number of entries started so far
+    pub entries: u64,
+    /// Bytes read from the files when writing, from the archive when reading
+    pub bytes_read: u64,
+    /// Bytes written to the archive when writing, to the files when reading
+    pub bytes_written: u64,
+    /// The value of `bytes_read` at the end, if it is known
+    pub total: Option<u64>,
+    /// Type of the current entry, None once the last entry is done
+    pub file_type: Option<FileType>,
+    /// Uncompressed bytes of the current file so far
+    pub entry_size: u64,
+    /// Compressed bytes of the current file so far, as counted by `Summary::compressed_size`
+    pub entry_compressed_size: u64,
+}
+
+/// Receives the progress of an `Encode` or a `Decode`,
+/// it is called when an entry starts and after each chunk
+pub trait Progress: Send {
+    fn update(&mut self, status: &Status<'_>);
+
+    /// Called when `Decode::unpack` skips an entry that cannot be extracted
+    fn failed(&mut self, _err: &Error) {}
+}
+
+/// How `Encode::append_dir_all` walks a directory
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    follow_links: bool,
+    exclude: Vec<PathBuf>,
+    reference: Option<Manifest>,
+    checksum: bool,
+}
+
+impl WalkOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Descend into symbolic links to directories
+    pub fn follow_links(&mut self, yes: bool) -> &mut Self {
+        self.follow_links = yes;
+        self
+    }
+
+    /// Skip a file or directory, such as the archive being written
+    pub fn exclude<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        let path = path.as_ref();
+        self.exclude
+            .push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+        self
+    }
+
+    /// Only add the files that changed since the archive of `manifest`,
+    /// paths that no longer exist are added as `FileType::Deleted`
+    pub fn incremental(&mut self, manifest: Manifest) -> &mut Self {
+        self.reference = Some(manifest);
+        self
+    }
+
+    /// Also compare the content of files whose size and modification time did not change
+    pub fn checksum(&mut self, yes: bool) -> &mut Self {
+        self.checksum = yes;
+        self
+    }
+}
+
+/// How `Decode::unpack` writes the entries
+#[derive(Debug, Clone)]
+pub struct UnpackOptions {
+    overwrite: bool,
+    preserve_metadata: bool,
+    keep_going: bool,
+}
+
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            preserve_metadata: true,
+            keep_going: false,
+        }
+    }
+}
+
+impl UnpackOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace existing files instead of failing
+    pub fn overwrite(&mut self, yes: bool) -> &mut Self {
+        self.overwrite = yes;
+        self
+    }
+
+    /// Restore the permissions and modification time of files
+    pub fn preserve_metadata(&mut self, yes: bool) -> &mut Self {
+        self.preserve_metadata = yes;
+        self
+    }
+
+    /// Skip the entries that cannot be read or written and continue from the next one,
+    /// instead of stopping at the first error. Archives of versions before 6 still stop
+    /// at the first entry that cannot be read
+    pub fn keep_going(&mut self, yes: bool) -> &mut Self {
+        self.keep_going = yes;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ScryptParams {
     pub salt: [u8; 16],
     pub n: u8,
@@ -127,6 +734,7 @@ pub struct ScryptParams {
     pub p: u32,
 }
 
+#[cfg(feature = "encryption")]
 impl Default for ScryptParams {
     fn default() -> Self {
         Self {
@@ -138,26 +746,108 @@ impl Default for ScryptParams {
     }
 }
 
+/// The key is copied into a buffer that is wiped when the password is dropped
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub struct Password {
+    key: Zeroizing<String>,
+    params: ScryptParams,
+}
+
+impl Password {
+    pub fn new(key: &str, params: ScryptParams) -> Self {
+        Self {
+            key: Zeroizing::new(key.to_string()),
+            params,
+        }
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Password")
+            .field("key", &"[REDACTED]")
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+// Convert the password to a key encryption key through scrypt
+#[cfg(feature = "encryption")]
+fn derive_key(key: &str, params: &ScryptParams) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let scrypt_params = scrypt::ScryptParams::new(params.n, params.r, params.p)
+        .map_err(|_| Error::InvalidScryptParams)?;
+    let mut value = Zeroizing::new([0; 32]);
+    scrypt::scrypt(key.as_bytes(), &params.salt, &scrypt_params, &mut *value)
+        .map_err(|_| Error::InvalidScryptParams)?;
+    Ok(value)
+}
+
+#[cfg(feature = "encryption")]
+fn cipher(key: &[u8; 32]) -> Aes256Gcm {
+    Aes256Gcm::new(GenericArray::from_slice(key))
+}
+
+// The number of passwords that can unlock an archive
+pub const KEY_SLOTS: usize = 8;
+
+#[cfg(feature = "encryption")]
+const KEY_SLOT_EMPTY: u8 = 0;
+const KEY_SLOT_ACTIVE: u8 = 1;
+
+// State + Scrypt params + Nonce + Wrapped data key (32) + Tag (16)
+const KEY_SLOT_SIZE: usize = 1 + SCRYPT_PARAMS_SIZE + 12 + 48;
+
+// The archive content is encrypted with a random data key,
+// each key slot stores a copy of it wrapped by a password
 #[derive(Debug)]
-pub struct Password<'a> {
-    key: &'a str,
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+struct KeySlot {
     params: ScryptParams,
+    nonce: [u8; 12],
+    wrapped: Vec<u8>,
 }
 
-impl<'a> Password<'a> {
-    pub fn new(key: &'a str, params: ScryptParams) -> Self {
-        Self { key, params }
+#[cfg(feature = "encryption")]
+impl KeySlot {
+    fn wrap(pw: &Password, data_key: &[u8; 32]) -> Result<Self, Error> {
+        let kek = derive_key(&pw.key, &pw.params)?;
+        let nonce: [u8; 12] = thread_rng().gen();
+        let wrapped = cipher(&kek)
+            .encrypt(GenericArray::from_slice(&nonce), &data_key[..])
+            .map_err(|_| Error::EncryptionFailed)?;
+        Ok(Self {
+            params: pw.params.clone(),
+            nonce,
+            wrapped,
+        })
+    }
+
+    fn unwrap(&self, key: &str) -> Result<Option<Zeroizing<[u8; 32]>>, Error> {
+        let kek = derive_key(key, &self.params)?;
+        let data =
+            match cipher(&kek).decrypt(GenericArray::from_slice(&self.nonce), &self.wrapped[..]) {
+                Ok(data) => Zeroizing::new(data),
+                Err(_) => return Ok(None),
+            };
+        let mut data_key = Zeroizing::new([0; 32]);
+        data_key.copy_from_slice(&data);
+        Ok(Some(data_key))
     }
 }
 
-// Convert the password to Aes256Gcm key through scrypt
-fn cipher(pw: Password) -> Result<Aes256Gcm, ()> {
-    let params =
-        scrypt::ScryptParams::new(pw.params.n, pw.params.r, pw.params.p).map_err(|_| ())?;
-    let mut value = [0; 32];
-    scrypt::scrypt(pw.key.as_bytes(), &pw.params.salt, &params, &mut value).unwrap();
-    let key = GenericArray::from_slice(&value);
-    Ok(Aes256Gcm::new(key))
+type KeySlots = [Option<KeySlot>; KEY_SLOTS];
+
+// Find the key slot the password unlocks
+#[cfg(feature = "encryption")]
+fn unlock(slots: &KeySlots, key: &str) -> Result<(usize, Zeroizing<[u8; 32]>), Error> {
+    for (i, slot) in slots.iter().enumerate() {
+        if let Some(slot) = slot {
+            if let Some(data_key) = slot.unwrap(key)? {
+                return Ok((i, data_key));
+            }
+        }
+    }
+    Err(Error::InvalidPassword)
 }
 
 // File identification
@@ -172,339 +862,2223 @@ fn read_head<R: Read>(r: &mut R) -> Result<(), Error> {
     Ok(())
 }
 
+/// Whether the data starts like an archive or the first volume of a split archive
+pub fn is_archive<R: Read>(mut r: R) -> bool {
+    read_head(&mut r).is_ok()
+}
+
 fn write_head<W: Write>(w: &mut W) -> IoResult<usize> {
     w.write(&HEAD)
 }
 
 // File version
-const VERSION: [u8; 1] = [1];
+const VERSION: [u8; 1] = [6];
 
-fn read_version<R: Read>(r: &mut R) -> Result<(), Error> {
+// The versions that can still be read, they are written in the current version
+// when they are rewritten
+const READ_VERSIONS: &[u8] = &[1, 2, 3, 4, 5, 6];
+
+// The first versions with entry metadata, the end of archive marker and summary,
+// the manifest, and the sync markers
+const METADATA_VERSION: u8 = 3;
+const END_VERSION: u8 = 4;
+const MANIFEST_VERSION: u8 = 5;
+const SYNC_VERSION: u8 = 6;
+
+fn read_version<R: Read>(r: &mut R) -> Result<u8, Error> {
     let mut buf = [0; 1];
     r.read_exact(&mut buf).rst()?;
-    if buf != VERSION {
+    if !READ_VERSIONS.contains(&buf[0]) {
         return Err(Error::InvalidVersion);
     }
-    Ok(())
+    Ok(buf[0])
 }
 
 fn write_version<W: Write>(w: &mut W) -> IoResult<usize> {
     w.write(&VERSION)
 }
 
-fn read_scrypt_option<R: Read>(r: &mut R) -> Result<Option<ScryptParams>, Error> {
-    let mut buf = [0; 1];
-    r.read_exact(&mut buf).rst()?;
-    match buf {
-        ENCRYPT_NONE => Ok(None),
-        ENCRYPT_AES_256_GCM => {
-            let mut salt_buf = [0; 16];
-            r.read_exact(&mut salt_buf).rst()?;
-            let mut n_buf = [0; 1];
-            r.read_exact(&mut n_buf).rst()?;
-            let mut r_buf = [0; 4];
-            r.read_exact(&mut r_buf).rst()?;
-            let mut p_buf = [0; 4];
-            r.read_exact(&mut p_buf).rst()?;
-            Ok(Some(ScryptParams {
-                salt: salt_buf,
-                n: u8::from_be_bytes(n_buf),
-                r: u32::from_be_bytes(r_buf),
-                p: u32::from_be_bytes(p_buf),
-            }))
+// Salt + N + R + P
+const SCRYPT_PARAMS_SIZE: usize = 16 + 1 + 4 + 4;
+
+fn read_scrypt_params<R: Read>(r: &mut R) -> Result<ScryptParams, Error> {
+    let mut salt_buf = [0; 16];
+    r.read_exact(&mut salt_buf).rst()?;
+    let mut n_buf = [0; 1];
+    r.read_exact(&mut n_buf).rst()?;
+    let mut r_buf = [0; 4];
+    r.read_exact(&mut r_buf).rst()?;
+    let mut p_buf = [0; 4];
+    r.read_exact(&mut p_buf).rst()?;
+    Ok(ScryptParams {
+        salt: salt_buf,
+        n: u8::from_be_bytes(n_buf),
+        r: u32::from_be_bytes(r_buf),
+        p: u32::from_be_bytes(p_buf),
+    })
+}
+
+#[cfg(feature = "encryption")]
+fn write_scrypt_params<W: Write>(w: &mut W, params: &ScryptParams) -> IoResult<()> {
+    w.write_all(&params.salt)?;
+    w.write_all(&params.n.to_be_bytes())?;
+    w.write_all(&params.r.to_be_bytes())?;
+    w.write_all(&params.p.to_be_bytes())?;
+    Ok(())
+}
+
+fn read_key_slots<R: Read>(r: &mut R) -> Result<KeySlots, Error> {
+    let mut slots = KeySlots::default();
+    for slot in slots.iter_mut() {
+        let mut buf = [0; KEY_SLOT_SIZE];
+        r.read_exact(&mut buf).rst()?;
+        if buf[0] == KEY_SLOT_ACTIVE {
+            let mut buf = &buf[1..];
+            let params = read_scrypt_params(&mut buf)?;
+            let nonce = read_nonce(&mut buf).rst()?;
+            *slot = Some(KeySlot {
+                params,
+                nonce,
+                wrapped: buf.to_vec(),
+            });
         }
-        _ => Err(Error::InvalidEncryptMethod),
     }
+    Ok(slots)
 }
 
-fn write_scrypt_params<W: Write>(w: &mut W, params: Option<&ScryptParams>) -> IoResult<()> {
-    match params {
-        Some(params) => {
-            w.write(&ENCRYPT_AES_256_GCM)?;
-            w.write(&params.salt)?;
-            w.write(&params.n.to_be_bytes())?;
-            w.write(&params.r.to_be_bytes())?;
-            w.write(&params.p.to_be_bytes())?;
-        }
-        None => {
-            w.write(&ENCRYPT_NONE)?;
+#[cfg(feature = "encryption")]
+fn write_key_slots<W: Write>(w: &mut W, slots: &KeySlots) -> IoResult<()> {
+    // Empty slots are kept, so that the header size never changes
+    for slot in slots {
+        match slot {
+            Some(slot) => {
+                w.write_all(&[KEY_SLOT_ACTIVE])?;
+                write_scrypt_params(w, &slot.params)?;
+                w.write_all(&slot.nonce)?;
+                w.write_all(&slot.wrapped)?;
+            }
+            None => {
+                w.write_all(&[KEY_SLOT_EMPTY; KEY_SLOT_SIZE])?;
+            }
         }
     }
     Ok(())
 }
 
-fn read_chunk<R: Read>(r: &mut R) -> IoResult<Vec<u8>> {
-    // 2 bytes chunk length
-    let mut len = [0; 2];
-    r.read_exact(&mut len)?;
-    // Chunk
-    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
-    r.read_exact(&mut buf)?;
-    Ok(buf)
+/// How the content of an archive is encrypted
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EncryptMethod {
+    None,
+    /// The entry data and the manifest are encrypted with AES-256-GCM
+    Aes256Gcm,
+    /// The info and entry types are encrypted too, and chunks are padded
+    Aes256GcmPrivate,
 }
 
-fn write_chunk<W: Write>(w: &mut W, buf: &[u8]) -> Result<(), Error> {
-    if buf.len() > 65535 {
-        return Err(Error::ChunkTooLong);
+fn read_encrypt_method<R: Read>(r: &mut R) -> Result<EncryptMethod, Error> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf).rst()?;
+    match buf {
+        ENCRYPT_NONE => Ok(EncryptMethod::None),
+        ENCRYPT_AES_256_GCM => Ok(EncryptMethod::Aes256Gcm),
+        ENCRYPT_AES_256_GCM_PRIVATE => Ok(EncryptMethod::Aes256GcmPrivate),
+        _ => Err(Error::InvalidEncryptMethod),
     }
-    // 2 bytes chunk length
-    w.write(&(buf.len() as u16).to_be_bytes()).rst()?;
-    // Chunk
-    w.write(&buf).rst()?;
-    Ok(())
 }
 
-fn read_chunk_to_string<R: Read>(r: &mut R) -> Result<String, Error> {
-    let buf = read_chunk(r).rst()?;
-    String::from_utf8(buf).map_err(|err| Error::Utf8(err))
+fn write_encrypt_method<W: Write>(w: &mut W, method: EncryptMethod) -> IoResult<()> {
+    match method {
+        EncryptMethod::None => w.write_all(&ENCRYPT_NONE),
+        EncryptMethod::Aes256Gcm => w.write_all(&ENCRYPT_AES_256_GCM),
+        EncryptMethod::Aes256GcmPrivate => w.write_all(&ENCRYPT_AES_256_GCM_PRIVATE),
+    }
 }
 
-fn read_nonce<R: Read>(r: &mut R) -> IoResult<[u8; 12]> {
-    let mut buf = [0; 12];
-    r.read_exact(&mut buf)?;
-    Ok(buf)
+/// The part of an archive before the entries, it is read without a password
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Header {
+    /// Format version
+    pub version: u8,
+    /// Empty for a private archive, whose info is encrypted
+    pub info: String,
+    pub method: EncryptMethod,
+    /// Key derivation parameters of the passwords that can unlock the archive,
+    /// a version 1 archive has one password and no key slots
+    pub key_slots: Vec<ScryptParams>,
 }
 
-fn read_encrypt_chunk<R: Read>(r: &mut R, cipher: &Aes256Gcm) -> Result<Option<Vec<u8>>, Error> {
-    let encrypted = match read_chunk(r) {
-        Ok(buf) => {
-            if buf.is_empty() {
-                return Ok(None);
-            } else {
-                buf
-            }
-        }
-        Err(err) => {
-            if err.kind() == ErrorKind::UnexpectedEof {
-                return Ok(None);
-            } else {
-                return Err(Error::IO(err));
-            }
-        }
-    };
-    let nonce = read_nonce(r).rst()?;
-    let data = match cipher.decrypt(GenericArray::from_slice(&nonce), &encrypted[..]) {
-        Ok(data) => data,
-        Err(_) => return Err(Error::DecryptionFailed),
-    };
-    Ok(Some(data))
+/// Read the header of an archive, the reader must start at the beginning of the archive
+pub fn read_header<R: Read>(r: R) -> Result<Header, Error> {
+    match read_frame(&mut Hashing::new(r), &mut Framing::new(), None, None)? {
+        Frame::Header(header) => Ok(header.header),
+        _ => unreachable!(),
+    }
 }
 
-fn write_encrypt_chunk<W: Write>(w: &mut W, cipher: &Aes256Gcm, buf: &[u8]) -> Result<(), Error> {
-    if buf.is_empty() {
-        write_chunk(w, &[])?;
-    } else {
-        let nonce: [u8; 12] = thread_rng().gen();
-        let data = match cipher.encrypt(GenericArray::from_slice(&nonce), buf) {
-            Ok(data) => data,
-            Err(_) => return Err(Error::EncryptionFailed),
-        };
-        write_chunk(w, &data)?;
-        w.write(&nonce).rst()?;
+// End of archive marker, followed by the summary and the manifest
+const END: u8 = 0xfe;
+
+// Written before every entry header and the end of archive marker,
+// reading a damaged archive continues from the next one
+const SYNC: [u8; 8] = *b"\xa5meisync";
+
+fn read_sync<R: Read>(r: &mut R) -> Result<(), Error> {
+    let mut buf = [0; SYNC.len()];
+    r.read_exact(&mut buf).map_err(truncated)?;
+    if buf != SYNC {
+        return Err(Error::InvalidSync);
     }
     Ok(())
 }
 
-pub struct Encode<W> {
-    inner: W,
-    cipher: Option<Aes256Gcm>,
-    params: CompressParams,
+// Signature block, written after the manifest
+const SIGNATURE: u8 = 0xff;
+const SIGNATURE_SIZE: usize = 1 + 64;
+
+// The offset of the manifest, always the last bytes of an archive.
+// It is not signed, the manifest it points to is
+const MANIFEST: u8 = 0xfd;
+const LOCATOR_SIZE: usize = 1 + 8;
+
+fn read_locator<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let mut buf = [0; LOCATOR_SIZE];
+    r.read_exact(&mut buf).map_err(truncated)?;
+    if buf[0] != MANIFEST {
+        return Err(Error::FileType(buf[0]));
+    }
+    let mut offset = [0; 8];
+    offset.copy_from_slice(&buf[1..]);
+    Ok(u64::from_be_bytes(offset))
+}
+
+// An optional recovery record follows the archive, it ends with two copies of:
+// Record checksums length + Archive length + Start of the SHA-256 of both + Magic
+const RECOVERY_MAGIC: [u8; 8] = *b"meirecov";
+const RECOVERY_TRAILER_SIZE: usize = 8 + 8 + 8 + 8;
+const RECOVERY_END_SIZE: usize = RECOVERY_TRAILER_SIZE * 2;
+
+// The record checksums length and the archive length of the last intact trailer
+fn read_trailer(end: &[u8; RECOVERY_END_SIZE]) -> Option<(u64, u64)> {
+    end.rchunks(RECOVERY_TRAILER_SIZE)
+        .find(|trailer| {
+            trailer[24..] == RECOVERY_MAGIC
+                && Sha256::digest(&trailer[..16])[..8] == trailer[16..24]
+        })
+        .map(|trailer| {
+            let n = |i: usize| {
+                let mut b = [0; 8];
+                b.copy_from_slice(&trailer[i..i + 8]);
+                u64::from_be_bytes(b)
+            };
+            (n(0), n(8))
+        })
+}
+
+// The length of the archive without its recovery record
+fn archive_len<R: Read + Seek>(r: &mut R) -> IoResult<u64> {
+    let len = r.seek(SeekFrom::End(0))?;
+    if len < RECOVERY_END_SIZE as u64 {
+        return Ok(len);
+    }
+    r.seek(SeekFrom::Start(len - RECOVERY_END_SIZE as u64))?;
+    let mut buf = [0; RECOVERY_END_SIZE];
+    r.read_exact(&mut buf)?;
+    Ok(trailer_archive_len(len, &buf))
+}
+
+// The length of the archive in a file of `len` bytes ending with `end`
+fn trailer_archive_len(len: u64, end: &[u8; RECOVERY_END_SIZE]) -> u64 {
+    read_trailer(end).map_or(len, |(_, archive_len)| archive_len.min(len))
+}
+
+// Every byte of the archive except the key slots goes through the digest,
+// so that changing passwords does not invalidate the signature
+struct Hashing<T> {
+    inner: T,
+    digest: Sha512,
+    // Offset in the archive, including the bytes that are not hashed
+    pos: u64,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            digest: Sha512::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = self.inner.read(buf)?;
+        self.digest.update(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "signing")]
+fn read_signature<R: Read>(r: &mut R) -> Result<Signature, Error> {
+    let mut buf = [0; 64];
+    r.read_exact(&mut buf).rst()?;
+    Signature::from_bytes(&buf).map_err(|_| Error::InvalidSignature)
+}
+
+// The signature block and what follows it, the manifest locator was added with the manifest
+#[cfg(feature = "signing")]
+fn trailer_size(version: u8) -> u64 {
+    if version < MANIFEST_VERSION {
+        SIGNATURE_SIZE as u64
+    } else {
+        (SIGNATURE_SIZE + LOCATOR_SIZE) as u64
+    }
+}
+
+#[cfg(feature = "signing")]
+fn verify_signature(key: &PublicKey, digest: Sha512, signature: &Signature) -> Result<(), Error> {
+    key.verify(&digest.finalize(), signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+fn read_chunk<R: Read>(r: &mut R) -> IoResult<Vec<u8>> {
+    // 2 bytes chunk length
+    let mut len = [0; 2];
+    r.read_exact(&mut len)?;
+    // Chunk
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn write_chunk<W: Write>(w: &mut W, buf: &[u8]) -> Result<(), Error> {
+    if buf.len() > 65535 {
+        return Err(Error::ChunkTooLong);
+    }
+    // 2 bytes chunk length
+    w.write_all(&(buf.len() as u16).to_be_bytes()).rst()?;
+    // Chunk
+    w.write_all(buf).rst()?;
+    Ok(())
+}
+
+fn read_nonce<R: Read>(r: &mut R) -> IoResult<[u8; 12]> {
+    let mut buf = [0; 12];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Padmé, the padded length leaks at most O(log log n) bits of the length
+#[cfg(feature = "encryption")]
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let e = (usize::BITS - 1 - len.leading_zeros()) as usize;
+    let s = (usize::BITS - e.leading_zeros()) as usize;
+    let mask = (1 << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+// ISO/IEC 7816-4 padding: 0x80 followed by zeros
+#[cfg(feature = "encryption")]
+fn pad(buf: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut padded = Zeroizing::new(Vec::with_capacity(padme(buf.len() + 1)));
+    padded.extend_from_slice(buf);
+    padded.push(0x80);
+    padded.resize(padme(buf.len() + 1), 0);
+    padded
+}
+
+#[cfg(feature = "encryption")]
+fn unpad(mut buf: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match buf.iter().rposition(|&b| b != 0) {
+        Some(i) if buf[i] == 0x80 => {
+            buf.truncate(i);
+            Ok(buf)
+        }
+        _ => Err(Error::DecryptionFailed),
+    }
+}
+
+// Content encryption, chunks are padded in private mode.
+// It is never created without the `encryption` feature
+#[derive(Clone)]
+struct Crypto {
+    #[cfg(feature = "encryption")]
+    cipher: Aes256Gcm,
+    padding: bool,
+}
+
+#[cfg(feature = "encryption")]
+impl Crypto {
+    // Decrypt a chunk, returns None at the end of a chunk sequence in private mode
+    fn open(&self, encrypted: &[u8], nonce: &[u8; 12]) -> Result<Option<Vec<u8>>, Error> {
+        let mut data = self
+            .cipher
+            .decrypt(GenericArray::from_slice(nonce), encrypted)
+            .map_err(|_| Error::DecryptionFailed)?;
+        if self.padding {
+            data = unpad(data)?;
+            if data.is_empty() {
+                return Ok(None);
+            }
+        }
+        Ok(Some(data))
+    }
+}
+
+// The content cipher of an archive, the password unlocks one of the key slots
+#[cfg(feature = "encryption")]
+fn open_crypto(
+    method: EncryptMethod,
+    slots: Option<KeySlots>,
+    password: Option<&str>,
+) -> Result<Option<Crypto>, Error> {
+    match (slots, password) {
+        (Some(slots), Some(key)) => {
+            let (_, data_key) = unlock(&slots, key)?;
+            Ok(Some(Crypto {
+                cipher: cipher(&data_key),
+                padding: method == EncryptMethod::Aes256GcmPrivate,
+            }))
+        }
+        (Some(_), None) => Err(Error::PasswordRequired),
+        (None, Some(_)) => Err(Error::NoPasswordRequired),
+        (None, None) => Ok(None),
+    }
+}
+
+// A version 1 archive is encrypted with the key derived from its password,
+// a wrong password is only found when the first chunk is decrypted
+#[cfg(feature = "encryption")]
+fn open_legacy_crypto(
+    params: &ScryptParams,
+    password: Option<&str>,
+) -> Result<Option<Crypto>, Error> {
+    let key = derive_key(password.ok_or(Error::PasswordRequired)?, params)?;
+    Ok(Some(Crypto {
+        cipher: cipher(&key),
+        padding: false,
+    }))
+}
+
+// The key slots of a version 1 archive when it is migrated, the derived key becomes
+// the data key and the password gets the first slot with new parameters
+#[cfg(feature = "encryption")]
+fn legacy_key_slots(
+    params: &ScryptParams,
+    key: &str,
+) -> Result<(KeySlots, Zeroizing<[u8; 32]>), Error> {
+    let data_key = derive_key(key, params)?;
+    let mut slots = KeySlots::default();
+    let pw = Password::new(key, ScryptParams::default());
+    slots[0] = Some(KeySlot::wrap(&pw, &data_key)?);
+    Ok((slots, data_key))
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Crypto {
+    fn open(&self, _: &[u8], _: &[u8; 12]) -> Result<Option<Vec<u8>>, Error> {
+        Err(Error::EncryptionDisabled)
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open_crypto(
+    _: EncryptMethod,
+    slots: Option<KeySlots>,
+    password: Option<&str>,
+) -> Result<Option<Crypto>, Error> {
+    match (slots, password) {
+        (Some(_), _) => Err(Error::EncryptionDisabled),
+        (None, Some(_)) => Err(Error::NoPasswordRequired),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open_legacy_crypto(_: &ScryptParams, _: Option<&str>) -> Result<Option<Crypto>, Error> {
+    Err(Error::EncryptionDisabled)
+}
+
+fn read_encrypt_chunk<R: Read>(r: &mut R, crypto: &Crypto) -> Result<Option<Vec<u8>>, Error> {
+    let encrypted = read_chunk(r).rst()?;
+    if encrypted.is_empty() {
+        return Ok(None);
+    }
+    let nonce = read_nonce(r).rst()?;
+    crypto.open(&encrypted, &nonce)
+}
+
+#[cfg(feature = "encryption")]
+fn write_encrypt_chunk<W: Write>(w: &mut W, crypto: &Crypto, buf: &[u8]) -> Result<(), Error> {
+    // In private mode even the end of a file is an encrypted chunk
+    if buf.is_empty() && !crypto.padding {
+        write_chunk(w, &[])?;
+    } else {
+        let padded;
+        let buf = if crypto.padding {
+            padded = pad(buf);
+            &padded[..]
+        } else {
+            buf
+        };
+        let nonce: [u8; 12] = thread_rng().gen();
+        let data = match crypto.cipher.encrypt(GenericArray::from_slice(&nonce), buf) {
+            Ok(data) => data,
+            Err(_) => return Err(Error::EncryptionFailed),
+        };
+        write_chunk(w, &data)?;
+        w.write_all(&nonce).rst()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn write_encrypt_chunk<W: Write>(_: &mut W, _: &Crypto, _: &[u8]) -> Result<(), Error> {
+    Err(Error::EncryptionDisabled)
+}
+
+pub struct Encode<W> {
+    inner: Hashing<W>,
+    crypto: Option<Crypto>,
+    params: CompressParams,
+    #[cfg(feature = "signing")]
+    signer: Option<Keypair>,
+    // The archive continued by `open_append` or `Decode::rewrite` was signed
+    was_signed: bool,
+    summary: Summary,
+    manifest: Manifest,
+    progress: Option<Box<dyn Progress>>,
+    // Path, type and sizes of the current entry, only kept for the progress observer
+    entry: String,
+    file_type: Option<FileType>,
+    entry_size: u64,
+    entry_compressed_size: u64,
+    read: u64,
+    total: Option<u64>,
 }
 
 impl<W: Write> Encode<W> {
     pub fn new(
-        mut writer: W,
+        writer: W,
+        info: &str,
+        password: Option<Password>,
+        params: CompressParams,
+    ) -> Result<Self, Error> {
+        Self::with_method(writer, info, password, params, false)
+    }
+
+    /// Create an archive that also encrypts the info and entry types,
+    /// and pads chunks so that only the approximate archive size is revealed
+    pub fn new_private(
+        writer: W,
+        info: &str,
+        password: Password,
+        params: CompressParams,
+    ) -> Result<Self, Error> {
+        Self::with_method(writer, info, Some(password), params, true)
+    }
+
+    fn with_method(
+        writer: W,
         info: &str,
         password: Option<Password>,
         params: CompressParams,
+        private: bool,
+    ) -> Result<Self, Error> {
+        let method = match (&password, private) {
+            (None, _) => EncryptMethod::None,
+            (Some(_), false) => EncryptMethod::Aes256Gcm,
+            (Some(_), true) => EncryptMethod::Aes256GcmPrivate,
+        };
+        let (key_slots, crypto): (Option<Vec<u8>>, _) = match password {
+            #[cfg(feature = "encryption")]
+            Some(pw) => {
+                let data_key = Zeroizing::new(thread_rng().gen::<[u8; 32]>());
+                let mut slots = KeySlots::default();
+                slots[0] = Some(KeySlot::wrap(&pw, &data_key)?);
+                let mut buf = Vec::with_capacity(KEY_SLOT_SIZE * KEY_SLOTS);
+                write_key_slots(&mut buf, &slots).rst()?;
+                let crypto = Crypto {
+                    cipher: cipher(&data_key),
+                    padding: private,
+                };
+                (Some(buf), Some(crypto))
+            }
+            #[cfg(not(feature = "encryption"))]
+            Some(_) => return Err(Error::EncryptionDisabled),
+            None => (None, None),
+        };
+        Self::with_header(writer, info, method, key_slots.as_deref(), crypto, params)
+    }
+
+    // The key slots are written as they are, they are not part of the signed digest
+    fn with_header(
+        writer: W,
+        info: &str,
+        method: EncryptMethod,
+        key_slots: Option<&[u8]>,
+        crypto: Option<Crypto>,
+        params: CompressParams,
     ) -> Result<Self, Error> {
+        let private = method == EncryptMethod::Aes256GcmPrivate;
+        let mut writer = Hashing::new(writer);
         write_head(&mut writer).rst()?;
         write_version(&mut writer).rst()?;
-        write_chunk(&mut writer, info.as_bytes())?;
-        let scrypt = password.as_ref().map(|s| &s.params);
-        write_scrypt_params(&mut writer, scrypt).rst()?;
-
-        let cipher = match password {
-            Some(pw) => match cipher(pw) {
-                Ok(cipher) => Some(cipher),
-                Err(_) => return Err(Error::InvalidScryptParams),
-            },
-            None => None,
-        };
+        write_chunk(&mut writer, if private { &[] } else { info.as_bytes() })?;
+        write_encrypt_method(&mut writer, method).rst()?;
+        if let Some(slots) = key_slots {
+            writer.inner.write_all(slots).rst()?;
+            writer.pos += slots.len() as u64;
+        }
 
-        Ok(Self {
+        let mut encode = Self {
             inner: writer,
-            cipher,
+            crypto,
             params,
-        })
+            #[cfg(feature = "signing")]
+            signer: None,
+            was_signed: false,
+            summary: Summary::default(),
+            manifest: Manifest::default(),
+            progress: None,
+            entry: String::new(),
+            file_type: None,
+            entry_size: 0,
+            entry_compressed_size: 0,
+            read: 0,
+            total: None,
+        };
+        if private {
+            encode.write_data(info.as_bytes())?;
+        }
+        Ok(encode)
+    }
+
+    fn private(&self) -> bool {
+        matches!(&self.crypto, Some(crypto) if crypto.padding)
+    }
+
+    // Write a chunk, encrypted if the archive has a password
+    fn write_data(&mut self, buf: &[u8]) -> Result<(), Error> {
+        match &self.crypto {
+            Some(crypto) => write_encrypt_chunk(&mut self.inner, crypto, buf),
+            None => write_chunk(&mut self.inner, buf),
+        }
+    }
+
+    // Write a chunk read by `Decode::read_raw_data`
+    fn write_raw(&mut self, raw: &[u8]) -> Result<(), Error> {
+        self.inner.write_all(raw).rst()
+    }
+
+    // Entry header: Metadata + Path, the type is part of it in private mode
+    fn write_entry(&mut self, tag: u8, p: &str, metadata: &Metadata) -> Result<(), Error> {
+        let offset = self.inner.pos;
+        self.write_entry_header(tag, p, metadata)
+            .map_err(|err| err.context(Operation::WriteEntry, Some(p), Some(offset)))
+    }
+
+    fn write_entry_header(&mut self, tag: u8, p: &str, metadata: &Metadata) -> Result<(), Error> {
+        // The manifest stores the path length in 2 bytes
+        if p.len() > u16::MAX as usize {
+            return Err(Error::PathTooLong);
+        }
+        self.inner.write_all(&SYNC).rst()?;
+        let mut buf = Zeroizing::new(Vec::with_capacity(1 + Metadata::SIZE + p.len()));
+        if self.private() {
+            buf.push(tag);
+        } else {
+            self.inner.write_all(&[tag]).rst()?;
+        }
+        metadata.write(&mut buf);
+        buf.extend_from_slice(p.as_bytes());
+        self.summary.entries += 1;
+        self.write_data(&buf)?;
+        if self.progress.is_some() {
+            self.entry.clear();
+            self.entry.push_str(p);
+            self.file_type = FileType::parse(tag).ok();
+            self.entry_size = 0;
+            self.entry_compressed_size = 0;
+            self.report();
+        }
+        Ok(())
+    }
+
+    fn report(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            progress.update(&Status {
+                entry: &self.entry,
+                entries: self.summary.entries,
+                bytes_read: self.read,
+                bytes_written: self.inner.pos,
+                total: self.total,
+                file_type: self.file_type,
+                entry_size: self.entry_size,
+                entry_compressed_size: self.entry_compressed_size,
+            });
+        }
+    }
+
+    /// Report the progress to an observer,
+    /// `append_dir_all` adds the size of the files it walks to the total
+    pub fn progress(&mut self, observer: Box<dyn Progress>) {
+        self.progress = Some(observer);
+    }
+
+    /// Sign the archive when it is finished
+    #[cfg(feature = "signing")]
+    pub fn sign(&mut self, keypair: Keypair) {
+        self.signer = Some(keypair);
+    }
+
+    /// The archive continued by `open_append` or `Decode::rewrite` was signed,
+    /// its signature is dropped unless `sign` is called
+    pub fn was_signed(&self) -> bool {
+        self.was_signed
+    }
+
+    /// Write the end of archive marker, the summary, the manifest and the signature block,
+    /// and return the inner writer. An archive without the marker is reported as truncated
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.progress.is_some() {
+            self.file_type = None;
+            self.report();
+        }
+        self.inner.write_all(&SYNC).rst()?;
+        let mut buf = Vec::with_capacity(1 + Summary::SIZE);
+        if self.private() {
+            buf.push(END);
+        } else {
+            self.inner.write_all(&[END]).rst()?;
+        }
+        self.summary.write(&mut buf);
+        self.write_data(&buf)?;
+
+        let offset = self.inner.pos;
+        let manifest = self.manifest.write()?;
+        for chunk in manifest.chunks(self.params.buf_size) {
+            self.write_data(chunk)?;
+        }
+        self.write_data(&[])?;
+
+        let Hashing {
+            mut inner, digest, ..
+        } = self.inner;
+        #[cfg(feature = "signing")]
+        if let Some(keypair) = self.signer {
+            let signature = keypair.sign(&digest.finalize());
+            inner.write_all(&[SIGNATURE]).rst()?;
+            inner.write_all(&signature.to_bytes()).rst()?;
+        }
+        #[cfg(not(feature = "signing"))]
+        let _ = digest;
+        inner.write_all(&[MANIFEST]).rst()?;
+        inner.write_all(&offset.to_be_bytes()).rst()?;
+        inner.flush().rst()?;
+        Ok(inner)
     }
 
     /// Add a directory to an archive
     pub fn write_directory(&mut self, p: &str) -> Result<(), Error> {
-        // File type
-        FileType::Directory.write(&mut self.inner).rst()?;
-        // File path
-        match &self.cipher {
-            Some(cipher) => {
-                write_encrypt_chunk(&mut self.inner, cipher, p.as_bytes())?;
-            }
-            None => {
-                write_chunk(&mut self.inner, p.as_bytes())?;
-            }
-        }
+        self.write_directory_with_metadata(p, &Metadata::default())
+    }
+
+    /// Add a directory to an archive with its modification time and permissions
+    pub fn write_directory_with_metadata(
+        &mut self,
+        p: &str,
+        metadata: &Metadata,
+    ) -> Result<(), Error> {
+        self.write_entry(FileType::DIRECTORY, p, metadata)?;
+        self.manifest.insert(ManifestEntry {
+            path: p.to_string(),
+            file_type: FileType::Directory,
+            metadata: *metadata,
+            size: 0,
+            hash: [0; 32],
+        });
+        self.inner.flush().rst()
+    }
+
+    /// Record that a path was removed, `Decode::unpack` deletes it
+    pub fn write_deleted(&mut self, p: &str) -> Result<(), Error> {
+        self.write_entry(FileType::DELETED, p, &Metadata::default())?;
+        self.manifest.remove(p);
         self.inner.flush().rst()
     }
 
     /// Add a file to an archive
     pub fn write_file<R: Read>(&mut self, p: &str, reader: &mut R) -> Result<usize, Error> {
-        let mut bytes = 0;
-        let mut reader = CompressorReader::new(
-            reader,
-            self.params.buf_size,
-            self.params.quality,
-            self.params.window_size,
-        );
-        // File type
-        FileType::File.write(&mut self.inner).rst()?;
-
-        match &self.cipher {
-            Some(cipher) => {
-                // File path
-                write_encrypt_chunk(&mut self.inner, cipher, p.as_bytes())?;
-                // File data
-                let mut buf = vec![0; self.params.buf_size];
-                loop {
-                    match reader.read(&mut buf) {
-                        Ok(n) => {
-                            if n == 0 {
-                                write_encrypt_chunk(&mut self.inner, cipher, &[])?;
-                                break;
-                            }
-                            bytes += n;
-                            write_encrypt_chunk(&mut self.inner, cipher, &buf[..n])?;
-                        }
-                        Err(err) => match err.kind() {
-                            ErrorKind::UnexpectedEof => {
-                                write_encrypt_chunk(&mut self.inner, cipher, &[])?;
-                                break;
-                            }
-                            _ => return Err(Error::IO(err)),
-                        },
-                    };
-                }
-            }
-            None => {
-                // File path
-                write_chunk(&mut self.inner, p.as_bytes())?;
-                // File data
-                let mut buf = vec![0; self.params.buf_size];
-                loop {
-                    match reader.read(&mut buf) {
-                        Ok(n) => {
-                            if n == 0 {
-                                write_chunk(&mut self.inner, &[])?;
-                                break;
-                            }
-                            bytes += n;
-                            write_chunk(&mut self.inner, &buf[..n])?;
-                        }
-                        Err(err) => match err.kind() {
-                            ErrorKind::UnexpectedEof => {
-                                write_chunk(&mut self.inner, &[])?;
-                                break;
-                            }
-                            _ => return Err(Error::IO(err)),
-                        },
-                    };
-                }
-            }
+        self.write_file_with_metadata(p, &Metadata::default(), reader)
+    }
+
+    /// Add a file to an archive with its modification time and permissions
+    pub fn write_file_with_metadata<R: Read>(
+        &mut self,
+        p: &str,
+        metadata: &Metadata,
+        reader: &mut R,
+    ) -> Result<usize, Error> {
+        let mut w = self.start_file(p, metadata)?;
+        if let Err(err) = io::copy(reader, &mut w) {
+            return Err(w.take_error().unwrap_or(Error::IO(err)));
         }
-        self.inner.flush().map(|_| bytes).rst()
+        w.finish()
     }
-}
 
-pub struct Decode<R> {
-    inner: R,
-    cipher: Option<Aes256Gcm>,
-    info: String,
-    buf_size: usize,
+    /// Add a file to an archive and write its content through the returned writer,
+    /// the file is complete when `FileWriter::finish` is called
+    pub fn start_file(&mut self, p: &str, metadata: &Metadata) -> Result<FileWriter<'_, W>, Error> {
+        self.write_entry(FileType::FILE, p, metadata)?;
+        let params = self.params;
+        let chunks = ChunkWriter {
+            encode: self,
+            path: p.to_string(),
+            metadata: *metadata,
+            buf: Vec::with_capacity(params.buf_size),
+            bytes: 0,
+            read: Cell::new(0),
+            error: RefCell::new(None),
+        };
+        Ok(FileWriter {
+            inner: Some(CompressorWriter::new(
+                chunks,
+                params.buf_size,
+                params.quality,
+                params.window_size,
+            )),
+            size: 0,
+            hash: Sha256::new(),
+        })
+    }
 }
 
-impl<R: Read> Decode<R> {
-    pub fn new(mut reader: R, password: Option<&str>, buf_size: usize) -> Result<Self, Error> {
-        read_head(&mut reader)?;
-        read_version(&mut reader)?;
-        let info = read_chunk_to_string(&mut reader)?;
-        let params = read_scrypt_option(&mut reader)?;
-
-        match (params.is_some(), password.is_some()) {
-            (true, false) => return Err(Error::PasswordRequired),
-            (false, true) => return Err(Error::NoPasswordRequired),
-            _ => {}
-        }
-
-        let cipher = match password.zip(params) {
-            Some((key, params)) => match cipher(Password { key, params }) {
-                Ok(cipher) => Some(cipher),
-                Err(_) => return Err(Error::InvalidScryptParams),
-            },
-            None => None,
-        };
+impl Encode<File> {
+    /// Continue writing an archive, the existing entries are read to check the password
+    /// and the archive, then the end of archive marker and the signature are overwritten.
+    /// The archive is only signed again if `sign` is called, see `was_signed`. An archive
+    /// of an older version must be rewritten first.
+    /// The file is changed in place, an error leaves it truncated: work on a copy to keep
+    /// the archive
+    pub fn open_append(
+        mut file: File,
+        password: Option<&str>,
+        params: CompressParams,
+    ) -> Result<Self, Error> {
+        file.seek(SeekFrom::Start(0)).rst()?;
+        let mut decode = Decode::new(BufReader::new(&mut file), password, DEFAULT_BUF_SIZE)?;
+        if decode.framing.version() != VERSION[0] {
+            return Err(Error::InvalidVersion);
+        }
+        while decode.read_entry()?.is_some() {}
+        let (offset, digest) = decode.end.take().ok_or(Error::Truncated)?;
+        let summary = decode.summary().copied().unwrap_or_default();
+        let manifest = decode.framing.take_manifest().unwrap_or_default();
+        let crypto = decode.crypto.take();
+        let was_signed = decode.framing.signed();
+        drop(decode);
 
+        file.set_len(offset).rst()?;
+        file.seek(SeekFrom::Start(offset)).rst()?;
         Ok(Self {
-            inner: reader,
-            cipher,
-            info,
-            buf_size,
+            inner: Hashing {
+                inner: file,
+                digest,
+                pos: offset,
+            },
+            crypto,
+            params,
+            #[cfg(feature = "signing")]
+            signer: None,
+            was_signed,
+            summary,
+            manifest,
+            progress: None,
+            entry: String::new(),
+            file_type: None,
+            entry_size: 0,
+            entry_compressed_size: 0,
+            read: 0,
+            total: None,
         })
     }
+}
 
-    pub fn info(&self) -> &str {
-        &self.info
-    }
+impl<W: Write> Encode<W> {
+    /// Add a directory and everything in it, the entries are stored under `archive_prefix`.
+    /// A single file can also be added, it is stored as `archive_prefix`
+    pub fn append_dir_all<P: AsRef<Path>>(
+        &mut self,
+        src: P,
+        archive_prefix: &str,
+        options: &WalkOptions,
+    ) -> Result<(), Error> {
+        let src = src.as_ref();
+        let mut paths = Vec::new();
+        walk(src, options, &mut Vec::new(), &mut paths)?;
+        let mut entries = Vec::with_capacity(paths.len());
+        for entry in paths {
+            let path = match entry.strip_prefix(src) {
+                Ok(relative) if relative.as_os_str().is_empty() => PathBuf::from(archive_prefix),
+                Ok(relative) => Path::new(archive_prefix).join(relative),
+                Err(_) => entry.clone(),
+            };
+            let p = match path.to_str() {
+                Some("") => continue,
+                Some(p) => p.to_string(),
+                None => return Err(Error::FilePath),
+            };
+            let unchanged = match &options.reference {
+                Some(reference) => {
+                    unchanged(&entry, reference.get(&p), options.checksum).map_err(|err| {
+                        let path = entry.display().to_string();
+                        err.context(Operation::Append, Some(&path), None)
+                    })?
+                }
+                None => None,
+            };
+            entries.push((entry, p, unchanged));
+        }
+        if self.progress.is_some() {
+            let size: u64 = entries
+                .iter()
+                .filter(|(_, _, unchanged)| unchanged.is_none())
+                .filter_map(|(entry, _, _)| fs::metadata(entry).ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum();
+            self.total = Some(self.total.unwrap_or(0) + size);
+        }
 
-    pub fn read_path(&mut self) -> Result<Option<(FileType, String)>, Error> {
-        let mut buf = [0; 1];
-        if let Err(err) = self.inner.read_exact(&mut buf) {
-            if err.kind() == ErrorKind::UnexpectedEof {
-                return Ok(None);
-            } else {
-                return Err(Error::IO(err));
+        // The paths of the reference under the prefix that were not found
+        let deleted: Vec<String> = match &options.reference {
+            Some(reference) => {
+                let walked: HashSet<&str> = entries.iter().map(|(_, p, _)| p.as_str()).collect();
+                reference
+                    .entries()
+                    .map(|entry| &entry.path)
+                    .filter(|path| Path::new(path).starts_with(archive_prefix))
+                    .filter(|path| !walked.contains(path.as_str()))
+                    .cloned()
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        for (entry, p, unchanged) in entries {
+            if let Some(unchanged) = unchanged {
+                self.manifest.insert(unchanged);
+                continue;
             }
+            self.append_path(&entry, &p).map_err(|err| {
+                let path = entry.display().to_string();
+                err.context(Operation::Append, Some(&path), None)
+            })?;
         }
+        for p in deleted {
+            self.write_deleted(&p)?;
+        }
+        Ok(())
+    }
+
+    fn append_path(&mut self, src: &Path, p: &str) -> Result<(), Error> {
+        let metadata = fs::metadata(src).rst()?;
+        if metadata.is_dir() {
+            self.write_directory_with_metadata(p, &Metadata::from(&metadata))
+        } else {
+            let mut f = File::open(src).rst()?;
+            self.write_file_with_metadata(p, &Metadata::from(&metadata), &mut f)
+                .map(|_| ())
+        }
+    }
+}
+
+// The entry of the reference manifest if the path did not change since then
+fn unchanged(
+    src: &Path,
+    reference: Option<&ManifestEntry>,
+    checksum: bool,
+) -> Result<Option<ManifestEntry>, Error> {
+    let reference = match reference {
+        Some(reference) => reference,
+        None => return Ok(None),
+    };
+    let metadata = fs::metadata(src).rst()?;
+    let same = match reference.file_type {
+        FileType::Directory => metadata.is_dir(),
+        FileType::File => {
+            metadata.is_file()
+                && metadata.len() == reference.size
+                && Metadata::from(&metadata) == reference.metadata
+        }
+        FileType::Deleted => false,
+    };
+    if same
+        && checksum
+        && reference.file_type == FileType::File
+        && hash_file(src)? != reference.hash
+    {
+        return Ok(None);
+    }
+    Ok(if same { Some(reference.clone()) } else { None })
+}
 
-        let file_type = FileType::parse(buf[0])?;
-        let file_path = match &self.cipher {
-            Some(cipher) => match read_encrypt_chunk(&mut self.inner, cipher)? {
-                Some(buf) => String::from_utf8(buf).map_err(|err| Error::Utf8(err))?,
+// The top level names of the paths, where `Decode::unpack` writes them
+fn roots<'a>(paths: impl Iterator<Item = &'a str>) -> BTreeSet<String> {
+    paths
+        .filter_map(safe_path)
+        .filter_map(|path| {
+            let root = path.components().next()?;
+            root.as_os_str().to_str().map(String::from)
+        })
+        .collect()
+}
+
+// The paths under the roots in `dir` and their names in an archive
+fn walk_roots(dir: &Path, roots: &BTreeSet<String>) -> Result<Vec<(PathBuf, String)>, Error> {
+    let mut entries = Vec::new();
+    for root in roots {
+        let src = dir.join(root);
+        if fs::symlink_metadata(&src).is_err() {
+            continue;
+        }
+        let mut paths = Vec::new();
+        walk(&src, &WalkOptions::default(), &mut Vec::new(), &mut paths)?;
+        for path in paths {
+            let p = match path.strip_prefix(dir).ok().and_then(Path::to_str) {
+                Some(p) => p.to_string(),
                 None => return Err(Error::FilePath),
-            },
-            None => read_chunk_to_string(&mut self.inner)?,
-        };
+            };
+            entries.push((path, p));
+        }
+    }
+    Ok(entries)
+}
+
+// Collect the paths in the order they are added, a directory comes before its content
+fn walk(
+    path: &Path,
+    options: &WalkOptions,
+    ancestors: &mut Vec<PathBuf>,
+    paths: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let context = |err: IoError| {
+        let path = path.display().to_string();
+        Error::IO(err).context(Operation::Append, Some(&path), None)
+    };
+    if !options.exclude.is_empty() {
+        let canonical = path.canonicalize().map_err(context)?;
+        if options.exclude.contains(&canonical) {
+            return Ok(());
+        }
+    }
+    paths.push(path.to_path_buf());
+
+    // The path itself is followed if it is a link, like the paths given on the command line
+    let metadata = if ancestors.is_empty() {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    }
+    .map_err(context)?;
+    let is_dir = if metadata.file_type().is_symlink() {
+        options.follow_links && path.is_dir()
+    } else {
+        metadata.is_dir()
+    };
+    if !is_dir {
+        return Ok(());
+    }
+    // A link to one of the parent directories would never end
+    let canonical = path.canonicalize().map_err(context)?;
+    if ancestors.contains(&canonical) {
+        return Ok(());
+    }
+    let mut children: Vec<PathBuf> = fs::read_dir(path)
+        .and_then(|dir| dir.map(|entry| entry.map(|entry| entry.path())).collect())
+        .map_err(context)?;
+    children.sort();
 
-        Ok(Some((file_type, file_path)))
+    ancestors.push(canonical);
+    for child in children {
+        walk(&child, options, ancestors, paths)?;
     }
+    ancestors.pop();
+    Ok(())
+}
+
+pub struct FileWriter<'a, W: Write> {
+    inner: Option<CompressorWriter<ChunkWriter<'a, W>>>,
+    size: u64,
+    // Of the uncompressed content, for the manifest
+    hash: Sha256,
+}
+
+impl<'a, W: Write> FileWriter<'a, W> {
+    /// Finish the compressed stream and end the file,
+    /// returns the number of compressed bytes
+    pub fn finish(mut self) -> Result<usize, Error> {
+        let chunks = self.inner.take().unwrap().into_inner();
+        if let Some(err) = chunks.error.borrow_mut().take() {
+            return Err(err);
+        }
+        chunks.finish(self.size, self.hash.finalize_reset().into())
+    }
+
+    // The archive error behind an IO error returned by `write`
+    fn take_error(&mut self) -> Option<Error> {
+        let chunks = self.inner.as_ref()?.get_ref();
+        let err = chunks.error.borrow_mut().take();
+        err
+    }
+}
+
+impl<'a, W: Write> Write for FileWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let inner = self.inner.as_mut().unwrap();
+        let n = inner.write(buf)?;
+        self.size += n as u64;
+        self.hash.update(&buf[..n]);
+        let read = &inner.get_ref().read;
+        read.set(read.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<'a, W: Write> Drop for FileWriter<'a, W> {
+    fn drop(&mut self) {
+        // End the file even if `finish` was not called, so that the archive stays readable
+        if let Some(inner) = self.inner.take() {
+            let hash = self.hash.finalize_reset().into();
+            let _ = inner.into_inner().finish(self.size, hash);
+        }
+    }
+}
+
+// Split the compressed stream into chunks
+struct ChunkWriter<'a, W: Write> {
+    encode: &'a mut Encode<W>,
+    path: String,
+    metadata: Metadata,
+    buf: Vec<u8>,
+    bytes: usize,
+    // Uncompressed bytes that have not been reported yet
+    read: Cell<u64>,
+    error: RefCell<Option<Error>>,
+}
+
+impl<'a, W: Write> ChunkWriter<'a, W> {
+    // Write a chunk, or the end of the file if the buffer is empty
+    fn write_data(&mut self, n: usize) -> Result<(), Error> {
+        let offset = self.encode.inner.pos;
+        self.encode
+            .write_data(&self.buf[..n])
+            .map_err(|err| err.context(Operation::WriteData, Some(&self.path), Some(offset)))
+    }
+
+    fn write_chunk(&mut self) -> Result<(), Error> {
+        let n = self.buf.len().min(self.encode.params.buf_size);
+        self.write_data(n)?;
+        self.bytes += n;
+        self.buf.drain(..n);
+        self.report();
+        Ok(())
+    }
+
+    fn finish(mut self, size: u64, hash: [u8; 32]) -> Result<usize, Error> {
+        while !self.buf.is_empty() {
+            self.write_chunk()?;
+        }
+        self.write_data(0)?;
+        self.encode.manifest.insert(ManifestEntry {
+            path: self.path.clone(),
+            file_type: FileType::File,
+            metadata: self.metadata,
+            size,
+            hash,
+        });
+        self.encode.summary.size += size;
+        self.encode.summary.compressed_size += self.bytes as u64;
+        self.encode.inner.flush().rst()?;
+        self.report();
+        Ok(self.bytes)
+    }
+
+    fn report(&mut self) {
+        let read = self.read.take();
+        self.encode.read += read;
+        self.encode.entry_size += read;
+        self.encode.entry_compressed_size = self.bytes as u64;
+        self.encode.report();
+    }
+}
+
+impl<'a, W: Write> Write for ChunkWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.buf.extend_from_slice(buf);
+        while self.buf.len() >= self.encode.params.buf_size {
+            if let Err(err) = self.write_chunk() {
+                let io_err = IoError::other(err.to_string());
+                *self.error.borrow_mut() = Some(err);
+                return Err(io_err);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+pub struct Decode<R> {
+    inner: Hashing<R>,
+    framing: Framing,
+    crypto: Option<Crypto>,
+    info: String,
+    buf_size: usize,
+    // Key slots are excluded from the digest
+    slots_range: Option<(u64, u64)>,
+    #[cfg(feature = "signing")]
+    verify_key: Option<PublicKey>,
+    // The digest a signature covers, before the last byte read by the framing
+    signed: Sha512,
+    // Index of the current entry
+    index: usize,
+    // Path of the current entry
+    path: Option<String>,
+    progress: Option<Box<dyn Progress>>,
+    written: u64,
+    total: Option<u64>,
+    // Type and sizes of the current entry, only kept for the progress observer
+    file_type: Option<FileType>,
+    entry_size: u64,
+    entry_compressed_size: u64,
+    // Offset of the end of archive marker and the digest before it
+    end: Option<(u64, Sha512)>,
+    method: EncryptMethod,
+    key_slots: Option<Vec<u8>>,
+    // A version 1 archive has no key slots, they are created from its password
+    // when it is rewritten
+    legacy: Option<(ScryptParams, Zeroizing<String>)>,
+}
+
+impl<R: Read> Decode<R> {
+    pub fn new(reader: R, password: Option<&str>, buf_size: usize) -> Result<Self, Error> {
+        let mut decode = Self {
+            inner: Hashing::new(reader),
+            framing: Framing::new(),
+            crypto: None,
+            info: String::new(),
+            buf_size,
+            slots_range: None,
+            #[cfg(feature = "signing")]
+            verify_key: None,
+            signed: Sha512::new(),
+            index: 0,
+            path: None,
+            progress: None,
+            written: 0,
+            total: None,
+            file_type: None,
+            entry_size: 0,
+            entry_compressed_size: 0,
+            end: None,
+            method: EncryptMethod::None,
+            key_slots: None,
+            legacy: None,
+        };
+        let header = match decode.next_frame()? {
+            Frame::Header(header) => header,
+            _ => unreachable!(),
+        };
+        decode.crypto = header.open(password)?;
+        if let (Some(params), Some(key)) = (&header.params, password) {
+            decode.legacy = Some((params.clone(), Zeroizing::new(key.to_string())));
+        }
+        decode.info = header.header.info;
+        decode.method = header.header.method;
+        decode.key_slots = header.slots;
+        decode.slots_range = header.slots_range;
+        if decode.framing.private() {
+            if let Frame::Info(info) = decode.next_frame()? {
+                decode.info = info;
+            }
+        }
+        Ok(decode)
+    }
+
+    /// Verify the signature when the end of the archive is reached.
+    ///
+    /// **The entries are not authenticated until the end**: they are yielded, and
+    /// `unpack` writes them to disk, before the signature is checked, only the last read
+    /// fails if it is missing or invalid. Use `verify_now` on a seekable reader to check
+    /// the signature before anything is read
+    #[cfg(feature = "signing")]
+    pub fn verify(&mut self, key: PublicKey) {
+        self.verify_key = Some(key);
+    }
+
+    pub fn info(&self) -> &str {
+        &self.info
+    }
+
+    /// Report the progress to an observer, `archive_size` is the total if it is known
+    pub fn progress(&mut self, observer: Box<dyn Progress>, archive_size: Option<u64>) {
+        self.progress = Some(observer);
+        self.total = archive_size;
+    }
+
+    fn report(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            progress.update(&Status {
+                entry: self.path.as_deref().unwrap_or_default(),
+                entries: self.index as u64,
+                bytes_read: self.inner.pos,
+                bytes_written: self.written,
+                total: self.total,
+                file_type: self.file_type,
+                entry_size: self.entry_size,
+                entry_compressed_size: self.entry_compressed_size,
+            });
+        }
+    }
+
+    /// The summary, available once all entries have been read
+    pub fn summary(&self) -> Option<&Summary> {
+        self.framing.summary()
+    }
+
+    /// The manifest, available once all entries have been read
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.framing.manifest()
+    }
+
+    // Read what the framing needs until it returns a frame
+    fn next_frame(&mut self) -> Result<Frame, Error> {
+        read_frame(
+            &mut self.inner,
+            &mut self.framing,
+            self.crypto.as_ref(),
+            Some(&mut self.signed),
+        )
+    }
+
+    // Read a chunk as it is stored, returns the chunk and its data,
+    // the data is None at the end of a chunk sequence
+    fn read_raw_data(&mut self) -> Result<(Vec<u8>, Option<Vec<u8>>), Error> {
+        let chunk = read_chunk(&mut self.inner).map_err(truncated)?;
+        let mut raw = Vec::with_capacity(2 + chunk.len() + 12);
+        raw.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        raw.extend_from_slice(&chunk);
+        if chunk.is_empty() {
+            return Ok((raw, None));
+        }
+        let data = match &self.crypto {
+            // The chunk is still authenticated, and the end of a file is padded in private mode
+            Some(crypto) => {
+                let nonce = read_nonce(&mut self.inner).map_err(truncated)?;
+                raw.extend_from_slice(&nonce);
+                crypto.open(&chunk, &nonce)?
+            }
+            None => Some(chunk),
+        };
+        Ok((raw, data))
+    }
+
+    // Read a chunk of the current file, returns None at the end of the file
+    fn read_file_data(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if !self.framing.in_file() {
+            return Ok(None);
+        }
+        let offset = self.inner.pos;
+        let data = match self
+            .next_frame()
+            .map_err(|err| err.context(Operation::ReadData, self.path.as_deref(), Some(offset)))?
+        {
+            Frame::Data(data) => Some(data),
+            _ => None,
+        };
+        if let Some(data) = &data {
+            self.entry_compressed_size += data.len() as u64;
+        }
+        self.report();
+        Ok(data)
+    }
+
+    // The manifest and the signature block follow the end of archive marker
+    fn read_end(&mut self) -> Result<(), Error> {
+        while !matches!(self.next_frame()?, Frame::Done) {}
+        #[cfg(feature = "signing")]
+        match (&self.verify_key, self.framing.signature()) {
+            (Some(key), Some(signature)) => {
+                verify_signature(key, self.signed.clone(), signature)?
+            }
+            (Some(_), None) => return Err(Error::SignatureMissing),
+            (None, _) => {}
+        }
+        Ok(())
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(FileType, String, Metadata)>, Error> {
+        while self.read_file_data()?.is_some() {}
+        if self.framing.is_done() {
+            return Ok(None);
+        }
+        let offset = self.inner.pos;
+        let digest = self.inner.digest.clone();
+        let frame = self
+            .next_frame()
+            .map_err(|err| err.context(Operation::ReadEntry, None, Some(offset)))?;
+        let (file_type, file_path, metadata) = match frame {
+            Frame::Entry(file_type, path, metadata) => (file_type, path, metadata),
+            _ => {
+                self.end = Some((offset, digest));
+                self.read_end()?;
+                self.file_type = None;
+                self.report();
+                return Ok(None);
+            }
+        };
+        self.index += 1;
+        self.path = Some(file_path.clone());
+        self.file_type = Some(file_type);
+        self.entry_size = 0;
+        self.entry_compressed_size = 0;
+        self.report();
+        Ok(Some((file_type, file_path, metadata)))
+    }
+
+    /// Skip to the next entry after an error, the rest of the entry and any damaged data
+    /// after it are discarded. Returns the number of bytes skipped,
+    /// or None if the archive ends before another entry.
+    /// Archives of versions before 6 have no sync markers and always return None
+    pub fn resync(&mut self) -> Result<Option<u64>, Error> {
+        if self.framing.version() < SYNC_VERSION {
+            self.framing.fail();
+            return Ok(None);
+        }
+        let start = self.inner.pos;
+        let mut scan = SyncScan::default();
+        while let Some(byte) = read_byte(&mut self.inner)? {
+            if scan.push(byte) {
+                self.framing.resync();
+                return Ok(Some(self.inner.pos - start - SYNC.len() as u64));
+            }
+        }
+        self.framing.fail();
+        Ok(None)
+    }
+
+    /// Read the next entry, the data of the previous file is skipped if it has not been read
+    pub fn read_path(&mut self) -> Result<Option<(FileType, String)>, Error> {
+        Ok(self
+            .read_entry()?
+            .map(|(file_type, path, _)| (file_type, path)))
+    }
+
+    /// Read the data of the current file, nothing is written for a directory
+    pub fn read_file<W: Write>(&mut self, writer: W) -> Result<(), Error> {
+        let mut writer = DecompressorWriter::new(writer, self.buf_size);
+        while let Some(data) = self.read_file_data()? {
+            writer.write_all(&data).rst()?;
+        }
+        writer.flush().rst()
+    }
+
+    /// Iterate over the entries, a file entry reads the decompressed content.
+    /// With `verify` an entry is unauthenticated until the iterator ends
+    pub fn entries(&mut self) -> Entries<'_, R> {
+        Entries {
+            decode: Rc::new(RefCell::new(self)),
+        }
+    }
+
+    /// Write all entries into the `dest` directory, it is created if it does not exist.
+    /// Entries with absolute paths or `..` components are rejected.
+    /// Returns the errors of the entries that were skipped with `keep_going`.
+    /// With `verify` the files are written before the signature is checked, use
+    /// `verify_now` to reject a forged archive before anything is written
+    pub fn unpack<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        options: &UnpackOptions,
+    ) -> Result<Vec<Error>, Error> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest).rst()?;
+        let mut failed = Vec::new();
+        loop {
+            match self.unpack_next(dest, options) {
+                Ok(true) => {}
+                Ok(false) => return Ok(failed),
+                Err(err) if options.keep_going => {
+                    if let Some(progress) = &mut self.progress {
+                        progress.failed(&err);
+                    }
+                    failed.push(err);
+                    if self.resync()?.is_none() {
+                        return Ok(failed);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Returns false at the end of the archive
+    fn unpack_next(&mut self, dest: &Path, options: &UnpackOptions) -> Result<bool, Error> {
+        let mut entries = self.entries();
+        let mut entry = match entries.next() {
+            Some(entry) => entry?,
+            None => return Ok(false),
+        };
+        let path = match safe_path(entry.path()) {
+            Some(path) => dest.join(path),
+            None => {
+                return Err(Error::FilePath.context(Operation::Unpack, Some(entry.path()), None))
+            }
+        };
+        unpack_entry(&mut entry, &path, options)
+            .map_err(|err| err.context(Operation::Unpack, Some(entry.path()), None))?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Decode<R> {
+    /// Compare the entries with the files in `dir` by decompressing them, unlike
+    /// `Manifest::diff_dir` the content of the archive is read and nothing is trusted
+    pub fn diff_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<Vec<Change>, Error> {
+        let dir = dir.as_ref();
+        let mut changes = Vec::new();
+        let mut paths = HashSet::new();
+        for entry in self.entries() {
+            let mut entry = entry?;
+            let p = entry.path().to_string();
+            if entry.file_type() == FileType::Deleted {
+                paths.remove(&p);
+                continue;
+            }
+            let path = match safe_path(&p) {
+                Some(path) => dir.join(path),
+                None => return Err(Error::FilePath.context(Operation::Compare, Some(&p), None)),
+            };
+            let change = compare_entry(&mut entry, &path)
+                .map_err(|err| err.context(Operation::Compare, Some(&p), None))?;
+            changes.extend(change);
+            paths.insert(p);
+        }
+        let roots = roots(paths.iter().map(String::as_str));
+        for (_, p) in walk_roots(dir, &roots)? {
+            if !paths.contains(&p) {
+                changes.push(Change::Added(p));
+            }
+        }
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(changes)
+    }
+
+    /// Build the manifest by decompressing every entry, for archives older than the manifest.
+    /// Unlike `read_manifest`, the sizes and checksums come from the content
+    pub fn build_manifest(&mut self) -> Result<Manifest, Error> {
+        let mut manifest = Manifest::default();
+        for entry in self.entries() {
+            let mut entry = entry?;
+            let path = entry.path().to_string();
+            let file_type = entry.file_type();
+            if file_type == FileType::Deleted {
+                manifest.remove(&path);
+                continue;
+            }
+            let mut hash = Sha256::new();
+            let size = io::copy(&mut entry, &mut hash).rst()?;
+            manifest.insert(ManifestEntry {
+                path,
+                file_type,
+                metadata: *entry.metadata(),
+                size,
+                hash: match file_type {
+                    FileType::File => hash.finalize().into(),
+                    _ => [0; 32],
+                },
+            });
+        }
+        Ok(manifest)
+    }
+
+    /// Write the entries that `keep` accepts to a new archive with the same info, encryption
+    /// and passwords. The chunks of the kept files are copied as they are, without being
+    /// decompressed or encrypted again. More entries can be added before the archive is finished.
+    /// `keep` is also asked about the paths of the manifest without an entry, which an
+    /// incremental archive lists. The new archive is only signed if `sign` is called, see
+    /// `Encode::was_signed`. An archive of an older version is written in the current version
+    pub fn rewrite<W: Write, F: FnMut(&str) -> bool>(
+        &mut self,
+        writer: W,
+        params: CompressParams,
+        mut keep: F,
+    ) -> Result<Encode<W>, Error> {
+        #[cfg(feature = "encryption")]
+        if let (None, Some((params, key))) = (&self.key_slots, &self.legacy) {
+            let (slots, _) = legacy_key_slots(params, key)?;
+            let mut buf = Vec::with_capacity(KEY_SLOT_SIZE * KEY_SLOTS);
+            write_key_slots(&mut buf, &slots).rst()?;
+            self.key_slots = Some(buf);
+        }
+        let mut encode = Encode::with_header(
+            writer,
+            &self.info,
+            self.method,
+            self.key_slots.as_deref(),
+            self.crypto.clone(),
+            params,
+        )?;
+        // The size and the hash of the kept files are only known from the summary
+        // and the manifest, older versions have neither and the files are decompressed
+        let legacy = self.framing.version() < MANIFEST_VERSION;
+        let mut dropped = 0;
+        let mut dropped_paths = HashSet::new();
+        let mut kept_paths = HashSet::new();
+        while let Some((file_type, path, metadata)) = self.read_entry()? {
+            if !keep(&path) {
+                let mut size = Count(0);
+                self.read_file(&mut size)?;
+                dropped += size.0;
+                dropped_paths.insert(path);
+                continue;
+            }
+            kept_paths.insert(path.clone());
+            encode.write_entry(file_type.tag(), &path, &metadata)?;
+            let mut content = DecompressorWriter::new(Content::default(), self.buf_size);
+            while self.framing.in_file() {
+                let offset = self.inner.pos;
+                let (raw, data) = self
+                    .read_raw_data()
+                    .map_err(|err| err.context(Operation::ReadData, Some(&path), Some(offset)))?;
+                let offset = encode.inner.pos;
+                encode
+                    .write_raw(&raw)
+                    .map_err(|err| err.context(Operation::WriteData, Some(&path), Some(offset)))?;
+                if let Some(Frame::Data(data)) = self.framing.feed(Input::Data(data))? {
+                    encode.summary.compressed_size += data.len() as u64;
+                    if legacy {
+                        content.write_all(&data).rst()?;
+                    }
+                }
+            }
+            if legacy {
+                content.flush().rst()?;
+                let content = content.get_ref();
+                encode.summary.size += content.size;
+                encode.manifest.insert(ManifestEntry {
+                    path,
+                    file_type,
+                    metadata,
+                    size: content.size,
+                    hash: match file_type {
+                        FileType::File => content.hash.clone().finalize().into(),
+                        _ => [0; 32],
+                    },
+                });
+            }
+        }
+        encode.was_signed = self.framing.signed();
+        if legacy {
+            return Ok(encode);
+        }
+        let size = self
+            .summary()
+            .map(|summary| summary.size)
+            .unwrap_or_default();
+        encode.summary.size += size.saturating_sub(dropped);
+        encode.manifest = self.manifest().cloned().unwrap_or_default();
+        encode.manifest.entries.retain(|path, _| {
+            kept_paths.contains(path) || (!dropped_paths.contains(path) && keep(path))
+        });
+        Ok(encode)
+    }
+}
+
+// Read what the framing needs until it returns a frame,
+// `signed` is set to the digest before each byte read
+fn read_frame<R: Read>(
+    r: &mut Hashing<R>,
+    framing: &mut Framing,
+    crypto: Option<&Crypto>,
+    mut signed: Option<&mut Sha512>,
+) -> Result<Frame, Error> {
+    loop {
+        let input = match framing.need() {
+            Need::Bytes { len, signed } => {
+                let mut buf = vec![0; len];
+                if signed {
+                    r.read_exact(&mut buf).map_err(truncated)?;
+                } else {
+                    r.inner.read_exact(&mut buf).map_err(truncated)?;
+                    r.pos += len as u64;
+                }
+                Input::Bytes(buf)
+            }
+            Need::Byte => {
+                if let Some(signed) = signed.as_deref_mut() {
+                    *signed = r.digest.clone();
+                }
+                Input::Byte(read_byte(r)?)
+            }
+            Need::Data => Input::Data(read_data(r, crypto)?),
+            Need::Nothing => return Ok(Frame::Done),
+        };
+        if let Some(frame) = framing.feed(input)? {
+            return Ok(frame);
+        }
+    }
+}
+
+// Returns None at the end of the archive
+fn read_byte<R: Read>(r: &mut R) -> Result<Option<u8>, Error> {
+    let mut buf = [0; 1];
+    match r.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf[0])),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(Error::IO(err)),
+    }
+}
+
+// Read a chunk, returns None at the end of a chunk sequence
+fn read_data<R: Read>(r: &mut R, crypto: Option<&Crypto>) -> Result<Option<Vec<u8>>, Error> {
+    let rst = match crypto {
+        Some(crypto) => read_encrypt_chunk(r, crypto),
+        None => read_chunk(r)
+            .rst()
+            .map(|data| if data.is_empty() { None } else { Some(data) }),
+    };
+    match rst {
+        Err(Error::IO(err)) if err.kind() == ErrorKind::UnexpectedEof => Err(Error::Truncated),
+        rst => rst,
+    }
+}
+
+// None if the entry matches the file on disk
+fn compare_entry<R: Read>(entry: &mut Entry<'_, R>, path: &Path) -> Result<Option<Change>, Error> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(Some(Change::Removed(entry.path().to_string())))
+        }
+        Err(err) => return Err(Error::IO(err)),
+    };
+    let same = match entry.file_type() {
+        FileType::File => metadata.is_file() && same_content(entry, path)?,
+        FileType::Directory => metadata.is_dir(),
+        FileType::Deleted => true,
+    };
+    Ok(if same {
+        None
+    } else {
+        Some(Change::Modified(entry.path().to_string()))
+    })
+}
+
+// Compare both streams a buffer at a time
+fn same_content<R: Read>(reader: &mut R, path: &Path) -> Result<bool, Error> {
+    let mut f = BufReader::new(File::open(path).rst()?);
+    let mut buf = vec![0; DEFAULT_BUF_SIZE];
+    let mut other = vec![0; DEFAULT_BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).map_err(from_io_error)?;
+        if n == 0 {
+            return Ok(f.read(&mut other).rst()? == 0);
+        }
+        match f.read_exact(&mut other[..n]) {
+            Ok(()) if buf[..n] == other[..n] => {}
+            Ok(()) => return Ok(false),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(Error::IO(err)),
+        }
+    }
+}
+
+// The archive ends in the middle of a chunk
+fn truncated(err: IoError) -> Error {
+    if err.kind() == ErrorKind::UnexpectedEof {
+        Error::Truncated
+    } else {
+        Error::IO(err)
+    }
+}
+
+// Counts the bytes written to it
+struct Count(u64);
+
+// The size and the SHA-256 of what is written
+#[derive(Default)]
+struct Content {
+    size: u64,
+    hash: Sha256,
+}
+
+impl Write for Content {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.size += buf.len() as u64;
+        self.hash.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Write for Count {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+// The relative path of an entry, None if it could be written outside of the destination
+fn safe_path(p: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(p).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+fn unpack_entry<R: Read>(
+    entry: &mut Entry<'_, R>,
+    path: &Path,
+    options: &UnpackOptions,
+) -> Result<(), Error> {
+    match entry.file_type() {
+        FileType::Directory => fs::create_dir_all(path).rst(),
+        // Like a file that is replaced, a deleted path is only removed with `overwrite`
+        FileType::Deleted => match fs::symlink_metadata(path) {
+            Ok(_) if !options.overwrite => Err(Error::IO(IoError::from(ErrorKind::AlreadyExists))),
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).rst(),
+            Ok(_) => fs::remove_file(path).rst(),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(Error::IO(err)),
+        },
+        FileType::File => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).rst()?;
+            }
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .create_new(!options.overwrite)
+                .truncate(true)
+                .open(path)
+                .rst()?;
+            // A partial file would look like a complete one
+            if let Err(err) = io::copy(entry, &mut f) {
+                let _ = fs::remove_file(path);
+                return Err(from_io_error(err));
+            }
+            if options.preserve_metadata {
+                set_metadata(path, &f, entry.metadata())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Restore the permissions and modification time of a file
+fn set_metadata(path: &Path, f: &File, metadata: &Metadata) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.mode != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(metadata.mode)).rst()?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    if metadata.mtime != 0 {
+        f.set_modified(UNIX_EPOCH + Duration::from_secs(metadata.mtime))
+            .rst()?;
+    }
+    Ok(())
+}
+
+fn parse_entry(tag: u8, mut buf: Vec<u8>) -> Result<(FileType, String, Metadata), Error> {
+    let file_type = FileType::parse(tag)?;
+    if buf.len() < Metadata::SIZE {
+        return Err(Error::FilePath);
+    }
+    let metadata = Metadata::parse(&buf);
+    let file_path = String::from_utf8(buf.split_off(Metadata::SIZE)).map_err(Error::Utf8)?;
+    Ok((file_type, file_path, metadata))
+}
+
+pub struct Entries<'a, R> {
+    decode: Rc<RefCell<&'a mut Decode<R>>>,
+}
+
+impl<'a, R: Read> Iterator for Entries<'a, R> {
+    type Item = Result<Entry<'a, R>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut decode = self.decode.borrow_mut();
+        let (file_type, path, metadata) = match decode.read_entry() {
+            Ok(entry) => entry?,
+            Err(err) => {
+                // Nothing can be read after an error
+                decode.framing.fail();
+                return Some(Err(err));
+            }
+        };
+        let data = match file_type {
+            FileType::File => Some(Decompressor::new(
+                EntryData {
+                    decode: self.decode.clone(),
+                    index: decode.index,
+                    buf: Vec::new(),
+                    pos: 0,
+                },
+                decode.buf_size,
+            )),
+            FileType::Directory | FileType::Deleted => None,
+        };
+        Some(Ok(Entry {
+            file_type,
+            path,
+            metadata,
+            data,
+            decode: self.decode.clone(),
+        }))
+    }
+}
+
+pub struct Entry<'a, R: Read> {
+    file_type: FileType,
+    path: String,
+    metadata: Metadata,
+    data: Option<Decompressor<EntryData<'a, R>>>,
+    decode: Rc<RefCell<&'a mut Decode<R>>>,
+}
+
+impl<'a, R: Read> Entry<'a, R> {
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+impl<'a, R: Read> Read for Entry<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = match &mut self.data {
+            Some(data) => data.read(buf)?,
+            None => return Ok(0),
+        };
+        let mut decode = self.decode.borrow_mut();
+        decode.written += n as u64;
+        decode.entry_size += n as u64;
+        Ok(n)
+    }
+}
+
+// The compressed chunks of a file entry
+struct EntryData<'a, R> {
+    decode: Rc<RefCell<&'a mut Decode<R>>>,
+    index: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R: Read> Read for EntryData<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.pos == self.buf.len() {
+            let mut decode = self.decode.borrow_mut();
+            if decode.index != self.index {
+                return Err(IoError::other("the iterator has moved past this entry"));
+            }
+            match decode.read_file_data().map_err(into_io_error)? {
+                Some(data) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = (&self.buf[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn into_io_error(err: Error) -> IoError {
+    match err {
+        Error::IO(err) => err,
+        err => IoError::new(ErrorKind::InvalidData, err),
+    }
+}
+
+// The archive error behind an IO error returned by `Entry::read`
+fn from_io_error(err: IoError) -> Error {
+    if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        *err.into_inner().unwrap().downcast::<Error>().unwrap()
+    } else {
+        Error::IO(err)
+    }
+}
+
+impl<R: Read + Seek> Decode<R> {
+    /// Verify the signature before any entry is yielded,
+    /// the reader must start at the beginning of the archive
+    #[cfg(feature = "signing")]
+    pub fn verify_now(&mut self, key: PublicKey) -> Result<(), Error> {
+        let trailer = trailer_size(self.framing.version());
+        let r = &mut self.inner.inner;
+        let pos = r.stream_position().rst()?;
+        let len = archive_len(r).rst()?;
+        if len < trailer {
+            return Err(Error::SignatureMissing);
+        }
+        let end = len - trailer;
+        r.seek(SeekFrom::Start(end)).rst()?;
+        let mut tag = [0; 1];
+        r.read_exact(&mut tag).rst()?;
+        if tag[0] != SIGNATURE {
+            return Err(Error::SignatureMissing);
+        }
+        let signature = read_signature(r)?;
+
+        let mut hashing = Hashing::new(io::sink());
+        let mut hash = |r: &mut R, from: u64, to: u64| {
+            r.seek(SeekFrom::Start(from)).rst()?;
+            io::copy(&mut r.take(to - from), &mut hashing).rst()
+        };
+        match self.slots_range {
+            Some((start, stop)) => {
+                hash(r, 0, start)?;
+                hash(r, stop, end)?;
+            }
+            None => {
+                hash(r, 0, end)?;
+            }
+        }
+        r.seek(SeekFrom::Start(pos)).rst()?;
+        verify_signature(&key, hashing.digest, &signature)?;
+        self.verify_key = Some(key);
+        Ok(())
+    }
+
+    /// Read the manifest at the end of the archive without reading the entries,
+    /// it is not covered by `verify`. Archives of older versions have no manifest
+    pub fn read_manifest(&mut self) -> Result<Manifest, Error> {
+        if self.framing.version() < MANIFEST_VERSION {
+            return Err(Error::InvalidVersion);
+        }
+        let r = &mut self.inner.inner;
+        let crypto = self.crypto.as_ref();
+        let pos = r.stream_position().rst()?;
+        let mut read = || {
+            let offset = manifest_offset(r)?;
+            r.seek(SeekFrom::Start(offset)).rst()?;
+            let mut manifest = Vec::new();
+            while let Some(data) = read_data(r, crypto)? {
+                manifest.extend_from_slice(&data);
+            }
+            Manifest::parse(&manifest)
+        };
+        let manifest = read();
+        r.seek(SeekFrom::Start(pos)).rst()?;
+        manifest
+    }
+
+    /// Read the summary, which is right before the manifest, without reading the entries.
+    /// It is not covered by `verify`. Archives of older versions have no manifest to find it
+    pub fn read_summary(&mut self) -> Result<Summary, Error> {
+        if self.framing.version() < MANIFEST_VERSION {
+            return Err(Error::InvalidVersion);
+        }
+        let private = self.framing.private();
+        let r = &mut self.inner.inner;
+        let crypto = self.crypto.as_ref();
+        let pos = r.stream_position().rst()?;
+        let mut read = || {
+            // The summary chunk has the same length in every archive of an encryption method,
+            // the end of archive marker is inside of it in private mode
+            let mut buf = Vec::with_capacity(1 + Summary::SIZE);
+            if private {
+                buf.push(END);
+            }
+            Summary::default().write(&mut buf);
+            let mut chunk = Vec::new();
+            match crypto {
+                Some(crypto) => write_encrypt_chunk(&mut chunk, crypto, &buf)?,
+                None => write_chunk(&mut chunk, &buf)?,
+            }
+            let len = chunk.len() as u64 + if private { 0 } else { 1 };
+            let offset = manifest_offset(r)?;
+            let start = offset.checked_sub(len).ok_or(Error::Truncated)?;
+            r.seek(SeekFrom::Start(start)).rst()?;
+            if !private {
+                let mut tag = [0; 1];
+                r.read_exact(&mut tag).map_err(truncated)?;
+                if tag[0] != END {
+                    return Err(Error::FileType(tag[0]));
+                }
+            }
+            let data = read_data(r, crypto)?.ok_or(Error::Truncated)?;
+            match (private, data.split_first()) {
+                (false, _) => Summary::parse(&data),
+                (true, Some((&END, summary))) => Summary::parse(summary),
+                (true, Some((&tag, _))) => Err(Error::FileType(tag)),
+                (true, None) => Err(Error::Truncated),
+            }
+        };
+        let summary = read();
+        r.seek(SeekFrom::Start(pos)).rst()?;
+        summary
+    }
+}
+
+// The offset of the manifest, read from the locator at the end of the archive
+fn manifest_offset<R: Read + Seek>(r: &mut R) -> Result<u64, Error> {
+    let len = archive_len(r).rst()?;
+    let locator = len
+        .checked_sub(LOCATOR_SIZE as u64)
+        .ok_or(Error::Truncated)?;
+    r.seek(SeekFrom::Start(locator)).rst()?;
+    read_locator(r)
+}
+
+#[cfg(feature = "encryption")]
+pub enum Rekey {
+    /// Replace the password that unlocked the archive
+    Change(Password),
+    /// Add another password that can unlock the archive
+    Add(Password),
+    /// Remove the password that unlocked the archive
+    Remove,
+}
+
+/// Change the passwords of an encrypted archive,
+/// only the key slots are rewritten and the entries are left untouched.
+/// The recovery record covers the key slots, it is computed again.
+/// A version 1 archive has no key slots, it is written again with `rekey_rewrite`
+#[cfg(feature = "encryption")]
+pub fn rekey<F: Read + Write + Seek>(mut file: F, password: &str, op: Rekey) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(0)).rst()?;
+    read_head(&mut file)?;
+    let version = read_version(&mut file)?;
+    read_chunk(&mut file).rst()?;
+    if read_encrypt_method(&mut file)? == EncryptMethod::None {
+        return Err(Error::NoPasswordRequired);
+    }
+    if version == 1 {
+        return Err(Error::RewriteRequired);
+    }
+    let offset = file.stream_position().rst()?;
+    // Otherwise `repair` would bring the old key slots back
+    #[cfg(feature = "recovery")]
+    let recovery = recovery_percent(&mut file)?;
+    #[cfg(not(feature = "recovery"))]
+    if archive_len(&mut file).rst()? != file.seek(SeekFrom::End(0)).rst()? {
+        return Err(Error::RecoveryDisabled);
+    }
+    file.seek(SeekFrom::Start(offset)).rst()?;
+    let mut slots = read_key_slots(&mut file)?;
+
+    let (index, data_key) = unlock(&slots, password)?;
+    change_key_slots(&mut slots, index, &data_key, op)?;
+    file.seek(SeekFrom::Start(offset)).rst()?;
+    write_key_slots(&mut file, &slots).rst()?;
+    #[cfg(feature = "recovery")]
+    if let Some(percent) = recovery {
+        add_recovery(&mut file, percent)?;
+    }
+    file.flush().rst()
+}
+
+/// Change the passwords of a version 1 archive, which has no key slots, by writing it
+/// to `writer` in the current version with key slots. The original is left untouched,
+/// write to a new file and rename it over the original. Returns the writer
+#[cfg(feature = "encryption")]
+pub fn rekey_rewrite<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    password: &str,
+    op: Rekey,
+) -> Result<W, Error> {
+    let mut decode = Decode::new(reader, Some(password), DEFAULT_BUF_SIZE)?;
+    let (params, key) = decode.legacy.as_ref().ok_or(Error::InvalidVersion)?;
+    let (mut slots, data_key) = legacy_key_slots(params, key)?;
+    change_key_slots(&mut slots, 0, &data_key, op)?;
+    let mut buf = Vec::with_capacity(KEY_SLOT_SIZE * KEY_SLOTS);
+    write_key_slots(&mut buf, &slots).rst()?;
+    decode.key_slots = Some(buf);
+    // The password is only checked by decrypting the entries
+    decode
+        .rewrite(writer, CompressParams::default(), |_| true)
+        .and_then(Encode::finish)
+        .map_err(|err| match err.root() {
+            Error::DecryptionFailed => Error::InvalidPassword,
+            _ => err,
+        })
+}
 
-    pub fn read_file<W: Write>(&mut self, writer: W) -> Result<(), Error> {
-        let mut writer = DecompressorWriter::new(writer, self.buf_size);
-        match &self.cipher {
-            Some(cipher) => loop {
-                let data = match read_encrypt_chunk(&mut self.inner, cipher)? {
-                    Some(data) => data,
-                    None => break,
-                };
-                writer.write(&data).rst()?;
-            },
-            None => loop {
-                let data = read_chunk(&mut self.inner).rst()?;
-                if data.is_empty() {
-                    break;
-                }
-                writer.write(&data).rst()?;
-            },
+#[cfg(feature = "encryption")]
+fn change_key_slots(
+    slots: &mut KeySlots,
+    index: usize,
+    data_key: &[u8; 32],
+    op: Rekey,
+) -> Result<(), Error> {
+    match op {
+        Rekey::Change(pw) => {
+            slots[index] = Some(KeySlot::wrap(&pw, data_key)?);
+        }
+        Rekey::Add(pw) => match slots.iter().position(|slot| slot.is_none()) {
+            Some(i) => slots[i] = Some(KeySlot::wrap(&pw, data_key)?),
+            None => return Err(Error::NoFreeKeySlot),
+        },
+        Rekey::Remove => {
+            if slots.iter().filter(|slot| slot.is_some()).count() == 1 {
+                return Err(Error::LastKeySlot);
+            }
+            slots[index] = None;
         }
-        writer.flush().rst()
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -512,10 +3086,13 @@ mod tests {
     use super::*;
     use std::env::temp_dir;
     use std::fs::{File, OpenOptions};
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
 
     fn archive(name: &str) -> File {
         OpenOptions::new()
             .create(true)
+            .truncate(false)
             .write(true)
             .read(true)
             .open(temp_dir().join(name))
@@ -526,6 +3103,9 @@ mod tests {
     fn test_head() {
         write_head(&mut archive("name")).unwrap();
         read_head(&mut archive("name")).unwrap();
+        assert!(is_archive(archive("name")));
+        assert!(!is_archive(&b"PK\x03\x04"[..]));
+        assert!(!is_archive(&b"me"[..]));
     }
 
     #[test]
@@ -534,19 +3114,342 @@ mod tests {
         read_version(&mut archive("version")).unwrap();
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_scrypt_params() {
-        write_scrypt_params(&mut archive("scrypt"), None).unwrap();
-        assert!(read_scrypt_option(&mut archive("scrypt"))
-            .unwrap()
-            .is_none());
-
         let opt = ScryptParams::default();
-        write_scrypt_params(&mut archive("scrypt"), Some(&opt)).unwrap();
-        let params = read_scrypt_option(&mut archive("scrypt")).unwrap().unwrap();
-        assert_eq!(params.n, opt.n);
-        assert_eq!(params.p, opt.p);
-        assert_eq!(params.p, opt.p);
+        write_scrypt_params(&mut archive("scrypt"), &opt).unwrap();
+        let params = read_scrypt_params(&mut archive("scrypt")).unwrap();
+        assert_eq!(params, opt);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_key_slots() {
+        write_encrypt_method(&mut archive("encrypt"), EncryptMethod::Aes256GcmPrivate).unwrap();
+        assert_eq!(
+            read_encrypt_method(&mut archive("encrypt")).unwrap(),
+            EncryptMethod::Aes256GcmPrivate
+        );
+
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut slots = KeySlots::default();
+        slots[1] = Some(KeySlot::wrap(&pw, &[1; 32]).unwrap());
+        write_key_slots(&mut archive("slots"), &slots).unwrap();
+        let slots = read_key_slots(&mut archive("slots")).unwrap();
+        let (index, data_key) = unlock(&slots, "123456").unwrap();
+        assert_eq!((index, *data_key), (1, [1; 32]));
+        assert!(matches!(
+            unlock(&slots, "654321"),
+            Err(Error::InvalidPassword)
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_read_header() {
+        let params = ScryptParams {
+            n: 10,
+            ..ScryptParams::default()
+        };
+        let mut buf = Vec::new();
+        let pw = Password::new("123456", params.clone());
+        let encode = Encode::new(&mut buf, "info", Some(pw), CompressParams::default()).unwrap();
+        encode.finish().unwrap();
+        let header = read_header(&buf[..]).unwrap();
+        assert_eq!(header.version, VERSION[0]);
+        assert_eq!(header.info, "info");
+        assert_eq!(header.method, EncryptMethod::Aes256Gcm);
+        assert_eq!(header.key_slots, vec![params]);
+        assert!(matches!(
+            read_header(&b"mei\0"[..]),
+            Err(Error::InvalidVersion)
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_rekey() {
+        let pw = |key| Password::new(key, ScryptParams::default());
+        let mut f = archive("rekey");
+        let mut encode = Encode::new(&mut f, "", Some(pw("a")), CompressParams::default()).unwrap();
+        encode.write_directory("directory").unwrap();
+
+        rekey(archive("rekey"), "a", Rekey::Add(pw("b"))).unwrap();
+        rekey(archive("rekey"), "a", Rekey::Change(pw("c"))).unwrap();
+        assert!(matches!(
+            rekey(archive("rekey"), "a", Rekey::Remove),
+            Err(Error::InvalidPassword)
+        ));
+        rekey(archive("rekey"), "c", Rekey::Remove).unwrap();
+        assert!(matches!(
+            rekey(archive("rekey"), "b", Rekey::Remove),
+            Err(Error::LastKeySlot)
+        ));
+
+        let mut decode = Decode::new(archive("rekey"), Some("b"), DEFAULT_BUF_SIZE).unwrap();
+        assert_eq!(
+            decode.read_path().unwrap().unwrap(),
+            (FileType::Directory, "directory".to_string())
+        );
+    }
+
+    // The archives in tests/fixtures were written by older versions, with a directory `dir`,
+    // a text file `dir/a.txt` and an incompressible file `b.bin`
+    fn check_fixture<R: Read>(decode: &mut Decode<R>, info: &str, metadata: bool) {
+        let mut x: u32 = 1;
+        let binary = (0..1000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        let expected = [
+            (FileType::Directory, "dir", Vec::new(), 0o755),
+            (FileType::File, "dir/a.txt", b"hello mei\n".repeat(20), 0o644),
+            (FileType::File, "b.bin", binary, 0o644),
+        ];
+        assert_eq!(decode.info(), info);
+        let mut entries = decode.entries();
+        for (file_type, path, content, mode) in expected {
+            let mut entry = entries.next().unwrap().unwrap();
+            assert_eq!((entry.file_type(), entry.path()), (file_type, path));
+            let expected = if metadata {
+                Metadata {
+                    mtime: 1_600_000_000,
+                    mode,
+                }
+            } else {
+                Metadata::default()
+            };
+            assert_eq!(entry.metadata(), &expected);
+            let mut buf = Vec::new();
+            entry.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, content);
+        }
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_v1_fixture() {
+        let buf = include_bytes!("../tests/fixtures/v1.mei");
+        assert_eq!(read_header(&buf[..]).unwrap().version, 1);
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        check_fixture(&mut decode, "v1 fixture", false);
+        assert!(decode.summary().is_none());
+        fs::write(temp_dir().join("v1_append"), &buf[..]).unwrap();
+        assert!(matches!(
+            Encode::open_append(archive("v1_append"), None, CompressParams::default()),
+            Err(Error::InvalidVersion)
+        ));
+
+        // Rewritten in the current version, the summary and the manifest are computed
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        let encode = decode
+            .rewrite(Vec::new(), CompressParams::default(), |_| true)
+            .unwrap();
+        let buf = encode.finish().unwrap();
+        assert_eq!(read_header(&buf[..]).unwrap().version, VERSION[0]);
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        check_fixture(&mut decode, "v1 fixture", false);
+        let summary = decode.summary().unwrap();
+        assert_eq!((summary.entries, summary.size), (3, 1200));
+        let manifest = decode.manifest().unwrap();
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(manifest.get("b.bin").unwrap().size, 1000);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_v1_encrypted_fixture() {
+        let buf = include_bytes!("../tests/fixtures/v1-encrypted.mei");
+        let header = read_header(&buf[..]).unwrap();
+        assert_eq!((header.version, header.key_slots.len()), (1, 1));
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        check_fixture(&mut decode, "v1 fixture", false);
+        let mut decode = Decode::new(&buf[..], Some("123456"), DEFAULT_BUF_SIZE).unwrap();
+        assert!(matches!(
+            decode.read_path().unwrap_err().root(),
+            Error::DecryptionFailed
+        ));
+
+        // Changing the passwords migrates the archive to key slots in a new archive
+        let pw = || Password::new("123456", ScryptParams::default());
+        assert!(matches!(
+            rekey(Cursor::new(buf.to_vec()), "mei", Rekey::Add(pw())),
+            Err(Error::RewriteRequired)
+        ));
+        assert!(matches!(
+            rekey_rewrite(&buf[..], Vec::new(), "654321", Rekey::Add(pw())),
+            Err(Error::InvalidPassword)
+        ));
+        let buf = rekey_rewrite(&buf[..], Vec::new(), "mei", Rekey::Add(pw())).unwrap();
+        let header = read_header(&buf[..]).unwrap();
+        assert_eq!((header.version, header.key_slots.len()), (VERSION[0], 2));
+        for password in &["mei", "123456"] {
+            let mut decode = Decode::new(&buf[..], Some(password), DEFAULT_BUF_SIZE).unwrap();
+            check_fixture(&mut decode, "v1 fixture", false);
+            assert_eq!(decode.summary().unwrap().size, 1200);
+        }
+    }
+
+    // The public key of the signed fixtures
+    #[cfg(feature = "signing")]
+    fn fixture_key() -> PublicKey {
+        PublicKey::from(&SecretKey::from_bytes(&[7; 32]).unwrap())
+    }
+
+    #[cfg(all(feature = "encryption", feature = "signing"))]
+    #[test]
+    fn test_v2_signed_fixture() {
+        let buf = include_bytes!("../tests/fixtures/v2-signed.mei");
+        assert_eq!(read_header(&buf[..]).unwrap().version, 2);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify(fixture_key());
+        check_fixture(&mut decode, "v2 fixture", false);
+        let mut decode = Decode::new(Cursor::new(&buf), Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify_now(fixture_key()).unwrap();
+        let other = Keypair::generate(&mut rand::rngs::OsRng).public;
+        assert!(matches!(
+            decode.verify_now(other),
+            Err(Error::InvalidSignature)
+        ));
+        assert!(matches!(decode.read_manifest(), Err(Error::InvalidVersion)));
+
+        // The key slots are changed in place and the signature stays valid
+        let mut f = Cursor::new(buf.to_vec());
+        let pw = Password::new("123456", ScryptParams::default());
+        rekey(&mut f, "mei", Rekey::Add(pw)).unwrap();
+        let buf = f.into_inner();
+        assert_eq!(read_header(&buf[..]).unwrap().version, 2);
+        let mut decode = Decode::new(&buf[..], Some("123456"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify(fixture_key());
+        check_fixture(&mut decode, "v2 fixture", false);
+    }
+
+    #[cfg(all(feature = "encryption", feature = "signing"))]
+    #[test]
+    fn test_v2_private_fixture() {
+        let buf = include_bytes!("../tests/fixtures/v2-private.mei");
+        let header = read_header(&buf[..]).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.method, EncryptMethod::Aes256GcmPrivate);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        check_fixture(&mut decode, "v2 fixture", false);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify(Keypair::generate(&mut rand::rngs::OsRng).public);
+        assert!(decode
+            .entries()
+            .any(|entry| matches!(entry, Err(Error::SignatureMissing))));
+
+        // Rewritten in the current version, still private
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        let encode = decode
+            .rewrite(Vec::new(), CompressParams::default(), |_| true)
+            .unwrap();
+        let buf = encode.finish().unwrap();
+        let header = read_header(&buf[..]).unwrap();
+        assert_eq!(header.version, VERSION[0]);
+        assert_eq!(header.method, EncryptMethod::Aes256GcmPrivate);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        check_fixture(&mut decode, "v2 fixture", false);
+        assert_eq!(decode.summary().unwrap().size, 1200);
+    }
+
+    #[cfg(all(feature = "encryption", feature = "signing"))]
+    #[test]
+    fn test_v3_private_signed_fixture() {
+        let buf = include_bytes!("../tests/fixtures/v3-private-signed.mei");
+        assert_eq!(read_header(&buf[..]).unwrap().version, 3);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify(fixture_key());
+        check_fixture(&mut decode, "v3 fixture", true);
+        let mut decode = Decode::new(Cursor::new(&buf), Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify_now(fixture_key()).unwrap();
+
+        let dest = temp_dir().join("mei_v3_fixture");
+        let _ = fs::remove_dir_all(&dest);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.unpack(&dest, &UnpackOptions::new()).unwrap();
+        let modified = fs::metadata(dest.join("b.bin")).unwrap().modified().unwrap();
+        assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn test_v4_signed_fixture() {
+        let buf = include_bytes!("../tests/fixtures/v4-signed.mei");
+        assert_eq!(read_header(&buf[..]).unwrap().version, 4);
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        decode.verify(fixture_key());
+        check_fixture(&mut decode, "v4 fixture", true);
+        let summary = decode.summary().unwrap();
+        assert_eq!((summary.entries, summary.size), (3, 1200));
+        assert!(decode.manifest().is_none());
+        let mut decode = Decode::new(Cursor::new(&buf), None, DEFAULT_BUF_SIZE).unwrap();
+        decode.verify_now(fixture_key()).unwrap();
+
+        // A damaged signature block
+        let mut buf = buf.to_vec();
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        decode.verify(fixture_key());
+        assert!(decode
+            .entries()
+            .any(|entry| matches!(entry, Err(Error::InvalidSignature))));
+    }
+
+    #[cfg(all(feature = "encryption", feature = "signing"))]
+    #[test]
+    fn test_v5_signed_fixture() {
+        let buf = include_bytes!("../tests/fixtures/v5-signed.mei");
+        assert_eq!(read_header(&buf[..]).unwrap().version, 5);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify(fixture_key());
+        check_fixture(&mut decode, "v5 fixture", true);
+        assert_eq!(decode.manifest().unwrap().len(), 3);
+        let mut decode = Decode::new(Cursor::new(&buf), Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify_now(fixture_key()).unwrap();
+        let manifest = decode.read_manifest().unwrap();
+        assert_eq!(manifest.get("dir/a.txt").unwrap().size, 200);
+
+        // Rewritten in the current version with the same manifest
+        let encode = decode
+            .rewrite(Vec::new(), CompressParams::default(), |path| path != "b.bin")
+            .unwrap();
+        let buf = encode.finish().unwrap();
+        assert_eq!(read_header(&buf[..]).unwrap().version, VERSION[0]);
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        assert_eq!(decode.entries().count(), 2);
+        assert_eq!(decode.summary().unwrap().size, 200);
+        assert_eq!(decode.manifest().unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_v5_keep_going() {
+        let dest = temp_dir().join("mei_v5_keep_going");
+        let _ = fs::remove_dir_all(&dest);
+        let mut buf = include_bytes!("../tests/fixtures/v5-signed.mei").to_vec();
+        // Damage the data of `dir/a.txt`
+        buf[810] ^= 1;
+        let mut decode = Decode::new(&buf[..], Some("mei"), DEFAULT_BUF_SIZE).unwrap();
+        let failed = decode
+            .unpack(&dest, UnpackOptions::new().keep_going(true))
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(failed[0].root(), Error::DecryptionFailed));
+        assert!(failed[0].to_string().contains("'dir/a.txt'"));
+        assert!(dest.join("dir").is_dir());
+        assert!(!dest.join("b.bin").exists());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_password_debug() {
+        let pw = Password::new("123456", ScryptParams::default());
+        assert!(!format!("{:?}", pw).contains("123456"));
     }
 
     #[test]
@@ -555,6 +3458,7 @@ mod tests {
         let mut encode = Encode::new(&mut f, "info", None, CompressParams::default()).unwrap();
         encode.write_directory("directory").unwrap();
         encode.write_file("file", &mut archive("data")).unwrap();
+        encode.finish().unwrap();
 
         let mut f = archive("encode");
         let mut decode = Decode::new(&mut f, None, DEFAULT_BUF_SIZE).unwrap();
@@ -572,4 +3476,906 @@ mod tests {
         decode.read_file(&mut std::io::stdout()).unwrap();
         assert!(decode.read_path().unwrap().is_none());
     }
+
+    #[test]
+    fn test_truncated() {
+        let mut encode = Encode::new(Vec::new(), "info", None, CompressParams::default()).unwrap();
+        let mut data: &[u8] = b"hello";
+        encode.write_file("file", &mut data).unwrap();
+        let buf = encode.finish().unwrap();
+
+        let mut decode = Decode::new(Cursor::new(buf.clone()), None, DEFAULT_BUF_SIZE).unwrap();
+        while decode.read_path().unwrap().is_some() {}
+        let summary = decode.summary().unwrap();
+        assert_eq!((summary.entries, summary.size), (1, 5));
+
+        let truncated = buf[..buf.len() - Summary::SIZE - 3].to_vec();
+        let mut decode = Decode::new(Cursor::new(truncated), None, DEFAULT_BUF_SIZE).unwrap();
+        decode.read_path().unwrap();
+        let err = decode.read_path().unwrap_err();
+        assert!(matches!(err.root(), Error::Truncated));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_error_context() {
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut encode =
+            Encode::new(Vec::new(), "info", Some(pw), CompressParams::default()).unwrap();
+        let mut data: &[u8] = b"hello";
+        encode.write_file("a/b.txt", &mut data).unwrap();
+        let mut buf = encode.finish().unwrap();
+
+        // The first data chunk follows the entry header
+        let header = SYNC.len() + 1 + 2 + 12 + Metadata::SIZE + "a/b.txt".len() + 16;
+        let data = HEAD.len() + VERSION.len() + 2 + 4 + 1 + KEY_SLOT_SIZE * KEY_SLOTS + header;
+        buf[data + 2 + 12] ^= 1;
+
+        let mut decode = Decode::new(Cursor::new(buf), Some("123456"), 1).unwrap();
+        decode.read_path().unwrap();
+        let err = decode.read_file(io::sink()).unwrap_err();
+        assert!(matches!(err.root(), Error::DecryptionFailed));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "reading the data of entry 'a/b.txt' at offset {}: authentication tag mismatch",
+                data
+            )
+        );
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn test_encryption_disabled() {
+        let mut buf = Vec::new();
+        write_head(&mut buf).unwrap();
+        write_version(&mut buf).unwrap();
+        write_chunk(&mut buf, b"info").unwrap();
+        write_encrypt_method(&mut buf, EncryptMethod::Aes256Gcm).unwrap();
+        buf.extend_from_slice(&[0; KEY_SLOT_SIZE * KEY_SLOTS]);
+
+        let decode = Decode::new(Cursor::new(buf), Some("123456"), DEFAULT_BUF_SIZE);
+        assert!(matches!(decode, Err(Error::EncryptionDisabled)));
+    }
+
+    // The signature block is skipped when it cannot be verified
+    #[cfg(not(feature = "signing"))]
+    #[test]
+    fn test_signing_disabled() {
+        let buf = include_bytes!("../tests/fixtures/v4-signed.mei");
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        check_fixture(&mut decode, "v4 fixture", true);
+        assert!(decode.framing.is_done());
+    }
+
+    #[test]
+    fn test_unpack() {
+        let src = temp_dir().join("mei_unpack_src");
+        let dest = temp_dir().join("mei_unpack_dest");
+        let _ = fs::remove_dir_all(&src);
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("dir/file"), b"hello").unwrap();
+        fs::write(src.join("skip"), b"").unwrap();
+
+        let mut encode = Encode::new(Vec::new(), "info", None, CompressParams::default()).unwrap();
+        encode
+            .append_dir_all(&src, "root", WalkOptions::new().exclude(src.join("skip")))
+            .unwrap();
+        encode.write_directory("../escape").unwrap();
+        let buf = encode.finish().unwrap();
+
+        let mut decode = Decode::new(Cursor::new(buf), None, DEFAULT_BUF_SIZE).unwrap();
+        let err = decode.unpack(&dest, &UnpackOptions::new()).unwrap_err();
+        assert!(matches!(err.root(), Error::FilePath));
+        assert_eq!(fs::read(dest.join("root/dir/file")).unwrap(), b"hello");
+        assert!(!dest.join("root/skip").exists());
+        assert!(!temp_dir().join("escape").exists());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_keep_going() {
+        let dest = temp_dir().join("mei_keep_going");
+        let _ = fs::remove_dir_all(&dest);
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut encode =
+            Encode::new(Vec::new(), "info", Some(pw), CompressParams::default()).unwrap();
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        for p in &["a", "b", "c"] {
+            encode.write_file(p, &mut &data[..]).unwrap();
+        }
+        let mut buf = encode.finish().unwrap();
+        let syncs: Vec<usize> = (0..buf.len())
+            .filter(|&i| buf[i..].starts_with(&SYNC))
+            .collect();
+        assert_eq!(syncs.len(), 4);
+        // Damage the data of `b` and the header of `c`
+        buf[syncs[2] - 100] ^= 1;
+        buf[syncs[2] + SYNC.len() + 20] ^= 1;
+
+        let mut decode = Decode::new(Cursor::new(&buf), Some("123456"), DEFAULT_BUF_SIZE).unwrap();
+        let err = decode.unpack(&dest, &UnpackOptions::new()).unwrap_err();
+        assert!(matches!(err.root(), Error::DecryptionFailed));
+
+        struct Failed(Arc<Mutex<usize>>);
+        impl Progress for Failed {
+            fn update(&mut self, _: &Status<'_>) {}
+
+            fn failed(&mut self, _: &Error) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let _ = fs::remove_dir_all(&dest);
+        let mut decode = Decode::new(Cursor::new(&buf), Some("123456"), DEFAULT_BUF_SIZE).unwrap();
+        let reported = Arc::new(Mutex::new(0));
+        decode.progress(Box::new(Failed(reported.clone())), None);
+        let failed = decode
+            .unpack(&dest, UnpackOptions::new().keep_going(true))
+            .unwrap();
+        assert_eq!(failed.len(), 2);
+        assert_eq!(*reported.lock().unwrap(), 2);
+        assert!(failed
+            .iter()
+            .all(|err| matches!(err.root(), Error::DecryptionFailed)));
+        assert!(failed[0].to_string().contains("'b'"));
+        assert_eq!(fs::read(dest.join("a")).unwrap(), data);
+        assert!(!dest.join("b").exists());
+        assert!(!dest.join("c").exists());
+        assert_eq!(decode.summary().unwrap().entries, 3);
+    }
+
+    #[test]
+    fn test_path_too_long() {
+        let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+        let long = "a".repeat(65536);
+        let err = encode.write_directory(&long).unwrap_err();
+        assert!(matches!(err.root(), Error::PathTooLong));
+        let err = encode.write_file(&long, &mut &b"a"[..]).unwrap_err();
+        assert!(matches!(err.root(), Error::PathTooLong));
+
+        // Nothing of the rejected entries was written
+        encode.write_file("a", &mut &b"a"[..]).unwrap();
+        let archive = encode.finish().unwrap();
+        let mut decode = Decode::new(Cursor::new(archive), None, DEFAULT_BUF_SIZE).unwrap();
+        let manifest = decode.read_manifest().unwrap();
+        assert_eq!(manifest.len(), 1);
+        assert!(manifest.get("a").is_some());
+    }
+
+    #[test]
+    fn test_incremental() {
+        let src = temp_dir().join("mei_incremental_src");
+        let dest = temp_dir().join("mei_incremental_dest");
+        let _ = fs::remove_dir_all(&src);
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("dir/a"), b"a").unwrap();
+        fs::write(src.join("dir/b"), b"b").unwrap();
+        fs::write(src.join("c"), b"c").unwrap();
+
+        let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+        encode
+            .append_dir_all(&src, "root", &WalkOptions::new())
+            .unwrap();
+        let base = encode.finish().unwrap();
+        let manifest = Decode::new(Cursor::new(base.clone()), None, DEFAULT_BUF_SIZE)
+            .unwrap()
+            .read_manifest()
+            .unwrap();
+        assert_eq!(manifest.len(), 5);
+        assert_eq!(manifest.get("root/dir/a").unwrap().size, 1);
+
+        fs::remove_file(src.join("dir/b")).unwrap();
+        fs::write(src.join("c"), b"cc").unwrap();
+        let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+        encode
+            .append_dir_all(&src, "root", WalkOptions::new().incremental(manifest))
+            .unwrap();
+        let incremental = encode.finish().unwrap();
+
+        let mut decode = Decode::new(Cursor::new(incremental.clone()), None, 1).unwrap();
+        let entries: Vec<(FileType, String)> =
+            std::iter::from_fn(|| decode.read_path().unwrap()).collect();
+        assert_eq!(
+            entries,
+            [
+                (FileType::File, "root/c".to_string()),
+                (FileType::Deleted, "root/dir/b".to_string())
+            ]
+        );
+        let paths: Vec<&str> = decode
+            .manifest()
+            .unwrap()
+            .entries()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        assert_eq!(paths, ["root", "root/c", "root/dir", "root/dir/a"]);
+
+        // The manifest lists `root/dir/a` without an entry, it is filtered too
+        let mut decode = Decode::new(Cursor::new(incremental.clone()), None, 1).unwrap();
+        let encode = decode
+            .rewrite(Vec::new(), CompressParams::default(), |path| {
+                !path.starts_with("root/dir")
+            })
+            .unwrap();
+        assert!(!encode.was_signed());
+        let rewritten = encode.finish().unwrap();
+        let manifest = Decode::new(Cursor::new(rewritten), None, DEFAULT_BUF_SIZE)
+            .unwrap()
+            .read_manifest()
+            .unwrap();
+        let paths: Vec<&str> = manifest.entries().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["root", "root/c"]);
+
+        for (buf, overwrite) in [(base, false), (incremental, true)] {
+            let mut decode = Decode::new(Cursor::new(buf), None, DEFAULT_BUF_SIZE).unwrap();
+            decode
+                .unpack(&dest, UnpackOptions::new().overwrite(overwrite))
+                .unwrap();
+        }
+        assert_eq!(fs::read(dest.join("root/c")).unwrap(), b"cc");
+        assert_eq!(fs::read(dest.join("root/dir/a")).unwrap(), b"a");
+        assert!(!dest.join("root/dir/b").exists());
+    }
+
+    #[test]
+    fn test_diff() {
+        let src = temp_dir().join("mei_diff_src");
+        let dest = temp_dir().join("mei_diff_dest");
+        let _ = fs::remove_dir_all(&src);
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("dir/a"), b"a").unwrap();
+        fs::write(src.join("b"), b"b").unwrap();
+        fs::write(src.join("c"), b"c").unwrap();
+
+        let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+        encode
+            .append_dir_all(&src, "root", &WalkOptions::new())
+            .unwrap();
+        let buf = encode.finish().unwrap();
+        let decode = || Decode::new(Cursor::new(buf.clone()), None, DEFAULT_BUF_SIZE).unwrap();
+        decode().unpack(&dest, &UnpackOptions::new()).unwrap();
+        fs::create_dir(dest.join("unrelated")).unwrap();
+        let manifest = decode().read_manifest().unwrap();
+        assert_eq!(decode().build_manifest().unwrap(), manifest);
+        assert!(manifest.diff_dir(&dest).unwrap().is_empty());
+        assert!(decode().diff_dir(&dest).unwrap().is_empty());
+
+        // The size does not change, only the checksum does
+        fs::write(dest.join("root/dir/a"), b"x").unwrap();
+        fs::remove_file(dest.join("root/b")).unwrap();
+        fs::write(dest.join("root/d"), b"d").unwrap();
+        let changes = [
+            Change::Removed("root/b".to_string()),
+            Change::Added("root/d".to_string()),
+            Change::Modified("root/dir/a".to_string()),
+        ];
+        assert_eq!(manifest.diff_dir(&dest).unwrap(), changes);
+        assert_eq!(decode().diff_dir(&dest).unwrap(), changes);
+
+        let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+        encode
+            .append_dir_all(dest.join("root"), "root", &WalkOptions::new())
+            .unwrap();
+        let other = Decode::new(Cursor::new(encode.finish().unwrap()), None, 1)
+            .unwrap()
+            .read_manifest()
+            .unwrap();
+        assert_eq!(manifest.diff(&other), changes);
+    }
+
+    #[test]
+    fn test_volumes() {
+        let base = temp_dir().join("mei_volumes.mei");
+        let volume = |n: u32| temp_dir().join(format!("mei_volumes.mei.{:03}", n));
+        let data: Vec<u8> = (0..10_000).map(|_| rand::random()).collect();
+        let w = VolumeWriter::create(&base, 1000).unwrap();
+        let mut encode = Encode::new(w, "info", None, CompressParams::default()).unwrap();
+        encode.write_file("file", &mut &data[..]).unwrap();
+        let paths = encode.finish().unwrap().finish().unwrap();
+        assert!(paths.len() > 10);
+        assert!(paths
+            .iter()
+            .all(|path| fs::metadata(path).unwrap().len() <= 1000));
+
+        let reader = VolumeReader::open(&base).unwrap();
+        assert_eq!(reader.volumes(), paths.len());
+        let mut decode = Decode::new(reader, None, DEFAULT_BUF_SIZE).unwrap();
+        assert_eq!(decode.read_manifest().unwrap().len(), 1);
+        decode.read_path().unwrap().unwrap();
+        let mut buf = Vec::new();
+        decode.read_file(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert!(decode.read_path().unwrap().is_none());
+
+        assert!(matches!(
+            VolumeReader::open(volume(2)),
+            Err(Error::VolumeOrder {
+                expected: 1,
+                found: 2,
+                ..
+            })
+        ));
+        fs::rename(volume(2), volume(0)).unwrap();
+        fs::rename(volume(3), volume(2)).unwrap();
+        assert!(matches!(
+            VolumeReader::open(volume(1)),
+            Err(Error::VolumeOrder {
+                expected: 2,
+                found: 3,
+                ..
+            })
+        ));
+        fs::remove_file(volume(2)).unwrap();
+        assert!(matches!(
+            VolumeReader::open(&base),
+            Err(Error::VolumeMissing(_))
+        ));
+
+        // A smaller archive replaces the volumes of the larger one
+        let stale = paths.len() as u32;
+        fs::write(volume(stale + 1), b"not a volume").unwrap();
+        let w = VolumeWriter::create(&base, 1000).unwrap();
+        let mut encode = Encode::new(w, "info", None, CompressParams::default()).unwrap();
+        encode.write_file("file", &mut &b"data"[..]).unwrap();
+        assert_eq!(encode.finish().unwrap().finish().unwrap(), vec![volume(1)]);
+        assert!((3..=stale).all(|n| !volume(n).exists()));
+        assert!(volume(stale + 1).exists());
+        let existing = VolumeWriter::existing(&base).unwrap();
+        assert!(existing.contains(&volume(1)) && existing.contains(&volume(stale + 1)));
+        fs::remove_file(volume(stale + 1)).unwrap();
+        assert_eq!(VolumeReader::open(&base).unwrap().volumes(), 1);
+    }
+
+    #[cfg(all(feature = "encryption", feature = "recovery"))]
+    #[test]
+    fn test_rekey_recovery() {
+        let pw = |key| Password::new(key, ScryptParams::default());
+        let mut encode =
+            Encode::new(Vec::new(), "", Some(pw("a")), CompressParams::default()).unwrap();
+        encode.write_file("file", &mut &b"data"[..]).unwrap();
+        let mut archive = Cursor::new(encode.finish().unwrap());
+        add_recovery(&mut archive, 10).unwrap();
+
+        // The recovery record is computed again, repairing does not bring back the old password
+        rekey(&mut archive, "a", Rekey::Change(pw("b"))).unwrap();
+        assert_eq!(check_recovery(&mut archive).unwrap().damaged, 0);
+        let rekeyed = archive.get_ref().clone();
+        repair(&mut archive).unwrap();
+        assert_eq!(archive.get_ref(), &rekeyed);
+        archive.set_position(0);
+        assert!(Decode::new(&mut archive, Some("a"), DEFAULT_BUF_SIZE).is_err());
+        archive.set_position(0);
+        assert!(Decode::new(&mut archive, Some("b"), DEFAULT_BUF_SIZE).is_ok());
+    }
+
+    #[cfg(feature = "recovery")]
+    #[test]
+    fn test_recovery() {
+        let data: Vec<u8> = (0..1_200_000).map(|_| rand::random()).collect();
+        let mut encode = Encode::new(Vec::new(), "info", None, CompressParams::default()).unwrap();
+        encode.write_file("file", &mut &data[..]).unwrap();
+        let mut archive = Cursor::new(encode.finish().unwrap());
+        let len = archive.get_ref().len() as u64;
+        assert_eq!(recovery_percent(&mut archive).unwrap(), None);
+        assert!(matches!(
+            check_recovery(&mut archive),
+            Err(Error::RecoveryMissing)
+        ));
+
+        let recovered = add_recovery(&mut archive, 10).unwrap();
+        assert_eq!(archive.get_ref().len() as u64, recovered);
+        assert_eq!(recovery_percent(&mut archive).unwrap(), Some(10));
+        // A smaller record replaces the previous one
+        let recovered = add_recovery(&mut archive, 5).unwrap();
+        archive.get_mut().truncate(recovered as usize);
+        assert_eq!(recovery_percent(&mut archive).unwrap(), Some(5));
+        let report = check_recovery(&mut archive).unwrap();
+        assert_eq!(report.damaged, 0);
+        assert_eq!(report.blocks, len.div_ceil(4096));
+
+        let original = archive.get_ref().clone();
+        for b in &mut archive.get_mut()[100_000..120_000] {
+            *b ^= 0xff;
+        }
+        let report = check_recovery(&mut archive).unwrap();
+        assert_eq!(report.damaged, 6);
+        assert_eq!(report.recoverable, 6);
+        assert!(report.is_recoverable());
+        repair(&mut archive).unwrap();
+        assert_eq!(archive.get_ref(), &original);
+
+        archive.set_position(0);
+        let mut decode = Decode::new(&mut archive, None, DEFAULT_BUF_SIZE).unwrap();
+        assert_eq!(decode.read_manifest().unwrap().len(), 1);
+        decode.read_path().unwrap().unwrap();
+        let mut buf = Vec::new();
+        decode.read_file(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // The copy of the trailer is used if the last one is damaged
+        let end = archive.get_ref().len();
+        for i in [end - 24, end - 1] {
+            archive.get_mut()[i] ^= 1;
+            assert_eq!(archive_len(&mut archive).unwrap(), len);
+            assert_eq!(recovery_percent(&mut archive).unwrap(), Some(5));
+            repair(&mut archive).unwrap();
+            assert_eq!(archive.get_ref(), &original);
+        }
+        // And the copy of the checksums if the first one is damaged
+        archive.get_mut()[len as usize] ^= 1;
+        repair(&mut archive).unwrap();
+        assert_eq!(archive.get_ref(), &original);
+
+        for b in &mut archive.get_mut()[..400_000] {
+            *b = 0;
+        }
+        let report = repair(&mut archive).unwrap();
+        assert!(!report.is_recoverable());
+        assert_eq!(report.unrecoverable, vec![(0, 98 * 4096)]);
+    }
+
+    #[test]
+    fn test_progress() {
+        // Entry, entries, bytes read and bytes written of the last update
+        type Update = (String, u64, u64, u64);
+        // Type, size and compressed size of the current entry
+        type EntryUpdate = (Option<FileType>, u64, u64);
+        struct Last(Arc<Mutex<Option<Update>>>, Arc<Mutex<Option<EntryUpdate>>>);
+
+        impl Progress for Last {
+            fn update(&mut self, status: &Status<'_>) {
+                *self.0.lock().unwrap() = Some((
+                    status.entry.to_string(),
+                    status.entries,
+                    status.bytes_read,
+                    status.bytes_written,
+                ));
+                *self.1.lock().unwrap() = Some((
+                    status.file_type,
+                    status.entry_size,
+                    status.entry_compressed_size,
+                ));
+            }
+        }
+
+        let last = Arc::new(Mutex::new(None));
+        let last_entry = Arc::new(Mutex::new(None));
+        let progress = || Box::new(Last(last.clone(), last_entry.clone()));
+        let mut encode = Encode::new(Vec::new(), "info", None, CompressParams::default()).unwrap();
+        encode.progress(progress());
+        let mut data: &[u8] = &[0; 100_000];
+        encode.write_file("file", &mut data).unwrap();
+        let (entry, entries, read, written) = last.lock().unwrap().take().unwrap();
+        assert_eq!((entry.as_str(), entries, read), ("file", 1, 100_000));
+        let (file_type, size, compressed_size) = last_entry.lock().unwrap().take().unwrap();
+        assert_eq!((file_type, size), (Some(FileType::File), 100_000));
+        let buf = encode.finish().unwrap();
+        assert!(written > 0 && written < buf.len() as u64);
+        assert_eq!(last_entry.lock().unwrap().take().unwrap().0, None);
+
+        let mut decode = Decode::new(Cursor::new(buf.clone()), None, DEFAULT_BUF_SIZE).unwrap();
+        decode.progress(progress(), Some(buf.len() as u64));
+        for entry in decode.entries() {
+            io::copy(&mut entry.unwrap(), &mut io::sink()).unwrap();
+        }
+        let (entry, entries, read, written) = last.lock().unwrap().take().unwrap();
+        assert_eq!(
+            (entry.as_str(), entries, read, written),
+            ("file", 1, buf.len() as u64, 100_000)
+        );
+        assert_eq!(last_entry.lock().unwrap().take().unwrap().0, None);
+        let summary = decode.summary().unwrap();
+        assert_eq!(summary.compressed_size, compressed_size);
+    }
+
+    #[cfg(all(feature = "async", feature = "encryption", feature = "signing"))]
+    #[tokio::test]
+    async fn test_async_archive() {
+        use futures_core::Stream;
+        use std::future::poll_fn;
+        use std::pin::Pin;
+
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let public = keypair.public;
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut encode =
+            AsyncEncode::new_private(Vec::new(), "info", pw, CompressParams::default())
+                .await
+                .unwrap();
+        encode.sign(keypair);
+        encode.write_directory("directory").await.unwrap();
+        let data = vec![7; 100_000];
+        encode.write_file("file", &mut &data[..]).await.unwrap();
+        let buf = encode.finish().await.unwrap();
+
+        // The same archive as the blocking API
+        let mut decode = Decode::new(Cursor::new(buf.clone()), Some("123456"), 1).unwrap();
+        decode.verify(public);
+        assert_eq!(decode.entries().count(), 2);
+
+        let handle = tokio::spawn(async move {
+            let mut decode = AsyncDecode::new(Cursor::new(buf), Some("123456"), DEFAULT_BUF_SIZE)
+                .await
+                .unwrap();
+            let other = Keypair::generate(&mut rand::rngs::OsRng).public;
+            assert!(matches!(
+                decode.verify_now(other).await,
+                Err(Error::InvalidSignature)
+            ));
+            decode.verify_now(public).await.unwrap();
+            assert_eq!(decode.info(), "info");
+            let mut stream = decode.into_stream();
+            let mut events = Vec::new();
+            while let Some(event) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+                events.push(event.unwrap());
+            }
+            events
+        });
+        let mut paths = Vec::new();
+        let mut content = Vec::new();
+        for event in handle.await.unwrap() {
+            match event {
+                EntryEvent::Entry(entry) => paths.push(entry.path().to_string()),
+                EntryEvent::Data(buf) => content.extend_from_slice(&buf),
+            }
+        }
+        assert_eq!(paths, ["directory", "file"]);
+        assert_eq!(content, data);
+    }
+
+    #[cfg(all(feature = "async", feature = "encryption"))]
+    #[tokio::test]
+    async fn test_async_resync() {
+        // Entries and bytes read of the last update
+        struct Last(Arc<Mutex<(u64, u64)>>);
+        impl Progress for Last {
+            fn update(&mut self, status: &Status<'_>) {
+                *self.0.lock().unwrap() = (status.entries, status.bytes_read);
+            }
+        }
+
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut encode =
+            Encode::new(Vec::new(), "info", Some(pw), CompressParams::default()).unwrap();
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        for p in &["a", "b", "c"] {
+            encode.write_file(p, &mut &data[..]).unwrap();
+        }
+        let mut buf = encode.finish().unwrap();
+        let syncs: Vec<usize> = (0..buf.len())
+            .filter(|&i| buf[i..].starts_with(&SYNC))
+            .collect();
+        // Damage the data of `b`
+        buf[syncs[2] - 100] ^= 1;
+
+        let last = Arc::new(Mutex::new((0, 0)));
+        let mut decode = AsyncDecode::new(Cursor::new(&buf), Some("123456"), DEFAULT_BUF_SIZE)
+            .await
+            .unwrap();
+        decode.progress(Box::new(Last(last.clone())), Some(buf.len() as u64));
+        let mut read = Vec::new();
+        let mut failed = 0;
+        loop {
+            let entry = match decode.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(_) => unreachable!(),
+            };
+            let mut content = Vec::new();
+            match decode.read_file(&mut content).await {
+                Ok(_) => read.push((entry.path().to_string(), content == data)),
+                Err(err) => {
+                    assert!(matches!(err.root(), Error::DecryptionFailed));
+                    failed += 1;
+                    assert!(decode.resync().await.unwrap().is_some());
+                }
+            }
+        }
+        assert_eq!(failed, 1);
+        assert_eq!(read, [("a".to_string(), true), ("c".to_string(), true)]);
+        assert_eq!(*last.lock().unwrap(), (3, buf.len() as u64));
+        assert_eq!(decode.summary().unwrap().entries, 3);
+    }
+
+    #[cfg(all(feature = "encryption", feature = "signing"))]
+    #[test]
+    fn test_append() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let public = keypair.public;
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut f = archive("append");
+        f.set_len(0).unwrap();
+        let mut encode =
+            Encode::new_private(&mut f, "info", pw, CompressParams::default()).unwrap();
+        encode.write_directory("directory").unwrap();
+        encode.finish().unwrap();
+
+        assert!(matches!(
+            Encode::open_append(archive("append"), Some("654321"), CompressParams::default()),
+            Err(Error::InvalidPassword)
+        ));
+        let params = CompressParams::default();
+        let mut encode = Encode::open_append(archive("append"), Some("123456"), params).unwrap();
+        assert!(!encode.was_signed());
+        encode.sign(keypair);
+        let mut data: &[u8] = b"hello";
+        encode.write_file("file", &mut data).unwrap();
+        encode.finish().unwrap();
+
+        let mut decode = Decode::new(archive("append"), Some("123456"), DEFAULT_BUF_SIZE).unwrap();
+        decode.verify_now(public).unwrap();
+        assert_eq!(decode.info(), "info");
+        let paths: Vec<String> = decode
+            .entries()
+            .map(|entry| entry.unwrap().path().to_string())
+            .collect();
+        assert_eq!(paths, ["directory", "file"]);
+        let summary = decode.summary().unwrap();
+        assert_eq!((summary.entries, summary.size), (2, 5));
+
+        let encode = Encode::open_append(archive("append"), Some("123456"), params).unwrap();
+        assert!(encode.was_signed());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_rewrite() {
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut encode =
+            Encode::new_private(Vec::new(), "info", pw, CompressParams::default()).unwrap();
+        encode.write_directory("directory").unwrap();
+        encode
+            .write_file("directory/secret", &mut &b"secret"[..])
+            .unwrap();
+        encode.write_file("file", &mut &b"data"[..]).unwrap();
+        let buf = encode.finish().unwrap();
+
+        let mut decode = Decode::new(Cursor::new(buf), Some("123456"), 1).unwrap();
+        let mut encode = decode
+            .rewrite(Vec::new(), CompressParams::default(), |path| {
+                path != "directory/secret"
+            })
+            .unwrap();
+        encode.write_file("new", &mut &b"new"[..]).unwrap();
+        let buf = encode.finish().unwrap();
+
+        let mut decode = Decode::new(Cursor::new(buf), Some("123456"), 1).unwrap();
+        assert_eq!(decode.info(), "info");
+        let mut files = Vec::new();
+        while let Some((kind, path)) = decode.read_path().unwrap() {
+            let mut data = Vec::new();
+            if kind == FileType::File {
+                decode.read_file(&mut data).unwrap();
+            }
+            files.push((path, data));
+        }
+        assert_eq!(
+            files,
+            [
+                ("directory".to_string(), b"".to_vec()),
+                ("file".to_string(), b"data".to_vec()),
+                ("new".to_string(), b"new".to_vec()),
+            ]
+        );
+        let summary = decode.summary().unwrap();
+        assert_eq!((summary.entries, summary.size), (3, 7));
+        let manifest = decode.manifest().unwrap();
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(manifest.get("file").unwrap().size, 4);
+    }
+
+    #[cfg(all(feature = "encryption", feature = "signing"))]
+    #[test]
+    fn test_signature() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let public = keypair.public;
+        let other = Keypair::generate(&mut rand::rngs::OsRng);
+        let pw = Password::new("123456", ScryptParams::default());
+
+        let mut encode =
+            Encode::new(Vec::new(), "info", Some(pw), CompressParams::default()).unwrap();
+        encode.sign(keypair);
+        encode.write_directory("directory").unwrap();
+        let mut buf = encode.finish().unwrap();
+
+        let decode = |buf: &[u8]| Decode::new(Cursor::new(buf.to_vec()), Some("123456"), 1);
+
+        let mut decode1 = decode(&buf).unwrap();
+        decode1.verify_now(public).unwrap();
+        assert!(decode1.read_path().unwrap().is_some());
+        assert!(decode1.read_path().unwrap().is_none());
+
+        let mut decode2 = decode(&buf).unwrap();
+        assert!(matches!(
+            decode2.verify_now(other.public),
+            Err(Error::InvalidSignature)
+        ));
+        decode2.verify(other.public);
+        assert!(decode2.read_path().unwrap().is_some());
+        assert!(matches!(decode2.read_path(), Err(Error::InvalidSignature)));
+
+        // Changing the passwords keeps the signature valid
+        let mut f = Cursor::new(buf.clone());
+        rekey(
+            &mut f,
+            "123456",
+            Rekey::Add(Password::new("a", ScryptParams::default())),
+        )
+        .unwrap();
+        decode(f.get_ref()).unwrap().verify_now(public).unwrap();
+
+        let len = buf.len();
+        buf[len - LOCATOR_SIZE - SIGNATURE_SIZE - 1] ^= 1;
+        assert!(matches!(
+            decode(&buf).unwrap().verify_now(public),
+            Err(Error::InvalidSignature)
+        ));
+        buf.drain(len - LOCATOR_SIZE - SIGNATURE_SIZE..len - LOCATOR_SIZE);
+        assert!(matches!(
+            decode(&buf).unwrap().verify_now(public),
+            Err(Error::SignatureMissing)
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_padding() {
+        assert_eq!(padme(1), 1);
+        assert_eq!(padme(9), 10);
+        assert_eq!(padme(100), 104);
+        assert_eq!(padme(1000), 1024);
+        assert_eq!(unpad(pad(b"data").to_vec()).unwrap(), b"data");
+        assert_eq!(unpad(pad(b"").to_vec()).unwrap(), b"");
+        assert!(unpad(vec![1, 0, 0]).is_err());
+    }
+
+    #[cfg(all(feature = "encryption", feature = "signing"))]
+    #[test]
+    fn test_private_archive() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let public = keypair.public;
+        let pw = Password::new("123456", ScryptParams::default());
+        let mut encode =
+            Encode::new_private(Vec::new(), "secret info", pw, CompressParams::default()).unwrap();
+        encode.sign(keypair);
+        encode.write_directory("directory").unwrap();
+        encode.write_file("file", &mut &b"data"[..]).unwrap();
+        let buf = encode.finish().unwrap();
+
+        let find = |needle: &[u8]| buf.windows(needle.len()).any(|w| w == needle);
+        assert!(!find(b"secret info"));
+
+        let mut decode = Decode::new(Cursor::new(buf), Some("123456"), 1).unwrap();
+        decode.verify(public);
+        assert_eq!(decode.info(), "secret info");
+        assert_eq!(
+            decode.read_path().unwrap().unwrap(),
+            (FileType::Directory, "directory".to_string())
+        );
+        assert_eq!(
+            decode.read_path().unwrap().unwrap(),
+            (FileType::File, "file".to_string())
+        );
+        let mut data = Vec::new();
+        decode.read_file(&mut data).unwrap();
+        assert_eq!(data, b"data");
+        assert!(decode.read_path().unwrap().is_none());
+        assert!(decode.read_path().unwrap().is_none());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_read_summary() {
+        let password = || Password::new("123456", ScryptParams::default());
+        for method in 0..3 {
+            let params = CompressParams::default();
+            let mut encode = match method {
+                0 => Encode::new(Vec::new(), "", None, params),
+                1 => Encode::new(Vec::new(), "", Some(password()), params),
+                _ => Encode::new_private(Vec::new(), "", password(), params),
+            }
+            .unwrap();
+            encode.write_directory("directory").unwrap();
+            encode.write_file("file", &mut &[1; 5000][..]).unwrap();
+            let buf = encode.finish().unwrap();
+
+            let password = Some("123456").filter(|_| method > 0);
+            let mut decode = Decode::new(Cursor::new(&buf), password, 1).unwrap();
+            let summary = decode.read_summary().unwrap();
+            assert_eq!((summary.entries, summary.size), (2, 5000));
+            // The position is kept
+            assert_eq!(
+                decode.read_path().unwrap().unwrap(),
+                (FileType::Directory, "directory".to_string())
+            );
+            while decode.read_path().unwrap().is_some() {}
+            assert_eq!(decode.summary(), Some(&summary));
+        }
+    }
+
+    #[test]
+    fn test_entries() {
+        let metadata = Metadata {
+            mtime: 1_600_000_000,
+            mode: 0o644,
+        };
+        let mut encode = Encode::new(Vec::new(), "", None, CompressParams::default()).unwrap();
+        encode
+            .write_file_with_metadata("a", &metadata, &mut &b"aaa"[..])
+            .unwrap();
+        encode
+            .write_directory_with_metadata("b", &metadata)
+            .unwrap();
+        encode
+            .write_file_with_metadata("c", &metadata, &mut &b"ccc"[..])
+            .unwrap();
+        let buf = encode.finish().unwrap();
+
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        let mut entries = decode.entries();
+        // Unread data is skipped
+        let a = entries.next().unwrap().unwrap();
+        assert_eq!((a.file_type(), a.path()), (FileType::File, "a"));
+        assert_eq!(a.metadata(), &metadata);
+        let b = entries.next().unwrap().unwrap();
+        assert_eq!((b.file_type(), b.path()), (FileType::Directory, "b"));
+        let mut c = entries.next().unwrap().unwrap();
+        let mut data = String::new();
+        c.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "ccc");
+        assert!(entries.next().is_none());
+
+        // Reading the path twice does not desync the stream
+        let mut decode = Decode::new(&buf[..], None, DEFAULT_BUF_SIZE).unwrap();
+        decode.read_path().unwrap().unwrap();
+        decode.read_path().unwrap().unwrap();
+        assert_eq!(
+            decode.read_path().unwrap().unwrap(),
+            (FileType::File, "c".to_string())
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_file_writer() {
+        let data = (0..100_000u32).map(|n| n.to_string()).collect::<String>();
+        let pw = Password::new("123456", ScryptParams::default());
+        let params = *CompressParams::default().buf_size(1024);
+        let mut encode = Encode::new(Vec::new(), "", Some(pw), params).unwrap();
+        let mut w = encode.start_file("file", &Metadata::default()).unwrap();
+        for line in data.as_bytes().chunks(999) {
+            w.write_all(line).unwrap();
+        }
+        assert!(w.finish().unwrap() > 0);
+        // Dropping the writer also ends the file
+        let mut w = encode.start_file("empty", &Metadata::default()).unwrap();
+        w.write_all(b"").unwrap();
+        drop(w);
+        let buf = encode.finish().unwrap();
+
+        let mut decode = Decode::new(&buf[..], Some("123456"), DEFAULT_BUF_SIZE).unwrap();
+        let mut entries = decode.entries();
+        let mut s = String::new();
+        entries
+            .next()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(s, data);
+        let mut s = String::new();
+        entries
+            .next()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(s, "");
+        assert!(entries.next().is_none());
+    }
 }
@@ -1,31 +1,66 @@
 mod app;
-mod lib;
+mod convert;
+mod json;
+mod progress;
 
-use app::Options;
-use lib::{CompressParams, Decode, Encode, FileType, Password, ScryptParams, DEFAULT_BUF_SIZE};
-use std::fs::{self, File};
-use std::io::{BufReader, BufWriter};
+use app::{Command, Options, RekeyMode};
+use mei::{
+    add_recovery, check_recovery, read_header, recovery_percent, rekey, rekey_rewrite, repair,
+    Change, CompressParams, Decode, Encode, EncryptMethod, FileType, Keypair, Manifest, Password,
+    Rekey, ScryptParams, Summary, UnpackOptions, VolumeReader, VolumeWriter, WalkOptions,
+    DEFAULT_BUF_SIZE,
+};
+use progress::{type_name, Report, Reporter};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use walkdir::{DirEntry, WalkDir};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 
 #[macro_export]
 macro_rules! exit {
     ($($arg:tt)*) => {
-        {
-            eprint!("Error: ");
-            eprintln!($($arg)*);
-            exit(1)
-        }
+        $crate::exit_error(format!($($arg)*))
     };
 }
 
+// Set by `--format json`, errors are then written to stderr as JSON too
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// Set to 2 by `diff`, which exits with 1 if there are differences, like `diff`
+static ERROR_CODE: AtomicI32 = AtomicI32::new(1);
+
+fn exit_error(msg: String) -> ! {
+    if JSON.load(Ordering::Relaxed) {
+        let object = json::Object::new()
+            .field("event", "error")
+            .field("status", "failed")
+            .field("error", msg);
+        eprintln!("{}", object);
+    } else {
+        eprintln!("Error: {}", msg);
+    }
+    exit(ERROR_CODE.load(Ordering::Relaxed))
+}
+
 fn main() {
     let options = app::options();
-    if options.compress {
-        compress_archive(options);
-    } else {
-        decompress_archive(options);
+    match options.command {
+        Command::Compress => compress_archive(options),
+        Command::Decompress => decompress_archive(options),
+        Command::Append => append_archive(options),
+        Command::Rewrite => rewrite_archive(options),
+        Command::Rekey(ref mode) => rekey_archive(&options, mode),
+        Command::Keygen => keygen(options),
+        Command::List => list_archive(&options),
+        Command::Test => test_archive(options),
+        Command::Info => info_archive(&options),
+        Command::Diff { ref target, stream } => diff_archive(&options, target, stream),
+        Command::Repair { test } => repair_archive(&options, test),
+        Command::Convert => convert::convert_archive(options),
+        Command::Cat { ref path } => cat_file(&options, path),
     }
 }
 
@@ -36,100 +71,791 @@ fn compress_archive(options: Options) {
     }
 
     // Check output file
-    if !options.force && Path::new(&options.output).exists() {
-        exit!("'{}' already exist", options.output);
+    let output = match options.volume_size {
+        Some(_) => format!("{}.001", options.output),
+        None => options.output.clone(),
+    };
+    if !options.force && Path::new(&output).exists() {
+        exit!("'{}' already exist", output);
     }
 
-    // Temp output file
-    let temp = temp_path();
-    let writer = buf_writer(&temp, true);
-    let filter = Path::new(&temp).canonicalize().throw();
+    // The manifest of the archive that an incremental archive is based on
+    let key = options.password.as_ref().map(|s| s.as_str());
+    let reference = options.incremental.as_ref().map(|p| {
+        let mut decode = Decode::new(buf_reader(p), key, DEFAULT_BUF_SIZE).throw();
+        decode.read_manifest().throw()
+    });
 
-    // Input files
-    let files = files(&options.input, filter);
-    let prefix = Path::new(&options.input)
-        .parent()
-        .unwrap_or_else(|| Path::new(&options.input));
+    match options.volume_size {
+        // Volumes are written in place
+        Some(size) => {
+            let writer = BufWriter::new(VolumeWriter::create(&options.output, size).throw());
+            // Also skip the volumes of an archive that is replaced
+            let volumes = VolumeWriter::existing(&options.output).throw();
+            let writer = write_archive(options, reference, writer, &volumes);
+            let writer = writer.into_inner().map_err(|err| err.into_error()).throw();
+            writer.finish().throw();
+        }
+        None => {
+            // Temp output file
+            let temp = temp_path();
+            let writer = buf_writer(&temp, true);
+            let recovery = options.recovery;
+            let writer = write_archive(options, reference, writer, &[PathBuf::from(&temp)]);
+            if let Some(percent) = recovery {
+                let mut file = writer.into_inner().map_err(|err| err.into_error()).throw();
+                add_recovery(&mut file, percent).throw();
+            }
+            if Path::new(&output).is_dir() {
+                fs::remove_dir_all(&output).throw();
+            }
+            fs::rename(temp, output).throw();
+        }
+    }
+}
 
+// A new archive with the password, info, quality and signing key of the options
+fn encoder<W: Write>(options: &mut Options, writer: W) -> Result<Encode<W>, mei::Error> {
     let password = options
         .password
-        .as_deref()
+        .as_ref()
         .map(|key| Password::new(key, ScryptParams::default()));
     let params = *CompressParams::default().quality(options.quality);
-    let mut encode = Encode::new(writer, &options.info, password, params).throw();
-
-    for entry in files {
-        let path = entry.path().strip_prefix(&prefix).throw().to_path_buf();
-        let p = path.to_str().unwrap_or_default();
-        if entry.path().is_dir() {
-            println!("Adding: {}", p);
-            encode.write_directory(p).throw();
-        } else {
-            print!("Adding: {}", p);
-            let mut f = File::open(entry.path()).throw();
-            let bytes = encode.write_file(p, &mut f).throw();
-            let len = entry.metadata().throw().len() as f32;
-            println!(" [{:.1}%]", (bytes as f32) / len * 100.);
+    let mut encode = match password {
+        Some(pw) if options.private => Encode::new_private(writer, &options.info, pw, params),
+        password => Encode::new(writer, &options.info, password, params),
+    }?;
+    if let Some(keypair) = options.sign.take() {
+        encode.sign(keypair);
+    }
+    Ok(encode)
+}
+
+// Add the input path, `exclude` is the file being written
+fn write_archive<W: Write>(
+    mut options: Options,
+    reference: Option<Manifest>,
+    writer: W,
+    exclude: &[PathBuf],
+) -> W {
+    let mut encode = encoder(&mut options, writer).throw();
+    encode.progress(Box::new(Reporter::new(options.report, "Adding")));
+
+    let mut walk = WalkOptions::new();
+    for path in exclude {
+        walk.exclude(path);
+    }
+    if let Some(manifest) = reference {
+        walk.incremental(manifest).checksum(options.checksum);
+    }
+    let input = Path::new(&options.input);
+    encode
+        .append_dir_all(input, &archive_name(input), &walk)
+        .throw();
+    encode.finish().throw()
+}
+
+// The entries of a path are stored under its name
+fn archive_name(path: &Path) -> String {
+    path.canonicalize()
+        .throw()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn append_archive(options: Options) {
+    for path in &options.append {
+        if !Path::new(path).exists() {
+            exit!("'{}' does not exist", path);
+        }
+    }
+    let archive = Path::new(&options.input);
+    let mut file = File::open(archive)
+        .unwrap_or_else(|err| exit!("Failed to open '{}': {}", options.input, err));
+    let recovery = recovery_percent(&mut file).throw();
+
+    // A copy next to the archive is appended to, so that it can be renamed over it
+    let temp = archive.with_file_name(temp_path());
+    let fail = |err: mei::Error| -> ! {
+        let _ = fs::remove_file(&temp);
+        exit!("{}", err)
+    };
+    fs::copy(archive, &temp).unwrap_or_else(|err| fail(mei::Error::IO(err)));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&temp)
+        .unwrap_or_else(|err| fail(mei::Error::IO(err)));
+    let password = options.password.as_ref().map(|s| s.as_str());
+    let params = *CompressParams::default().quality(options.quality);
+    let mut encode = Encode::open_append(file, password, params).unwrap_or_else(|err| fail(err));
+    match options.sign {
+        Some(keypair) => encode.sign(keypair),
+        None if encode.was_signed() && !options.force => {
+            let _ = fs::remove_file(&temp);
+            exit!(
+                "'{}' is signed, use '--sign' to sign it again or '--force' to drop the signature",
+                options.input
+            );
         }
+        None => {}
     }
+    encode.progress(Box::new(Reporter::new(options.report, "Adding")));
 
-    if Path::new(&options.output).is_dir() {
-        fs::remove_dir_all(&options.output).throw();
+    for path in &options.append {
+        let path = Path::new(path);
+        encode
+            .append_dir_all(
+                path,
+                &archive_name(path),
+                WalkOptions::new().exclude(archive).exclude(&temp),
+            )
+            .unwrap_or_else(|err| fail(err));
+    }
+    let mut f = encode.finish().unwrap_or_else(|err| fail(err));
+    // Appending drops the recovery record, it is computed again
+    if let Some(percent) = recovery {
+        add_recovery(&mut f, percent).unwrap_or_else(|err| fail(err));
+    }
+    f.sync_all().throw();
+    fs::rename(&temp, archive).throw();
+}
+
+// Delete and update entries, the archive is replaced once the new one is complete
+fn rewrite_archive(options: Options) {
+    for path in &options.update {
+        if !Path::new(path).exists() {
+            exit!("'{}' does not exist", path);
+        }
+    }
+    let archive = Path::new(&options.input);
+    let mut reader = buf_reader(&options.input);
+    if reader.get_ref().volumes() > 1 {
+        exit!(
+            "'{}' is split into volumes, it cannot be rewritten",
+            options.input
+        );
+    }
+    let recovery = recovery_percent(&mut reader).throw();
+    reader.rewind().throw();
+    let password = options.password.as_ref().map(|s| s.as_str());
+    let mut decode = Decode::new(reader, password, DEFAULT_BUF_SIZE).throw();
+
+    // Written next to the archive, so that it can be renamed over it
+    let temp = archive.with_file_name(temp_path());
+    let fail = |err: mei::Error| -> ! {
+        let _ = fs::remove_file(&temp);
+        exit!("{}", err)
+    };
+    let writer = buf_writer(&temp, true);
+    let names: Vec<String> = options
+        .update
+        .iter()
+        .map(|path| archive_name(Path::new(path)))
+        .collect();
+    let params = *CompressParams::default().quality(options.quality);
+    let mut encode = decode
+        .rewrite(writer, params, |path| {
+            if options.delete.iter().any(|pattern| glob(pattern, path)) {
+                match options.report {
+                    Report::Quiet => {}
+                    Report::Json => {
+                        let object = json::Object::new()
+                            .field("event", "delete")
+                            .field("path", path);
+                        println!("{}", object);
+                    }
+                    _ => println!("Deleting: {}", path),
+                }
+                return false;
+            }
+            !names.iter().any(|name| is_under(path, name))
+        })
+        .unwrap_or_else(|err| fail(err));
+    match options.sign {
+        Some(keypair) => encode.sign(keypair),
+        None if encode.was_signed() && !options.force => {
+            let _ = fs::remove_file(&temp);
+            exit!(
+                "'{}' is signed, use '--sign' to sign it again or '--force' to drop the signature",
+                options.input
+            );
+        }
+        None => {}
+    }
+    encode.progress(Box::new(Reporter::new(options.report, "Updating")));
+    for (path, name) in options.update.iter().zip(&names) {
+        encode
+            .append_dir_all(
+                path,
+                name,
+                WalkOptions::new().exclude(archive).exclude(&temp),
+            )
+            .unwrap_or_else(|err| fail(err));
+    }
+    let w = encode.finish().unwrap_or_else(|err| fail(err));
+    let mut f = w
+        .into_inner()
+        .unwrap_or_else(|err| fail(mei::Error::IO(err.into_error())));
+    if let Some(percent) = recovery {
+        add_recovery(&mut f, percent).unwrap_or_else(|err| fail(err));
+    }
+    let permissions = fs::metadata(archive).throw().permissions();
+    f.set_permissions(permissions).throw();
+    f.sync_all().throw();
+    fs::rename(&temp, archive).throw();
+}
+
+// The entry is the directory or file at `name`, or is inside of it
+fn is_under(path: &str, name: &str) -> bool {
+    path == name
+        || (path.starts_with(name) && path[name.len()..].starts_with(std::path::MAIN_SEPARATOR))
+}
+
+// `*` and `?` do not match `/`, `**` does. A pattern without `/` is matched against
+// the name of an entry. The entries in a matching directory match too
+fn glob(pattern: &str, path: &str) -> bool {
+    let components: Vec<&str> = path.split(std::path::MAIN_SEPARATOR).collect();
+    (1..=components.len()).any(|n| {
+        if pattern.contains('/') {
+            wildcard(pattern.as_bytes(), components[..n].join("/").as_bytes())
+        } else {
+            wildcard(pattern.as_bytes(), components[n - 1].as_bytes())
+        }
+    })
+}
+
+fn wildcard(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern {
+        [] => s.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=s.len()).any(|i| wildcard(rest, &s[i..])),
+        [b'*', rest @ ..] => (0..=s.len())
+            .take_while(|&i| i == 0 || s[i - 1] != b'/')
+            .any(|i| wildcard(rest, &s[i..])),
+        [b'?', rest @ ..] => !s.is_empty() && s[0] != b'/' && wildcard(rest, &s[1..]),
+        [c, rest @ ..] => s.first() == Some(c) && wildcard(rest, &s[1..]),
     }
-    fs::rename(temp, options.output).throw();
 }
 
 fn decompress_archive(options: Options) {
     let reader = buf_reader(&options.input);
-    let root = {
-        let path = PathBuf::from(&options.output);
-        if !path.exists() {
-            fs::create_dir_all(&path)
-                .unwrap_or_else(|err| exit!("Failed to create '{}': {:#?}", options.output, err));
-        } else {
-            if !path.is_dir() {
-                exit!("'{}' is not a directory", options.output)
+    let reader_size = reader.get_ref().size();
+    if Path::new(&options.output).is_file() {
+        exit!("'{}' is not a directory", options.output)
+    }
+    let mut decode = Decode::new(
+        reader,
+        options.password.as_ref().map(|s| s.as_str()),
+        DEFAULT_BUF_SIZE,
+    )
+    .throw();
+    if let Some(key) = options.verify {
+        decode.verify_now(key).throw();
+    }
+    if options.report != Report::Json {
+        println!("Info: {}", decode.info());
+    }
+    let size = Some(reader_size);
+    decode.progress(Box::new(Reporter::new(options.report, "Output")), size);
+
+    let failed = decode
+        .unpack(
+            &options.output,
+            UnpackOptions::new()
+                .overwrite(options.force)
+                .keep_going(options.keep_going),
+        )
+        .throw();
+    // The JSON summary is printed first
+    drop(decode);
+    // Some entries were extracted, unlike a failure that stops the extraction
+    if !failed.is_empty() {
+        if options.report != Report::Json {
+            eprintln!("Failed entries: {}", failed.len());
+            for err in &failed {
+                eprintln!("  {}", err);
             }
         }
-        path
+        exit(2);
+    }
+}
+
+// Sizes come from the manifest, which only knows the last entry of a path.
+// Archives older than the manifest are decompressed to find the sizes
+fn list_archive(options: &Options) {
+    let password = options.password.as_ref().map(|s| s.as_str());
+    let mut decode = Decode::new(buf_reader(&options.input), password, DEFAULT_BUF_SIZE).throw();
+    let mut entries = Vec::new();
+    match decode.read_manifest() {
+        Ok(manifest) => {
+            while let Some((file_type, p)) = decode.read_path().throw() {
+                entries.push((file_type, p, None));
+            }
+            let mut seen = HashSet::new();
+            for (file_type, p, size) in entries.iter_mut().rev() {
+                if seen.insert(p.clone()) && *file_type == FileType::File {
+                    *size = manifest.get(p).map(|m| m.size);
+                }
+            }
+        }
+        Err(mei::Error::InvalidVersion) => {
+            for entry in decode.entries() {
+                let mut entry = entry.throw();
+                let size = match entry.file_type() {
+                    FileType::File => Some(io::copy(&mut entry, &mut io::sink()).throw()),
+                    _ => None,
+                };
+                entries.push((entry.file_type(), entry.path().to_string(), size));
+            }
+        }
+        Err(err) => Err(err).throw(),
+    }
+    let stdout = io::stdout();
+    let mut w = BufWriter::new(stdout.lock());
+    let mut write = || -> io::Result<()> {
+        for (file_type, p, size) in &entries {
+            if options.json {
+                let object = json::Object::new()
+                    .field("event", "entry")
+                    .field("path", p.as_str())
+                    .field("type", type_name(*file_type))
+                    .field("size", *size);
+                writeln!(w, "{}", object)?;
+            } else {
+                let size = size.map_or_else(|| "-".to_string(), |size| size.to_string());
+                writeln!(w, "{:<9} {:>12}  {}", type_name(*file_type), size, p)?;
+            }
+        }
+        w.flush()
     };
-    let mut decode = Decode::new(reader, options.password.as_deref(), DEFAULT_BUF_SIZE).throw();
-    println!("Info: {}", decode.info());
-
-    loop {
-        let opt = decode.read_path().throw();
-        let (file_type, file_path) = match opt {
-            Some(opt) => opt,
-            None => break,
-        };
-        let path = root.join(file_path);
-        println!("Output: {}", path.display());
+    match write() {
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => {}
+        rst => rst.throw(),
+    }
+}
 
-        match file_type {
-            FileType::Directory => {
-                if let Err(err) = fs::create_dir_all(&path) {
-                    exit!("Failed to create '{}': {:#?}", path.display(), err);
+// Nothing is written, the files are only compared with the checksums of the manifest
+fn test_archive(options: Options) {
+    let reader = buf_reader(&options.input);
+    let size = Some(reader.get_ref().size());
+    let password = options.password.as_ref().map(|s| s.as_str());
+    let mut decode = Decode::new(reader, password, DEFAULT_BUF_SIZE).throw();
+    if let Some(key) = options.verify {
+        decode.verify_now(key).throw();
+    }
+    decode.progress(Box::new(Reporter::new(options.report, "Testing")), size);
+
+    let mut hashes = HashMap::new();
+    for entry in decode.entries() {
+        let mut entry = entry.throw();
+        let p = entry.path().to_string();
+        if entry.file_type() == FileType::File {
+            let mut hash = Sha256::new();
+            io::copy(&mut entry, &mut hash).throw();
+            hashes.insert(p, hash.finalize());
+        } else {
+            hashes.remove(&p);
+        }
+    }
+    // Archives older than the manifest have no checksums, decoding them is the test
+    if let Some(manifest) = decode.manifest() {
+        for (p, hash) in &hashes {
+            match manifest.get(p) {
+                Some(m) if m.hash[..] == hash[..] => {}
+                _ => exit!("'{}' does not match its checksum", p),
+            }
+        }
+    }
+    // The progress bar or the JSON summary is finished first
+    drop(decode);
+    if options.report != Report::Json {
+        println!("No errors: {}", options.input);
+    }
+}
+
+// The header is read without a password, the entries need one if the archive is encrypted.
+// The counts come from the summary and the manifest, only older archives are read to the end
+fn info_archive(options: &Options) {
+    let header = read_header(buf_reader(&options.input)).throw();
+    let reader = buf_reader(&options.input);
+    let archive_size = reader.get_ref().size();
+    let password = options.password.as_ref().map(|s| s.as_str());
+    let stats = if header.method == EncryptMethod::None || password.is_some() {
+        let mut decode = Decode::new(reader, password, DEFAULT_BUF_SIZE).throw();
+        let mut stats = Stats {
+            info: decode.info().to_string(),
+            ..Stats::default()
+        };
+        match decode.read_summary() {
+            Ok(summary) => {
+                for entry in decode.read_manifest().throw().entries() {
+                    match entry.file_type {
+                        FileType::Directory => stats.directories += 1,
+                        _ => stats.files += 1,
+                    }
+                }
+                stats.entries = summary.entries;
+                stats.summary = Some(summary);
+            }
+            Err(mei::Error::InvalidVersion) => {
+                let mut deleted = 0;
+                while let Some((file_type, _)) = decode.read_path().throw() {
+                    match file_type {
+                        FileType::File => stats.files += 1,
+                        FileType::Directory => stats.directories += 1,
+                        FileType::Deleted => deleted += 1,
+                    }
                 }
+                stats.entries = stats.files + stats.directories + deleted;
+                stats.deleted = Some(deleted);
+                stats.summary = decode.summary().copied();
+            }
+            Err(err) => Err(err).throw(),
+        }
+        Some(stats)
+    } else {
+        None
+    };
+    // Only a private archive hides its info
+    let info = match &stats {
+        Some(stats) => Some(stats.info.clone()),
+        None if header.method == EncryptMethod::Aes256GcmPrivate => None,
+        None => Some(header.info.clone()),
+    };
+    let summary = stats.as_ref().and_then(|stats| stats.summary);
+    let ratio = summary
+        .filter(|summary| summary.size > 0)
+        .map(|summary| summary.compressed_size as f64 / summary.size as f64);
+    let encryption = match header.method {
+        EncryptMethod::None => "none",
+        EncryptMethod::Aes256Gcm => "aes-256-gcm",
+        EncryptMethod::Aes256GcmPrivate => "aes-256-gcm-private",
+    };
+
+    if options.json {
+        let key_slots = header
+            .key_slots
+            .iter()
+            .map(|params| {
+                json::Object::new()
+                    .field("kdf", "scrypt")
+                    .field("log_n", params.n as u32)
+                    .field("r", params.r)
+                    .field("p", params.p)
+            })
+            .collect::<Vec<_>>();
+        let object = json::Object::new()
+            .field("path", options.input.as_str())
+            .field("version", header.version as u32)
+            .field("info", info)
+            .field("encryption", encryption)
+            .field("key_slots", key_slots)
+            .field("codec", CODEC)
+            .field("entries", stats.as_ref().map(|stats| stats.entries))
+            .field("files", stats.as_ref().map(|stats| stats.files))
+            .field("directories", stats.as_ref().map(|stats| stats.directories))
+            .field("deleted", stats.as_ref().and_then(|stats| stats.deleted))
+            .field("size", summary.map(|summary| summary.size))
+            .field(
+                "compressed_size",
+                summary.map(|summary| summary.compressed_size),
+            )
+            .field("ratio", ratio)
+            .field("archive_size", archive_size);
+        println!("{}", object);
+        return;
+    }
+
+    println!("Version: {}", header.version);
+    match info {
+        Some(info) => println!("Info: {}", info),
+        None => println!("Info: encrypted"),
+    }
+    println!("Encryption: {}", encryption);
+    for (i, params) in header.key_slots.iter().enumerate() {
+        println!(
+            "Password {}: scrypt, N=2^{}, r={}, p={}",
+            i + 1,
+            params.n,
+            params.r,
+            params.p
+        );
+    }
+    println!("Compression: {}", CODEC);
+    match &stats {
+        Some(stats) => {
+            println!("Entries: {}", stats.entries);
+            println!("Files: {}", stats.files);
+            println!("Directories: {}", stats.directories);
+            if let Some(deleted) = stats.deleted {
+                println!("Deleted: {}", deleted);
             }
-            FileType::File => {
-                let mut w = buf_writer(&path, options.force);
-                if let Err(err) = decode.read_file(&mut w) {
-                    exit!("Failed to read '{}': {:#?}", path.display(), err);
+            match summary {
+                Some(summary) => {
+                    println!("Size: {}", progress::size(summary.size));
+                    println!(
+                        "Compressed size: {}",
+                        progress::size(summary.compressed_size)
+                    );
+                }
+                None => {
+                    println!("Size: unknown");
+                    println!("Compressed size: unknown");
                 }
             }
+            match ratio {
+                Some(ratio) => println!("Ratio: {:.1}%", ratio * 100.0),
+                None => println!("Ratio: -"),
+            }
+        }
+        None => println!("Entries: unknown, a password is required"),
+    }
+    println!("Archive size: {}", progress::size(archive_size));
+}
+
+// Entries are always compressed with brotli
+const CODEC: &str = "brotli";
+
+#[derive(Default)]
+struct Stats {
+    info: String,
+    // Every entry written, with the deleted ones and the older versions of a path
+    entries: u64,
+    // The files and directories of the manifest
+    files: u64,
+    directories: u64,
+    // Only counted in archives without a manifest
+    deleted: Option<u64>,
+    // Archives older than version 4 have no summary, the sizes are unknown
+    summary: Option<Summary>,
+}
+
+// Only the file is decompressed, stdout gets nothing but its content
+fn cat_file(options: &Options, path: &str) {
+    let password = options.password.as_ref().map(|s| s.as_str());
+    let mut decode = Decode::new(buf_reader(&options.input), password, DEFAULT_BUF_SIZE).throw();
+    let path = path
+        .trim_start_matches("./")
+        .trim_end_matches('/')
+        .replace('/', std::path::MAIN_SEPARATOR_STR);
+    while let Some((file_type, p)) = decode.read_path().throw() {
+        if p != path {
+            continue;
+        }
+        match file_type {
+            FileType::File => {}
+            FileType::Directory => exit!("'{}' is a directory", path),
+            FileType::Deleted => exit!("'{}' was deleted", path),
+        }
+        let stdout = io::stdout();
+        let mut w = BufWriter::new(stdout.lock());
+        match decode
+            .read_file(&mut w)
+            .and_then(|_| w.flush().map_err(mei::Error::IO))
+        {
+            // The reader is gone, like `mei cat archive.mei log | head`
+            Err(mei::Error::IO(err)) if err.kind() == ErrorKind::BrokenPipe => {}
+            rst => rst.throw(),
+        }
+        return;
+    }
+    exit!("'{}' is not in the archive", path);
+}
+
+fn rekey_archive(options: &Options, mode: &RekeyMode) {
+    let new_password = || {
+        let key = options.new_password.as_ref().unwrap();
+        Password::new(key, ScryptParams::default())
+    };
+    let op = match mode {
+        RekeyMode::Change => Rekey::Change(new_password()),
+        RekeyMode::Add => Rekey::Add(new_password()),
+        RekeyMode::Remove => Rekey::Remove,
+    };
+    let password = options.password.as_ref().unwrap();
+    if read_header(buf_reader(&options.input)).throw().version == 1 {
+        return rewrite_rekey(options, password, op);
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&options.input)
+        .unwrap_or_else(|err| exit!("Failed to open '{}': {}", options.input, err));
+    rekey(&mut file, password, op).throw();
+    println!("Rekeyed: {}", options.input);
+}
+
+// A version 1 archive has no key slots, it is written again next to it and renamed over it
+fn rewrite_rekey(options: &Options, password: &str, op: Rekey) {
+    let archive = Path::new(&options.input);
+    let recovery = recovery_percent(&mut buf_reader(&options.input)).throw();
+    let temp = archive.with_file_name(temp_path());
+    let fail = |err: mei::Error| -> ! {
+        let _ = fs::remove_file(&temp);
+        exit!("{}", err)
+    };
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .unwrap_or_else(|err| fail(mei::Error::IO(err)));
+    let w = rekey_rewrite(
+        buf_reader(&options.input),
+        BufWriter::new(file),
+        password,
+        op,
+    )
+    .unwrap_or_else(|err| fail(err));
+    let mut f = w
+        .into_inner()
+        .unwrap_or_else(|err| fail(mei::Error::IO(err.into_error())));
+    if let Some(percent) = recovery {
+        add_recovery(&mut f, percent).unwrap_or_else(|err| fail(err));
+    }
+    f.sync_all().unwrap_or_else(|err| fail(mei::Error::IO(err)));
+    fs::rename(&temp, archive).unwrap_or_else(|err| fail(mei::Error::IO(err)));
+    println!("Rekeyed: {}", options.input);
+}
+
+// Exits with 1 if some damage cannot be repaired
+fn repair_archive(options: &Options, test: bool) {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(!test)
+        .open(&options.input)
+        .unwrap_or_else(|err| exit!("Failed to open '{}': {}", options.input, err));
+    let report = if test {
+        check_recovery(&mut file)
+    } else {
+        repair(&mut file)
+    }
+    .throw();
+    println!(
+        "Recovery record: {}%, {} blocks of {} bytes",
+        report.percent, report.blocks, report.block_size
+    );
+    println!(
+        "Damaged blocks: {} ({} recoverable)",
+        report.damaged, report.recoverable
+    );
+    println!("Damaged recovery blocks: {}", report.damaged_parity);
+    for (offset, len) in &report.unrecoverable {
+        println!("Unrecoverable: bytes {}-{}", offset, offset + len);
+    }
+    if !test && report.is_recoverable() && report.damaged + report.damaged_parity > 0 {
+        file.sync_all().throw();
+        println!("Repaired: {}", options.input);
+    }
+    if !report.is_recoverable() {
+        exit(1);
+    }
+}
+
+// Exits with 1 if there are differences and 2 if it fails, like `diff`
+fn diff_archive(options: &Options, target: &str, stream: bool) {
+    let password = options.password.as_ref().map(|s| s.as_str());
+    let open = |p: &str| Decode::new(buf_reader(p), password, DEFAULT_BUF_SIZE).throw();
+    let mut decode = open(&options.input);
+    let changes = if Path::new(target).is_dir() {
+        // Archives older than the manifest are always streamed
+        let manifest = match stream {
+            true => None,
+            false => match decode.read_manifest() {
+                Err(mei::Error::InvalidVersion) => None,
+                manifest => Some(manifest.throw()),
+            },
+        };
+        match manifest {
+            Some(manifest) => manifest.diff_dir(target),
+            None => decode.diff_dir(target),
+        }
+        .throw()
+    } else {
+        if stream {
+            exit!("'--stream' compares an archive with a directory");
+        }
+        let old = manifest(&mut decode);
+        old.diff(&manifest(&mut open(target)))
+    };
+    for change in &changes {
+        match change {
+            Change::Added(p) => println!("Added: {}", p),
+            Change::Removed(p) => println!("Removed: {}", p),
+            Change::Modified(p) => println!("Modified: {}", p),
+        }
+    }
+    if !changes.is_empty() {
+        exit(1);
+    }
+}
+
+// Archives older than the manifest are decompressed to build it
+fn manifest<R: io::Read + Seek>(decode: &mut Decode<R>) -> Manifest {
+    match decode.read_manifest() {
+        Err(mei::Error::InvalidVersion) => decode.build_manifest().throw(),
+        manifest => manifest.throw(),
+    }
+}
+
+fn keygen(options: Options) {
+    let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+    let secret = format!("{}.key", options.input);
+    let public = format!("{}.pub", options.input);
+
+    let mut w = BufWriter::new(secret_file(&secret, options.force));
+    w.write_all(hex(keypair.secret.as_bytes()).as_bytes())
+        .throw();
+    w.flush().throw();
+    let mut w = buf_writer(&public, options.force);
+    w.write_all(hex(keypair.public.as_bytes()).as_bytes())
+        .throw();
+    w.flush().throw();
+
+    println!("Secret key: {}", secret);
+    println!("Public key: {}", public);
+}
+
+// Only the owner can read the secret key, from the moment the file exists
+fn secret_file(p: &str, force: bool) -> File {
+    if force {
+        if let Err(err) = fs::remove_file(p) {
+            if err.kind() != ErrorKind::NotFound {
+                exit!("Failed to remove '{}': {}", p, err);
+            }
         }
     }
+    if let Some(parent) = Path::new(p).parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(p).unwrap_or_else(|err| match err.kind() {
+        ErrorKind::AlreadyExists => exit!("File '{}' already exists", p),
+        _ => exit!("Failed to create '{}': {}", p, err),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 trait ThrowError<T> {
     fn throw(self) -> T;
 }
 
-impl<T, E: std::fmt::Debug> ThrowError<T> for Result<T, E> {
+impl<T, E: std::fmt::Display> ThrowError<T> for Result<T, E> {
     fn throw(self) -> T {
         match self {
             Ok(val) => val,
-            Err(err) => exit!("{:#?}", err),
+            Err(err) => exit!("{}", err),
         }
     }
 }
@@ -144,10 +870,11 @@ fn temp_path() -> String {
     name
 }
 
-fn buf_reader(p: &str) -> BufReader<File> {
-    File::open(p)
-        .map(|f| BufReader::new(f))
-        .unwrap_or_else(|err| exit!("Failed to open '{}': {:?}", p, err))
+// Split archives are read through all of their volumes
+fn buf_reader(p: &str) -> BufReader<VolumeReader> {
+    VolumeReader::open(p)
+        .map(BufReader::new)
+        .unwrap_or_else(|err| exit!("Failed to open '{}': {}", p, err))
 }
 
 fn buf_writer<P: AsRef<Path>>(p: P, force: bool) -> BufWriter<File> {
@@ -158,22 +885,13 @@ fn buf_writer<P: AsRef<Path>>(p: P, force: bool) -> BufWriter<File> {
     if let Some(parent) = p.parent() {
         let _ = fs::create_dir_all(parent);
     }
-    File::create(p)
-        .map(|f| BufWriter::new(f))
-        .unwrap_or_else(|err| exit!("Failed to create '{}': {:?}", p.display(), err))
-}
-
-fn files(path: &str, filter: PathBuf) -> impl Iterator<Item = DirEntry> {
-    let mut excluded = false;
-    WalkDir::new(path).into_iter().filter_map(move |rst| {
-        let entry = rst.throw();
-        if !excluded {
-            let path = entry.path().canonicalize().throw();
-            if path == filter {
-                excluded = true;
-                return None;
-            }
-        }
-        return Some(entry);
-    })
+    // Readable, so that a recovery record can be computed over what was written
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(p)
+        .map(BufWriter::new)
+        .unwrap_or_else(|err| exit!("Failed to create '{}': {}", p.display(), err))
 }
@@ -0,0 +1,334 @@
+use crate::json::Object;
+use mei::{Error, FileType, Progress, Status};
+use std::io::{stderr, stdout, Write};
+use std::time::{Duration, Instant};
+
+// Redraw the progress bar at most this often
+const REFRESH: Duration = Duration::from_millis(100);
+const BAR_WIDTH: usize = 30;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Report {
+    /// One line per entry
+    Entries,
+    /// A progress bar with throughput and ETA
+    Bar,
+    Quiet,
+    /// One JSON object per entry when it is done, and a summary at the end
+    Json,
+}
+
+pub struct Reporter {
+    report: Report,
+    // Printed before the path of each entry
+    label: &'static str,
+    entries: u64,
+    start: Instant,
+    drawn: Option<Instant>,
+    // The last status, drawn again when the reporter is dropped
+    last: Option<(u64, Option<u64>, u64)>,
+    // The entry printed when the next one starts
+    current: Option<Event>,
+    totals: Totals,
+    // The entries and the JSON events are printed here, stdout outside of tests
+    out: Box<dyn Write + Send>,
+}
+
+struct Event {
+    path: String,
+    file_type: FileType,
+    size: u64,
+    compressed_size: u64,
+    error: Option<String>,
+}
+
+#[derive(Default)]
+struct Totals {
+    entries: u64,
+    failed: u64,
+    size: u64,
+    compressed_size: u64,
+}
+
+impl Reporter {
+    pub fn new(report: Report, label: &'static str) -> Self {
+        Self::with_output(report, label, Box::new(stdout()))
+    }
+
+    fn with_output(report: Report, label: &'static str, out: Box<dyn Write + Send>) -> Self {
+        Self {
+            report,
+            label,
+            entries: 0,
+            start: Instant::now(),
+            drawn: None,
+            last: None,
+            current: None,
+            totals: Totals::default(),
+            out,
+        }
+    }
+
+    fn print_event(&mut self) {
+        let event = match self.current.take() {
+            Some(event) => event,
+            None => return,
+        };
+        self.totals.entries += 1;
+        let mut object = Object::new()
+            .field("event", "entry")
+            .field("path", event.path)
+            .field("type", type_name(event.file_type));
+        if event.file_type == FileType::File {
+            if event.error.is_none() {
+                self.totals.size += event.size;
+                self.totals.compressed_size += event.compressed_size;
+            }
+            object = object
+                .field("size", event.size)
+                .field("compressed_size", event.compressed_size)
+                .field("ratio", ratio(event.compressed_size, event.size));
+        }
+        object = match event.error {
+            Some(err) => {
+                self.totals.failed += 1;
+                object.field("status", "failed").field("error", err)
+            }
+            None => object.field("status", "ok"),
+        };
+        let _ = writeln!(self.out, "{}", object);
+    }
+
+    fn draw(&self, done: u64, total: Option<u64>, entries: u64) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let speed = if elapsed > 0. {
+            done as f64 / elapsed
+        } else {
+            0.
+        };
+        let mut line = String::new();
+        match total {
+            Some(total) if total > 0 => {
+                let ratio = (done as f64 / total as f64).min(1.);
+                let filled = (ratio * BAR_WIDTH as f64) as usize;
+                line.push('[');
+                line.push_str(&"#".repeat(filled));
+                line.push_str(&"-".repeat(BAR_WIDTH - filled));
+                line.push_str(&format!(
+                    "] {:>3}% {}/{}",
+                    (ratio * 100.) as u32,
+                    size(done),
+                    size(total)
+                ));
+                let eta = if speed > 0. {
+                    duration(total.saturating_sub(done) as f64 / speed)
+                } else {
+                    "--:--".to_string()
+                };
+                line.push_str(&format!(" {}/s ETA {}", size(speed as u64), eta));
+            }
+            _ => line.push_str(&format!("{} {}/s", size(done), size(speed as u64))),
+        }
+        line.push_str(&format!(" {} entries", entries));
+        let mut err = stderr();
+        let _ = write!(err, "\r{}\x1b[K", line);
+        let _ = err.flush();
+    }
+}
+
+impl Progress for Reporter {
+    fn update(&mut self, status: &Status<'_>) {
+        match self.report {
+            Report::Quiet => {}
+            Report::Entries => {
+                if status.entries != self.entries {
+                    self.entries = status.entries;
+                    let _ = writeln!(self.out, "{}: {}", self.label, status.entry);
+                }
+            }
+            Report::Json => {
+                if status.file_type.is_none() || status.entries != self.entries {
+                    self.print_event();
+                }
+                self.entries = status.entries;
+                match (&mut self.current, status.file_type) {
+                    (Some(event), Some(_)) => {
+                        event.size = status.entry_size;
+                        event.compressed_size = status.entry_compressed_size;
+                    }
+                    (None, Some(file_type)) => {
+                        self.current = Some(Event {
+                            path: status.entry.to_string(),
+                            file_type,
+                            size: status.entry_size,
+                            compressed_size: status.entry_compressed_size,
+                            error: None,
+                        })
+                    }
+                    (_, None) => {}
+                }
+            }
+            Report::Bar => {
+                self.last = Some((status.bytes_read, status.total, status.entries));
+                if self.drawn.map_or(true, |t| t.elapsed() >= REFRESH) {
+                    self.drawn = Some(Instant::now());
+                    self.draw(status.bytes_read, status.total, status.entries);
+                }
+            }
+        }
+    }
+
+    fn failed(&mut self, err: &Error) {
+        if self.report != Report::Json {
+            return;
+        }
+        let path = match err {
+            Error::Context { path, .. } => path.as_deref(),
+            _ => None,
+        };
+        match &mut self.current {
+            Some(event) if event.error.is_none() && path == Some(event.path.as_str()) => {
+                event.error = Some(err.to_string());
+            }
+            // The entry header could not be read
+            _ => {
+                self.totals.failed += 1;
+                let object = Object::new()
+                    .field("event", "error")
+                    .field("path", path)
+                    .field("status", "failed")
+                    .field("error", err.to_string());
+                let _ = writeln!(self.out, "{}", object);
+            }
+        }
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        if self.report == Report::Json {
+            self.print_event();
+            let totals = &self.totals;
+            let object = Object::new()
+                .field("event", "summary")
+                .field("entries", totals.entries)
+                .field("failed", totals.failed)
+                .field("size", totals.size)
+                .field("compressed_size", totals.compressed_size)
+                .field("ratio", ratio(totals.compressed_size, totals.size))
+                .field("status", if totals.failed > 0 { "partial" } else { "ok" });
+            let _ = writeln!(self.out, "{}", object);
+        }
+        if let Some((done, total, entries)) = self.last {
+            self.draw(done, total, entries);
+            eprintln!();
+        }
+    }
+}
+
+pub fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::File => "file",
+        FileType::Directory => "directory",
+        FileType::Deleted => "deleted",
+    }
+}
+
+// Compressed size over size, None for empty files
+pub fn ratio(compressed_size: u64, size: u64) -> Option<f64> {
+    if size > 0 {
+        Some(compressed_size as f64 / size as f64)
+    } else {
+        None
+    }
+}
+
+pub fn size(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut n = n as f64;
+    let mut unit = 0;
+    while n >= 1024. && unit < UNITS.len() - 1 {
+        n /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, UNITS[unit])
+    } else {
+        format!("{:.1} {}", n, UNITS[unit])
+    }
+}
+
+fn duration(secs: f64) -> String {
+    let secs = secs as u64;
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mei::Operation;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn status(entry: &str, entries: u64, file_type: Option<FileType>, size: u64) -> Status<'_> {
+        Status {
+            entry,
+            entries,
+            bytes_read: 0,
+            bytes_written: 0,
+            total: None,
+            file_type,
+            entry_size: size,
+            entry_compressed_size: size / 2,
+        }
+    }
+
+    #[test]
+    fn test_json_events() {
+        let out = Shared::default();
+        let mut reporter = Reporter::with_output(Report::Json, "Testing", Box::new(out.clone()));
+        reporter.update(&status("dir", 1, Some(FileType::Directory), 0));
+        reporter.update(&status("dir/a", 2, Some(FileType::File), 0));
+        reporter.update(&status("dir/a", 2, Some(FileType::File), 10));
+        reporter.update(&status("dir/b", 3, Some(FileType::File), 4));
+        reporter.failed(&Error::Context {
+            operation: Operation::Unpack,
+            path: Some("dir/b".to_string()),
+            offset: None,
+            source: Box::new(Error::Truncated),
+        });
+        // An error without the path of the current entry
+        reporter.failed(&Error::Truncated);
+        reporter.update(&status("dir/b", 3, None, 0));
+        drop(reporter);
+
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"event":"entry","path":"dir","type":"directory","status":"ok"}"#,
+                r#"{"event":"entry","path":"dir/a","type":"file","size":10,"compressed_size":5,"ratio":0.5,"status":"ok"}"#,
+                r#"{"event":"error","path":null,"status":"failed","error":"the archive is truncated"}"#,
+                r#"{"event":"entry","path":"dir/b","type":"file","size":4,"compressed_size":2,"ratio":0.5,"status":"failed","error":"unpacking 'dir/b': the archive is truncated"}"#,
+                r#"{"event":"summary","entries":3,"failed":2,"size":10,"compressed_size":5,"ratio":0.5,"status":"partial"}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,435 @@
+use super::*;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::hash_map::{self, HashMap};
+
+// The archive is split into blocks, and the blocks into interleaved groups:
+// block `i` is in group `i % groups`, so that a damaged range of the archive
+// costs each group as few blocks as possible.
+// A group and its parity blocks cannot have more than 256 blocks
+const GROUP_BLOCKS: u64 = 128;
+const MIN_BLOCK_SIZE: u64 = 4096;
+// Larger archives use larger blocks to keep the checksums small
+const MAX_BLOCKS: u64 = 1 << 20;
+
+// Start of the SHA-256 of a block
+const CHECKSUM_SIZE: usize = 8;
+
+// The recovery record is written after the archive:
+// Checksums + Parity blocks + Checksums + Trailer + Trailer
+// The checksums are stored twice, they are needed to find the damaged blocks:
+// Percent + Block size + Archive length + Data block checksums + Parity block checksums + SHA-256
+const TABLE_HEADER_SIZE: usize = 1 + 4 + 8;
+
+/// What `check_recovery` found, or what `repair` found before repairing
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RecoveryReport {
+    /// Size of the recovery record relative to the archive
+    pub percent: u8,
+    /// Number of blocks the archive is split into
+    pub blocks: u64,
+    pub block_size: u64,
+    /// Blocks of the archive whose checksum does not match
+    pub damaged: u64,
+    /// Damaged blocks that can be rebuilt
+    pub recoverable: u64,
+    /// Offset and length of the archive ranges that cannot be rebuilt
+    pub unrecoverable: Vec<(u64, u64)>,
+    /// Damaged blocks of the recovery record
+    pub damaged_parity: u64,
+}
+
+impl RecoveryReport {
+    pub fn is_recoverable(&self) -> bool {
+        self.unrecoverable.is_empty()
+    }
+}
+
+struct Layout {
+    percent: u8,
+    block_size: u64,
+    archive_len: u64,
+    blocks: u64,
+    groups: u64,
+    // Index of the first parity block of each group, and the total
+    parity_start: Vec<u64>,
+    parity_blocks: u64,
+}
+
+impl Layout {
+    fn new(archive_len: u64, percent: u8) -> Self {
+        let mut block_size = MIN_BLOCK_SIZE;
+        while archive_len.div_ceil(block_size) > MAX_BLOCKS {
+            block_size *= 2;
+        }
+        let blocks = archive_len.div_ceil(block_size);
+        let groups = blocks.div_ceil(GROUP_BLOCKS);
+        let mut layout = Self {
+            percent,
+            block_size,
+            archive_len,
+            blocks,
+            groups,
+            parity_start: Vec::with_capacity(groups as usize),
+            parity_blocks: 0,
+        };
+        for group in 0..groups {
+            layout.parity_start.push(layout.parity_blocks);
+            layout.parity_blocks += layout.parity_count(group);
+        }
+        layout
+    }
+
+    fn data_blocks(&self, group: u64) -> Vec<u64> {
+        (group..self.blocks).step_by(self.groups as usize).collect()
+    }
+
+    fn parity_count(&self, group: u64) -> u64 {
+        let data = (self.blocks - group).div_ceil(self.groups);
+        (data * self.percent as u64).div_ceil(100).max(1)
+    }
+
+    fn parity_blocks(&self, group: u64) -> std::ops::Range<u64> {
+        let start = self.parity_start[group as usize];
+        start..start + self.parity_count(group)
+    }
+
+    fn table_len(&self) -> u64 {
+        (TABLE_HEADER_SIZE
+            + (self.blocks + self.parity_blocks) as usize * CHECKSUM_SIZE
+            + Sha256::output_size()) as u64
+    }
+
+    // Offset and length of a block, the last data block is shorter
+    fn data_range(&self, block: u64) -> (u64, u64) {
+        let offset = block * self.block_size;
+        (offset, self.block_size.min(self.archive_len - offset))
+    }
+
+    fn parity_offset(&self, block: u64) -> u64 {
+        self.archive_len + self.table_len() + block * self.block_size
+    }
+
+    fn record_len(&self) -> u64 {
+        self.table_len() * 2 + self.parity_blocks * self.block_size + RECOVERY_END_SIZE as u64
+    }
+
+    // The second copy of the checksums and the two copies of the trailer
+    fn write_end(&self, table: &[u8]) -> Vec<u8> {
+        let mut end = Vec::with_capacity(table.len() + RECOVERY_END_SIZE);
+        end.extend_from_slice(table);
+        for _ in 0..2 {
+            let start = end.len();
+            end.extend_from_slice(&self.table_len().to_be_bytes());
+            end.extend_from_slice(&self.archive_len.to_be_bytes());
+            let hash = Sha256::digest(&end[start..]);
+            end.extend_from_slice(&hash[..8]);
+            end.extend_from_slice(&RECOVERY_MAGIC);
+        }
+        end
+    }
+
+    fn write_table(&self, checksums: &[[u8; CHECKSUM_SIZE]]) -> Vec<u8> {
+        let mut table = Vec::with_capacity(self.table_len() as usize);
+        table.push(self.percent);
+        table.extend_from_slice(&(self.block_size as u32).to_be_bytes());
+        table.extend_from_slice(&self.archive_len.to_be_bytes());
+        for checksum in checksums {
+            table.extend_from_slice(checksum);
+        }
+        let hash = Sha256::digest(&table);
+        table.extend_from_slice(&hash);
+        table
+    }
+
+    // None if the checksums are damaged
+    fn parse_table(table: &[u8], archive_len: u64) -> Option<(Self, Vec<[u8; CHECKSUM_SIZE]>)> {
+        let (table, hash) = table.split_at(table.len().checked_sub(Sha256::output_size())?);
+        if table.len() < TABLE_HEADER_SIZE || Sha256::digest(table)[..] != *hash {
+            return None;
+        }
+        let mut block_size = [0; 4];
+        block_size.copy_from_slice(&table[1..5]);
+        let mut len = [0; 8];
+        len.copy_from_slice(&table[5..13]);
+        let layout = Self::new(archive_len, table[0]);
+        if table[0] == 0
+            || layout.block_size != u32::from_be_bytes(block_size) as u64
+            || u64::from_be_bytes(len) != archive_len
+            || layout.table_len() != (table.len() + hash.len()) as u64
+        {
+            return None;
+        }
+        let checksums = table[TABLE_HEADER_SIZE..]
+            .chunks(CHECKSUM_SIZE)
+            .map(|chunk| {
+                let mut checksum = [0; CHECKSUM_SIZE];
+                checksum.copy_from_slice(chunk);
+                checksum
+            })
+            .collect();
+        Some((layout, checksums))
+    }
+}
+
+fn checksum(block: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut checksum = [0; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&Sha256::digest(block)[..CHECKSUM_SIZE]);
+    checksum
+}
+
+// Read up to `len` bytes into a zeroed block, false if the file ends before
+fn read_block<F: Read + Seek>(
+    f: &mut F,
+    offset: u64,
+    len: u64,
+    block: &mut [u8],
+) -> IoResult<bool> {
+    block.iter_mut().for_each(|b| *b = 0);
+    f.seek(SeekFrom::Start(offset))?;
+    match f.read_exact(&mut block[..len as usize]) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn write_block<F: Write + Seek>(f: &mut F, offset: u64, block: &[u8]) -> IoResult<()> {
+    f.seek(SeekFrom::Start(offset))?;
+    f.write_all(block)
+}
+
+fn rs_error(err: reed_solomon_erasure::Error) -> Error {
+    Error::IO(IoError::new(ErrorKind::InvalidData, err.to_string()))
+}
+
+// Groups mostly have the same number of blocks
+#[derive(Default)]
+struct Codecs(HashMap<(usize, usize), ReedSolomon>);
+
+impl Codecs {
+    fn get(&mut self, data: usize, parity: usize) -> Result<&ReedSolomon, Error> {
+        match self.0.entry((data, parity)) {
+            hash_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+            hash_map::Entry::Vacant(entry) => {
+                Ok(entry.insert(ReedSolomon::new(data, parity).map_err(rs_error)?))
+            }
+        }
+    }
+}
+
+/// Add a recovery record of `percent` percent of the archive size, so that up to
+/// that much damage can be repaired. An existing recovery record is replaced.
+/// Returns the new length of the file, which is shorter than before if
+/// the previous recovery record was larger
+pub fn add_recovery<F: Read + Write + Seek>(f: &mut F, percent: u8) -> Result<u64, Error> {
+    if percent == 0 || percent > 100 {
+        return Err(Error::IO(IoError::new(
+            ErrorKind::InvalidInput,
+            "the recovery record size must be between 1% and 100%",
+        )));
+    }
+    let len = archive_len(f).rst()?;
+    let layout = Layout::new(len, percent);
+    let block_size = layout.block_size as usize;
+    let mut checksums = vec![[0; CHECKSUM_SIZE]; (layout.blocks + layout.parity_blocks) as usize];
+    let mut codecs = Codecs::default();
+    for group in 0..layout.groups {
+        let blocks = layout.data_blocks(group);
+        let mut data = vec![vec![0; block_size]; blocks.len()];
+        for (&block, buf) in blocks.iter().zip(data.iter_mut()) {
+            let (offset, len) = layout.data_range(block);
+            if !read_block(f, offset, len, buf).rst()? {
+                return Err(Error::Truncated);
+            }
+            checksums[block as usize] = checksum(buf);
+        }
+        let parity_blocks = layout.parity_blocks(group);
+        let mut parity = vec![vec![0; block_size]; parity_blocks.clone().count()];
+        codecs
+            .get(data.len(), parity.len())?
+            .encode_sep(&data, &mut parity)
+            .map_err(rs_error)?;
+        for (block, buf) in parity_blocks.zip(parity.iter()) {
+            checksums[(layout.blocks + block) as usize] = checksum(buf);
+            write_block(f, layout.parity_offset(block), buf).rst()?;
+        }
+    }
+    write_tables(f, &layout, &checksums)?;
+    Ok(len + layout.record_len())
+}
+
+fn write_tables<F: Write + Seek>(
+    f: &mut F,
+    layout: &Layout,
+    checksums: &[[u8; CHECKSUM_SIZE]],
+) -> Result<(), Error> {
+    let table = layout.write_table(checksums);
+    write_block(f, layout.archive_len, &table).rst()?;
+    let end = layout.write_end(&table);
+    write_block(f, layout.parity_offset(layout.parity_blocks), &end).rst()
+}
+
+struct Record {
+    layout: Layout,
+    checksums: Vec<[u8; CHECKSUM_SIZE]>,
+    // Whether one of the copies of the checksums or of the trailer is damaged
+    damaged: bool,
+}
+
+// A damaged trailer is found from its copy
+fn read_record<F: Read + Seek>(f: &mut F) -> Result<Record, Error> {
+    let file_len = f.seek(SeekFrom::End(0)).rst()?;
+    if file_len < RECOVERY_END_SIZE as u64 {
+        return Err(Error::RecoveryMissing);
+    }
+    f.seek(SeekFrom::End(-(RECOVERY_END_SIZE as i64))).rst()?;
+    let mut end = [0; RECOVERY_END_SIZE];
+    f.read_exact(&mut end).rst()?;
+    let (table_len, archive_len) = read_trailer(&end).ok_or(Error::RecoveryMissing)?;
+    let archive_len = archive_len.min(file_len);
+    let copies = [
+        Some(archive_len),
+        (file_len - RECOVERY_END_SIZE as u64).checked_sub(table_len),
+    ];
+    let mut found = None;
+    let mut damaged = false;
+    for offset in copies.iter() {
+        let mut table = vec![0; table_len.min(file_len) as usize];
+        let table = match offset {
+            Some(offset) if read_block(f, *offset, table.len() as u64, &mut table).rst()? => {
+                Layout::parse_table(&table, archive_len)
+            }
+            _ => None,
+        };
+        match table {
+            Some(table) if found.is_none() => found = Some(table),
+            Some(_) => {}
+            None => damaged = true,
+        }
+    }
+    let (layout, checksums) = found.ok_or(Error::RecoveryDamaged)?;
+    damaged |= layout.write_end(&[])[..] != end[..];
+    Ok(Record {
+        layout,
+        checksums,
+        damaged,
+    })
+}
+
+/// Size of the recovery record relative to the archive, None if there is none
+pub fn recovery_percent<F: Read + Seek>(f: &mut F) -> Result<Option<u8>, Error> {
+    match read_record(f) {
+        Ok(record) => Ok(Some(record.layout.percent)),
+        Err(Error::RecoveryMissing) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Find the damaged blocks and whether they can be rebuilt, nothing is written
+pub fn check_recovery<F: Read + Seek>(f: &mut F) -> Result<RecoveryReport, Error> {
+    scan(f, None)
+}
+
+/// Rebuild the damaged blocks that can be rebuilt, the report tells what was damaged
+pub fn repair<F: Read + Write + Seek>(f: &mut F) -> Result<RecoveryReport, Error> {
+    scan(f, Some(write_block::<F>))
+}
+
+type WriteBlock<F> = fn(&mut F, u64, &[u8]) -> IoResult<()>;
+
+fn scan<F: Read + Seek>(f: &mut F, write: Option<WriteBlock<F>>) -> Result<RecoveryReport, Error> {
+    let record = read_record(f)?;
+    let layout = &record.layout;
+    let block_size = layout.block_size as usize;
+    let mut report = RecoveryReport {
+        percent: layout.percent,
+        blocks: layout.blocks,
+        block_size: layout.block_size,
+        ..Default::default()
+    };
+    let mut codecs = Codecs::default();
+    for group in 0..layout.groups {
+        // Data blocks then parity blocks, with whether they are intact
+        let mut blocks = Vec::new();
+        let mut shards = Vec::new();
+        for block in layout.data_blocks(group) {
+            let (offset, len) = layout.data_range(block);
+            let mut buf = vec![0; block_size];
+            let ok = read_block(f, offset, len, &mut buf).rst()?
+                && checksum(&buf) == record.checksums[block as usize];
+            blocks.push(block);
+            shards.push((buf, ok));
+        }
+        let data = blocks.len();
+        for block in layout.parity_blocks(group) {
+            let mut buf = vec![0; block_size];
+            let ok = read_block(f, layout.parity_offset(block), layout.block_size, &mut buf)
+                .rst()?
+                && checksum(&buf) == record.checksums[(layout.blocks + block) as usize];
+            blocks.push(layout.blocks + block);
+            shards.push((buf, ok));
+        }
+        let parity = shards.len() - data;
+        let damaged_data = shards[..data].iter().filter(|(_, ok)| !ok).count();
+        let damaged_parity = shards[data..].iter().filter(|(_, ok)| !ok).count();
+        report.damaged += damaged_data as u64;
+        report.damaged_parity += damaged_parity as u64;
+        if damaged_data + damaged_parity == 0 {
+            continue;
+        }
+        // Intact data blocks are enough to rebuild any number of parity blocks
+        if damaged_data > 0 && damaged_data + damaged_parity > parity {
+            for (&block, (_, ok)) in blocks.iter().zip(shards.iter()).take(data) {
+                if !ok {
+                    report.unrecoverable.push(layout.data_range(block));
+                }
+            }
+            continue;
+        }
+        report.recoverable += damaged_data as u64;
+        let write = match write {
+            Some(write) => write,
+            None => continue,
+        };
+        let codec = codecs.get(data, parity)?;
+        if damaged_data == 0 {
+            let (data_shards, parity_shards) = shards.split_at_mut(data);
+            let data_shards: Vec<_> = data_shards.iter().map(|(buf, _)| buf).collect();
+            let mut parity_bufs: Vec<_> = parity_shards.iter_mut().map(|(buf, _)| buf).collect();
+            codec
+                .encode_sep(&data_shards, &mut parity_bufs)
+                .map_err(rs_error)?;
+        } else {
+            codec.reconstruct(&mut shards).map_err(rs_error)?;
+        }
+        for (index, (&block, (buf, ok))) in blocks.iter().zip(shards.iter()).enumerate() {
+            if *ok {
+                continue;
+            }
+            if index < data {
+                let (offset, len) = layout.data_range(block);
+                write(f, offset, &buf[..len as usize]).rst()?;
+            } else {
+                write(f, layout.parity_offset(block - layout.blocks), buf).rst()?;
+            }
+        }
+    }
+    report.unrecoverable.sort_unstable();
+    report.unrecoverable.dedup_by(|next, prev| {
+        if prev.0 + prev.1 == next.0 {
+            prev.1 += next.1;
+            true
+        } else {
+            false
+        }
+    });
+    if let Some(write) = write {
+        if record.damaged {
+            let table = layout.write_table(&record.checksums);
+            write(f, layout.archive_len, &table).rst()?;
+            let end = layout.write_end(&table);
+            write(f, layout.parity_offset(layout.parity_blocks), &end).rst()?;
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,341 @@
+use super::*;
+use std::time::SystemTime;
+
+// Every volume starts with a header, the archive is split anywhere after it:
+// Magic + Archive id + Part number + Last part
+const VOLUME_MAGIC: [u8; 4] = *b"meiv";
+const VOLUME_HEADER_SIZE: usize = 4 + 8 + 4 + 1;
+
+struct VolumeHeader {
+    id: [u8; 8],
+    index: u32,
+    last: bool,
+}
+
+impl VolumeHeader {
+    // None if the file is not a volume
+    fn read(f: &mut File) -> Result<Option<Self>, Error> {
+        let mut buf = [0; VOLUME_HEADER_SIZE];
+        match f.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(Error::IO(err)),
+        }
+        if buf[..4] != VOLUME_MAGIC {
+            return Ok(None);
+        }
+        let mut id = [0; 8];
+        id.copy_from_slice(&buf[4..12]);
+        let mut index = [0; 4];
+        index.copy_from_slice(&buf[12..16]);
+        Ok(Some(Self {
+            id,
+            index: u32::from_be_bytes(index),
+            last: buf[16] == 1,
+        }))
+    }
+
+    fn write(&self, f: &mut File) -> IoResult<()> {
+        let mut buf = Vec::with_capacity(VOLUME_HEADER_SIZE);
+        buf.extend_from_slice(&VOLUME_MAGIC);
+        buf.extend_from_slice(&self.id);
+        buf.extend_from_slice(&self.index.to_be_bytes());
+        buf.push(self.last as u8);
+        f.write_all(&buf)
+    }
+}
+
+// `archive.mei` -> `archive.mei.001`
+fn volume_path(base: &Path, index: u32) -> PathBuf {
+    let mut path = base.as_os_str().to_os_string();
+    path.push(format!(".{:03}", index));
+    PathBuf::from(path)
+}
+
+// `archive.mei.001` -> `archive.mei`
+fn volume_base(path: &Path) -> Option<PathBuf> {
+    let ext = path.extension()?.to_str()?;
+    if ext.len() >= 3 && ext.bytes().all(|b| b.is_ascii_digit()) {
+        Some(path.with_extension(""))
+    } else {
+        None
+    }
+}
+
+// The paths named like volumes of `base` in its directory, with their part numbers
+fn existing_volumes(base: &Path) -> IoResult<Vec<(u32, PathBuf)>> {
+    let name = match base.file_name() {
+        Some(name) => name,
+        None => return Ok(Vec::new()),
+    };
+    let dir = match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut volumes = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = base.with_file_name(entry?.file_name());
+        let index = match volume_base(&path) {
+            Some(b) if b.file_name() == Some(name) => path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| ext.parse().ok()),
+            _ => None,
+        };
+        if let Some(index) = index {
+            volumes.push((index, path));
+        }
+    }
+    Ok(volumes)
+}
+
+/// Split an archive into files of at most `volume_size` bytes,
+/// named `archive.mei.001`, `archive.mei.002` and so on
+pub struct VolumeWriter {
+    base: PathBuf,
+    id: [u8; 8],
+    index: u32,
+    file: File,
+    // Archive bytes per volume, and in the current volume
+    capacity: u64,
+    written: u64,
+}
+
+impl VolumeWriter {
+    /// Create the first volume, existing volumes are replaced
+    pub fn create<P: AsRef<Path>>(base: P, volume_size: u64) -> Result<Self, Error> {
+        let base = base.as_ref().to_path_buf();
+        if volume_size <= VOLUME_HEADER_SIZE as u64 {
+            return Err(Error::IO(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the volume size must be greater than {}",
+                    VOLUME_HEADER_SIZE
+                ),
+            )));
+        }
+        // Only used to tell the volumes of different archives apart
+        let mut hash = Sha256::new();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        hash.update(now.unwrap_or_default().as_nanos().to_be_bytes());
+        hash.update(std::process::id().to_be_bytes());
+        hash.update(base.to_string_lossy().as_bytes());
+        let mut id = [0; 8];
+        id.copy_from_slice(&hash.finalize()[..8]);
+
+        let file = Self::create_volume(&base, id, 1).rst()?;
+        Ok(Self {
+            base,
+            id,
+            index: 1,
+            file,
+            capacity: volume_size - VOLUME_HEADER_SIZE as u64,
+            written: 0,
+        })
+    }
+
+    /// The paths named like volumes of `base` that exist, including those of an archive
+    /// that is being replaced, so that a directory walk can skip all of them
+    pub fn existing<P: AsRef<Path>>(base: P) -> Result<Vec<PathBuf>, Error> {
+        let volumes = existing_volumes(base.as_ref()).rst()?;
+        Ok(volumes.into_iter().map(|(_, path)| path).collect())
+    }
+
+    fn create_volume(base: &Path, id: [u8; 8], index: u32) -> IoResult<File> {
+        let mut file = File::create(volume_path(base, index))?;
+        let header = VolumeHeader {
+            id,
+            index,
+            last: false,
+        };
+        header.write(&mut file)?;
+        Ok(file)
+    }
+
+    /// Mark the current volume as the last one, remove the higher-numbered volumes left
+    /// over from a larger archive and return the paths of all volumes,
+    /// `VolumeReader` reports an archive without the last volume as incomplete
+    pub fn finish(mut self) -> Result<Vec<PathBuf>, Error> {
+        self.file.flush().rst()?;
+        self.file
+            .seek(SeekFrom::Start(VOLUME_HEADER_SIZE as u64 - 1))
+            .rst()?;
+        self.file.write_all(&[1]).rst()?;
+        self.file.sync_all().rst()?;
+        for (index, path) in existing_volumes(&self.base).rst()? {
+            // Only files that start with a volume header are removed
+            let stale = index > self.index
+                && File::open(&path)
+                    .map(|mut f| matches!(VolumeHeader::read(&mut f), Ok(Some(_))))
+                    .unwrap_or(false);
+            if stale {
+                fs::remove_file(&path).rst()?;
+            }
+        }
+        Ok((1..=self.index)
+            .map(|index| volume_path(&self.base, index))
+            .collect())
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // The next volume is only created when there is something to write to it
+        if self.written == self.capacity {
+            self.file.sync_all()?;
+            self.file = Self::create_volume(&self.base, self.id, self.index + 1)?;
+            self.index += 1;
+            self.written = 0;
+        }
+        let n = (buf.len() as u64).min(self.capacity - self.written) as usize;
+        let n = self.file.write(&buf[..n])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.file.flush()
+    }
+}
+
+/// Read an archive that is a single file or split into volumes as one stream
+pub struct VolumeReader {
+    // Path and archive bytes of each volume
+    volumes: Vec<(PathBuf, u64)>,
+    // 0 if the archive is not split
+    header: u64,
+    index: usize,
+    file: File,
+    pos: u64,
+}
+
+impl VolumeReader {
+    /// Open an archive or the first volume of a split archive,
+    /// `archive.mei` also opens `archive.mei.001` if it does not exist.
+    /// All volumes are checked before anything is read
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut path = path.as_ref().to_path_buf();
+        if !path.exists() && volume_path(&path, 1).exists() {
+            path = volume_path(&path, 1);
+        }
+        let mut file = File::open(&path).rst()?;
+        let first = match VolumeHeader::read(&mut file)? {
+            Some(header) => header,
+            None => {
+                let len = file.metadata().rst()?.len();
+                file.seek(SeekFrom::Start(0)).rst()?;
+                return Ok(Self {
+                    volumes: vec![(path, len)],
+                    header: 0,
+                    index: 0,
+                    file,
+                    pos: 0,
+                });
+            }
+        };
+        let base = match volume_base(&path) {
+            Some(base) if first.index == 1 => base,
+            _ => {
+                return Err(Error::VolumeOrder {
+                    path,
+                    expected: 1,
+                    found: first.index,
+                })
+            }
+        };
+
+        let mut volumes = Vec::new();
+        for index in 1.. {
+            let path = volume_path(&base, index);
+            let mut f = match File::open(&path) {
+                Ok(f) => f,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    return Err(Error::VolumeMissing(path))
+                }
+                Err(err) => return Err(Error::IO(err)),
+            };
+            let header = match VolumeHeader::read(&mut f)? {
+                Some(header) if header.id == first.id => header,
+                _ => return Err(Error::VolumeMismatch(path)),
+            };
+            if header.index != index {
+                return Err(Error::VolumeOrder {
+                    path,
+                    expected: index,
+                    found: header.index,
+                });
+            }
+            let len = f.metadata().rst()?.len() - VOLUME_HEADER_SIZE as u64;
+            volumes.push((path, len));
+            if header.last {
+                break;
+            }
+        }
+        let mut reader = Self {
+            volumes,
+            header: VOLUME_HEADER_SIZE as u64,
+            index: 0,
+            file,
+            pos: 0,
+        };
+        reader.open_volume(0, 0).rst()?;
+        Ok(reader)
+    }
+
+    /// Number of files the archive is split into, 1 if it is not split
+    pub fn volumes(&self) -> usize {
+        self.volumes.len()
+    }
+
+    /// Size of the archive without the volume headers
+    pub fn size(&self) -> u64 {
+        self.volumes.iter().map(|(_, len)| len).sum()
+    }
+
+    fn open_volume(&mut self, index: usize, offset: u64) -> IoResult<()> {
+        if index != self.index {
+            self.file = File::open(&self.volumes[index].0)?;
+            self.index = index;
+        }
+        self.file.seek(SeekFrom::Start(self.header + offset))?;
+        Ok(())
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 || buf.is_empty() || self.index + 1 == self.volumes.len() {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            self.open_volume(self.index + 1, 0)?;
+        }
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.size().checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        }
+        .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "invalid seek position"))?;
+        // A position after the end is in the last volume
+        let mut start = 0;
+        for (index, (_, len)) in self.volumes.iter().enumerate() {
+            if target < start + len || index + 1 == self.volumes.len() {
+                self.open_volume(index, target - start)?;
+                break;
+            }
+            start += len;
+        }
+        self.pos = target;
+        Ok(target)
+    }
+}
@@ -0,0 +1,416 @@
+#![cfg(feature = "cli")]
+
+use std::env::temp_dir;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::process::{Command, Output, Stdio};
+
+fn mei(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mei"))
+        .args(args)
+        .env("MEI_TEST_PASSWORD", "secret")
+        .env("MEI_TEST_EMPTY", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn path(p: &Path) -> &str {
+    p.to_str().unwrap()
+}
+
+fn encrypted_archive(name: &str) -> PathBuf {
+    let src = temp_dir().join(format!("{}_src", name));
+    let archive = temp_dir().join(format!("{}.mei", name));
+    let _ = fs::remove_dir_all(&src);
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    let out = mei(
+        &[
+            "create",
+            path(&src),
+            "-o",
+            path(&archive),
+            "--force",
+            "--password-env",
+            "MEI_TEST_PASSWORD",
+        ],
+        b"",
+    );
+    assert!(out.status.success());
+    archive
+}
+
+#[test]
+fn test_password_sources() {
+    let archive = encrypted_archive("mei_cli_password_sources");
+    let file = temp_dir().join("mei_cli_password");
+    fs::write(&file, b"secret\n").unwrap();
+
+    let out = mei(
+        &["test", path(&archive), "--password-file", path(&file)],
+        b"",
+    );
+    assert!(out.status.success());
+
+    #[cfg(unix)]
+    {
+        let wrong = temp_dir().join("mei_cli_wrong_password");
+        fs::write(&wrong, b"wrong\n").unwrap();
+        // The password is handed over on descriptor 3 by the shell
+        let fd = |file: &Path| {
+            Command::new("sh")
+                .arg("-c")
+                .arg("exec \"$0\" test \"$1\" --password-fd 3 3< \"$2\"")
+                .args([env!("CARGO_BIN_EXE_mei"), path(&archive), path(file)])
+                .output()
+                .unwrap()
+        };
+        assert!(fd(&file).status.success());
+        assert!(!fd(&wrong).status.success());
+    }
+}
+
+#[test]
+fn test_password_rejected() {
+    let archive = encrypted_archive("mei_cli_password_rejected");
+    let file = temp_dir().join("mei_cli_empty_password");
+    fs::write(&file, b"\n").unwrap();
+
+    let long = temp_dir().join("mei_cli_long_password");
+    fs::write(&long, vec![b'a'; 5000]).unwrap();
+
+    let file = format!("--password-file={}", path(&file));
+    let long = format!("--password-file={}", path(&long));
+    for arg in [
+        "--password-env=MEI_TEST_EMPTY",
+        &file,
+        &long,
+        "--password=",
+        "--password-fd=0",
+        "--password-fd=2",
+        "--password-fd=-1",
+        "--password-fd=99",
+    ] {
+        let out = mei(&["test", path(&archive), arg], b"");
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).starts_with("Error: "));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_keygen_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let name = temp_dir().join("mei_cli_keygen");
+    let _ = fs::remove_file(name.with_extension("key"));
+    let _ = fs::remove_file(name.with_extension("pub"));
+    assert!(mei(&["keygen", path(&name)], b"").status.success());
+    let mode = fs::metadata(name.with_extension("key"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    assert!(!mei(&["keygen", path(&name)], b"").status.success());
+    assert!(mei(&["keygen", path(&name), "--force"], b"")
+        .status
+        .success());
+}
+
+#[test]
+fn test_change_signed() {
+    let dir = temp_dir().join("mei_cli_change_signed");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("src/a.txt"), b"hello").unwrap();
+    fs::write(dir.join("b.txt"), b"world").unwrap();
+    let (key, archive, b) = (dir.join("k"), dir.join("a.mei"), dir.join("b.txt"));
+    let key_file = key.with_extension("key");
+    let (key_file, pub_file) = (path(&key_file), key.with_extension("pub"));
+    assert!(mei(&["keygen", path(&key)], b"").status.success());
+    let out = mei(
+        &[
+            "create",
+            path(&dir.join("src")),
+            "-o",
+            path(&archive),
+            "--sign",
+            key_file,
+        ],
+        b"",
+    );
+    assert!(out.status.success());
+    let signed = fs::read(&archive).unwrap();
+
+    // The signature is not dropped silently, and the archive is left as it was
+    let out = mei(&[path(&archive), "--append", path(&b)], b"");
+    assert!(!out.status.success());
+    assert_eq!(fs::read(&archive).unwrap(), signed);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 5);
+
+    let out = mei(
+        &[path(&archive), "--append", path(&b), "--sign", key_file],
+        b"",
+    );
+    assert!(out.status.success());
+    let verify = ["test", path(&archive), "--verify", path(&pub_file)];
+    assert!(mei(&verify, b"").status.success());
+    let signed = fs::read(&archive).unwrap();
+    let out = mei(&[path(&archive), "--delete", "b.txt"], b"");
+    assert!(!out.status.success());
+    assert_eq!(fs::read(&archive).unwrap(), signed);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 5);
+
+    let out = mei(&[path(&archive), "--append", path(&b), "--force"], b"");
+    assert!(out.status.success());
+    assert!(!mei(&verify, b"").status.success());
+}
+
+#[test]
+fn test_cat_prompt() {
+    let archive = encrypted_archive("mei_cli_cat_prompt");
+    let p = format!("mei_cli_cat_prompt_src{}a.txt", MAIN_SEPARATOR);
+    let out = mei(&["cat", path(&archive), &p, "-p"], b"secret\n");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"hello");
+}
+
+// Returns the rest of `s` after one JSON value, None if it is not valid
+fn json_value(s: &[u8]) -> Option<&[u8]> {
+    let s = json_space(s);
+    match s.first()? {
+        b'{' => json_list(&s[1..], b'}', |s| {
+            let s = json_space(json_string(json_space(s))?);
+            json_value(s.strip_prefix(b":")?)
+        }),
+        b'[' => json_list(&s[1..], b']', json_value),
+        b'"' => json_string(s),
+        b'-' | b'0'..=b'9' => {
+            let n = s
+                .iter()
+                .take_while(|c| c.is_ascii_digit() || b"-+.eE".contains(c))
+                .count();
+            std::str::from_utf8(&s[..n]).ok()?.parse::<f64>().ok()?;
+            Some(&s[n..])
+        }
+        _ => ["true", "false", "null"]
+            .iter()
+            .find_map(|lit| s.strip_prefix(lit.as_bytes())),
+    }
+}
+
+fn json_list<'a>(
+    mut s: &'a [u8],
+    end: u8,
+    item: impl Fn(&'a [u8]) -> Option<&'a [u8]>,
+) -> Option<&'a [u8]> {
+    if let Some(rest) = json_space(s).strip_prefix(&[end]) {
+        return Some(rest);
+    }
+    loop {
+        s = json_space(item(s)?);
+        match s.first()? {
+            b',' => s = &s[1..],
+            c if *c == end => return Some(&s[1..]),
+            _ => return None,
+        }
+    }
+}
+
+fn json_string(s: &[u8]) -> Option<&[u8]> {
+    let mut i = 1;
+    if s.first()? != &b'"' {
+        return None;
+    }
+    loop {
+        match s.get(i)? {
+            b'"' => return Some(&s[i + 1..]),
+            b'\\' => match s.get(i + 1)? {
+                b'u' => {
+                    let hex = std::str::from_utf8(s.get(i + 2..i + 6)?).ok()?;
+                    u16::from_str_radix(hex, 16).ok()?;
+                    i += 6;
+                }
+                c if b"\"\\/bfnrt".contains(c) => i += 2,
+                _ => return None,
+            },
+            c if *c < 0x20 => return None,
+            _ => i += 1,
+        }
+    }
+}
+
+fn json_space(s: &[u8]) -> &[u8] {
+    let n = s.iter().take_while(|c| c.is_ascii_whitespace()).count();
+    &s[n..]
+}
+
+#[test]
+fn test_json_lines() {
+    let archive = encrypted_archive("mei_cli_json_lines");
+    let dest = temp_dir().join("mei_cli_json_lines_dest");
+    let _ = fs::remove_dir_all(&dest);
+    for args in [
+        vec!["list", path(&archive), "-p", "--format", "json"],
+        vec!["test", path(&archive), "-p", "--format", "json"],
+        vec!["info", path(&archive), "-p", "--format", "json"],
+        vec!["info", path(&archive), "-p", "--json"],
+        vec![
+            "extract",
+            path(&archive),
+            "-o",
+            path(&dest),
+            "-p",
+            "--format",
+            "json",
+        ],
+    ] {
+        let out = mei(&args, b"secret\n");
+        assert!(out.status.success(), "{:?}", args);
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(!stdout.is_empty(), "{:?}", args);
+        for line in stdout.lines() {
+            assert_eq!(json_value(line.as_bytes()), Some(&b""[..]), "{}", line);
+        }
+    }
+}
+
+#[test]
+fn test_diff_status() {
+    let archive = encrypted_archive("mei_cli_diff_status");
+    let src = temp_dir().join("mei_cli_diff_status_src");
+    let dest = temp_dir().join("mei_cli_diff_status_dest");
+    let _ = fs::remove_dir_all(&dest);
+    let p = "--password-env=MEI_TEST_PASSWORD";
+    let out = mei(&["extract", path(&archive), "-o", path(&dest), p], b"");
+    assert!(out.status.success());
+
+    let diff = |target: &Path, p| mei(&["diff", path(&archive), path(target), p], b"").status;
+    assert_eq!(diff(&dest, p).code(), Some(0));
+    fs::write(dest.join("mei_cli_diff_status_src/a.txt"), b"world").unwrap();
+    assert_eq!(diff(&dest, p).code(), Some(1));
+    // Failures are not reported as differences
+    assert_eq!(diff(&src.join("missing"), p).code(), Some(2));
+    assert_eq!(diff(&dest, "--password-env=MEI_TEST_EMPTY").code(), Some(2));
+    assert_eq!(diff(&dest, "--unknown").code(), Some(2));
+}
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[test]
+fn test_old_versions() {
+    for name in ["v1.mei", "v4-signed.mei"] {
+        let out = mei(&["list", &fixture(name)], b"");
+        assert!(out.status.success(), "{}", name);
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(stdout.contains("200  dir/a.txt"), "{}", stdout);
+        assert!(mei(&["test", &fixture(name)], b"").status.success());
+    }
+    let (v1, v4) = (fixture("v1.mei"), fixture("v4-signed.mei"));
+    assert_eq!(mei(&["diff", &v1, &v4], b"").status.code(), Some(0));
+    let dest = temp_dir().join("mei_cli_old_versions");
+    let _ = fs::remove_dir_all(&dest);
+    assert!(mei(&["extract", &v1, "-o", path(&dest)], b"")
+        .status
+        .success());
+    assert_eq!(mei(&["diff", &v4, path(&dest)], b"").status.code(), Some(0));
+    fs::write(dest.join("b.bin"), b"changed").unwrap();
+    assert_eq!(mei(&["diff", &v4, path(&dest)], b"").status.code(), Some(1));
+
+    // The sizes of an archive without a summary are not 0
+    let out = mei(&["info", &fixture("v1.mei")], b"");
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("Size: unknown"), "{}", stdout);
+}
+
+#[test]
+fn test_rekey_v1() {
+    let dir = temp_dir().join("mei_cli_rekey_v1");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let archive = dir.join("a.mei");
+    fs::copy(fixture("v1-encrypted.mei"), &archive).unwrap();
+    let rekey = [
+        "rekey",
+        path(&archive),
+        "-p",
+        "mei",
+        "--new-password-env",
+        "MEI_TEST_PASSWORD",
+    ];
+    assert!(mei(&rekey, b"").status.success());
+    // The archive is replaced by the rewritten one
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    let test = |p| {
+        mei(&["test", path(&archive), "-p", p], b"")
+            .status
+            .success()
+    };
+    assert!(test("secret"));
+    assert!(!test("mei"));
+}
+
+#[test]
+fn test_volumes_replaced() {
+    let src = temp_dir().join("mei_cli_volumes");
+    let _ = fs::remove_dir_all(&src);
+    fs::create_dir_all(&src).unwrap();
+    let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+    fs::write(src.join("big"), &data).unwrap();
+    let archive = src.join("out.mei");
+    let volume = |n: u32| src.join(format!("out.mei.{:03}", n));
+
+    let args = [
+        path(&src),
+        "-o",
+        path(&archive),
+        "--volume-size",
+        "10K",
+        "--force",
+    ];
+    assert!(mei(&args, b"").status.success());
+    assert!(volume(3).exists());
+
+    // The volumes of the replaced archive are neither added nor left behind
+    fs::write(src.join("big"), b"small").unwrap();
+    assert!(mei(&args, b"").status.success());
+    assert!(!volume(2).exists() && !volume(3).exists());
+    let out = mei(&["list", path(&archive)], b"");
+    assert!(out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("out.mei"));
+}
+
+#[test]
+fn test_legacy_extract_args() {
+    let archive = encrypted_archive("mei_cli_legacy_extract");
+    let dest = temp_dir().join("mei_cli_legacy_extract_dest");
+    let _ = fs::remove_dir_all(&dest);
+
+    // A detected archive accepts the options of `extract` without '-d'
+    let args = [
+        path(&archive),
+        "-o",
+        path(&dest),
+        "--keep-going",
+        "--password-env",
+        "MEI_TEST_PASSWORD",
+    ];
+    let out = mei(&args, b"");
+    assert!(out.status.success());
+    assert!(dest.exists());
+
+    let src = temp_dir().join("mei_cli_legacy_extract_src");
+    let out = mei(&[path(&src), "--keep-going"], b"");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--keep-going"));
+}